@@ -7,6 +7,8 @@ use std::convert::{TryFrom, TryInto};
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Default)]
 pub struct MessageWatcherId;
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Default)]
+pub struct MessageWatcherSnapshotsId;
 
 // TODO: Required?
 impl TryFrom<String> for MessageWatcherId {
@@ -27,6 +29,13 @@ impl AsRef<str> for MessageWatcherId {
     }
 }
 
+// No snapshots are ever written to this stream.
+impl AsRef<str> for MessageWatcherSnapshotsId {
+    fn as_ref(&self) -> &str {
+        "external_messages_snapshots"
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MessageWatcherCommand {
     AddMessage(ExternalMessage),
@@ -66,20 +75,21 @@ impl Aggregate for MessageWatcher {
     }
 }
 
-// Since no state is maintained, no snapshot implementation is required.
+// Since no state is maintained, snapshots are never created. Restoring just
+// returns the stateless aggregate.
 #[async_trait]
 impl Snapshot for MessageWatcher {
-    type Id = MessageWatcherId;
+    type Id = MessageWatcherSnapshotsId;
     type State = Event;
     type Error = anyhow::Error;
 
     fn qualifies(&self) -> bool {
-        unimplemented!()
+        false
     }
-    async fn snapshot(&self) -> Self::State {
-        unimplemented!()
+    async fn snapshot(&self, _revision: u64) -> Self::State {
+        unreachable!()
     }
-    async fn restore(state: Self::State) -> Result<Self> {
-        unimplemented!()
+    async fn restore(self, _state: Self::State) -> Result<(Self, Option<u64>)> {
+        Ok((self, None))
     }
 }
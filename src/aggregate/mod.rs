@@ -1,5 +1,5 @@
-use crate::Result;
-use eventstore::{Client, EventData, ExpectedVersion, ReadResult, RecordedEvent, ResolvedEvent};
+use crate::store::{EventData, EventStore, ExpectedRevision, RecordedEvent};
+use crate::{Error, Result};
 use futures::join;
 use futures::TryStreamExt;
 use std::convert::{TryFrom, TryInto};
@@ -36,16 +36,24 @@ pub trait Aggregate {
     ) -> std::result::Result<Option<Vec<Self::Event>>, Self::Error>;
 }
 
+/// How often a command is retried if the aggregate stream was modified
+/// concurrently.
+const MAX_APPLY_ATTEMPTS: usize = 5;
+
 pub struct Repository<A> {
     aggregate: A,
-    client: Client,
+    store: Arc<dyn EventStore>,
+    // The revision of the latest event in the aggregate stream which was
+    // applied to the aggregate, or `None` if the stream is empty.
+    revision: Option<u64>,
 }
 
 impl<A> Repository<A>
 where
     A: 'static + Send + Sync + Aggregate + Snapshot,
     <A as Aggregate>::Id: Send + Sync + AsRef<str> + Default,
-    <A as Aggregate>::Event: Send + Sync + TryInto<EventData> + Clone,
+    <A as Aggregate>::Event: Send + Sync + TryInto<EventData> + TryFrom<RecordedEvent> + Clone,
+    <A as Aggregate>::Command: Clone,
     <A as Aggregate>::Error: 'static + Send + Sync + Debug,
     <A as Snapshot>::Id: Send + Sync + Default + AsRef<str>,
     <A as Snapshot>::State: Send + Sync + TryInto<EventData> + TryFrom<RecordedEvent>,
@@ -56,134 +64,203 @@ where
     pub fn wipe(&mut self) {
         self.aggregate.wipe()
     }
-    // TODO: Rename "client" to "store".
-    pub async fn new_with_snapshot_service(mut aggregate: A, client: Client) -> Result<Self> {
-        let mut snapshot_found = false;
+    pub async fn new_with_snapshot_service(
+        mut aggregate: A,
+        store: Arc<dyn EventStore>,
+    ) -> Result<Self> {
+        let snapshot_id = <A as Snapshot>::Id::default();
 
         // Check if there is a snapshot available in the eventstore.
-        match client
-            .read_stream(<A as Snapshot>::Id::default())
-            .start_from_end_of_stream()
-            .execute(1)
+        let latest = store
+            .read_from_end(snapshot_id.as_ref(), 1)
             .await
-            .map_err(|err| {
-                anyhow!(
-                    "failed to open stream to retrieve latest snapshot: {:?}",
-                    err
+            .map_err(|err| anyhow!("failed to retrieve latest snapshot: {:?}", err))?;
+
+        let mut revision = None;
+        if let Some(recorded) = latest.into_iter().next() {
+            info!("Snapshot found, restoring");
+
+            let (restored, covered) = aggregate
+                .restore(
+                    <A as Snapshot>::State::try_from(recorded)
+                        .map_err(|_| anyhow!("failed to convert snapshot into native type"))?,
                 )
-            })? {
-            ReadResult::Ok(mut stream) => {
-                while let Some(resolved) = stream.try_next().await.map_err(|err| {
-                    anyhow!("failed to retrieve snapshot from the eventstore: {:?}", err)
-                })? {
-                    if let Some(recorded) = resolved.event {
-                        info!("Snapshot found, restoring");
-
-                        aggregate =
-                            A::restore(<A as Snapshot>::State::try_from(recorded).map_err(
-                                |_| anyhow!("failed to convert snapshot into native type"),
-                            )?)
-                            .await
-                            .map_err(|err| anyhow!("failed to restore from snapshot: {:?}", err))?;
-
-                        info!("Snapshot restored");
-                        snapshot_found = true;
-                        break;
-                    }
-                }
-            }
-            ReadResult::StreamNotFound(_) => {}
-        }
+                .await
+                .map_err(|err| anyhow!("failed to restore from snapshot: {:?}", err))?;
 
-        if !snapshot_found {
+            aggregate = restored;
+            revision = covered;
+
+            if covered.is_some() {
+                info!("Snapshot restored");
+            } else {
+                warn!("Snapshot does not record the covered revision, discarding");
+            }
+        } else {
             warn!(
                 "No snapshots found on stream '{}', starting from scratch",
-                <A as Snapshot>::Id::default().as_ref()
+                snapshot_id.as_ref()
             );
         }
 
-        Ok(Repository {
+        let mut repository = Repository {
             aggregate: aggregate,
-            client: client,
-        })
+            store: store,
+            revision: revision,
+        };
+
+        // Apply all events which were written after the snapshot was created.
+        let before = repository.revision;
+        repository.catch_up().await?;
+        if repository.revision != before {
+            info!(
+                "Applied events up to revision {:?} of stream '{}'",
+                repository.revision,
+                <A as Aggregate>::Id::default().as_ref()
+            );
+        }
+
+        Ok(repository)
     }
     pub fn state(&self) -> &<A as Aggregate>::State {
         <A as Aggregate>::state(&self.aggregate)
     }
-    pub async fn apply(&mut self, command: <A as Aggregate>::Command) -> Result<()> {
-        let events = {
-            if let Some(events) = self
-                .aggregate
-                .handle(command)
-                .await
-                .map_err(|err| anyhow!("failed to handle aggregate command: {:?}", err))?
-            {
-                events
-            } else {
-                return Ok(());
-            }
-        };
-
-        let to_store = events.clone();
-
-        // Send events to the store.
-        self.client
-            .write_events(<A as Aggregate>::Id::default())
-            .send_iter(
-                to_store
-                    .into_iter()
-                    .map(|event| {
-                        event.try_into().map_err(|_| {
-                            anyhow!("Failed to convert event into eventstore native format").into()
-                        })
-                    })
-                    .collect::<Result<Vec<EventData>>>()?,
-            )
+    /// The revision of the latest event applied to the aggregate, or `None`
+    /// if the aggregate stream is empty.
+    pub fn revision(&self) -> Option<u64> {
+        self.revision
+    }
+    /// Applies all events of the aggregate stream which were written after
+    /// the latest applied revision, e.g. by another registrar instance.
+    pub async fn catch_up(&mut self) -> Result<()> {
+        let next = self.revision.map(|r| r + 1).unwrap_or(0);
+        let mut stream = self
+            .store
+            .read_from(<A as Aggregate>::Id::default().as_ref(), next)
             .await
-            .map_err(|err| {
-                anyhow!(
-                    "failed to send aggregate events to the eventstore: {:?}",
-                    err
-                )
-            })?
-            .map_err(|err| {
-                anyhow!(
-                    "failed to send aggregate events to the eventstore: {:?}",
-                    err
-                )
-            })?;
+            .map_err(|err| anyhow!("failed to open aggregate stream to catch up: {:?}", err))?;
+
+        while let Some(recorded) = stream.try_next().await? {
+            let revision = recorded.revision;
+            let event = <A as Aggregate>::Event::try_from(recorded)
+                .map_err(|_| anyhow!("failed to convert eventstore event into native type"))?;
 
-        // Apply events locally.
-        for event in events {
             self.aggregate.apply(event).await.map_err(|err| {
                 anyhow!(
                     "Failed to apply aggregate events to the local state: {:?}",
                     err
                 )
             })?;
+
+            self.revision = Some(revision);
         }
 
-        // Create a snapshot, if dictated.
-        if self.aggregate.qualifies() {
-            let state = self.aggregate.snapshot().await;
-            let event = state
-                .try_into()
-                .map_err(|_| anyhow!("Failed to convert native snapshot into evenstore event"))?;
-
-            let _ = self
-                .client
-                .write_events(<A as Snapshot>::Id::default())
-                .send_event(event)
+        Ok(())
+    }
+    /// Handles the command and appends the resulting events to the aggregate
+    /// stream. If the stream was modified concurrently, the aggregate catches
+    /// up and the command is retried. Returns `Error::ConcurrencyConflict` if
+    /// that still fails after `MAX_APPLY_ATTEMPTS`.
+    pub async fn apply(&mut self, command: <A as Aggregate>::Command) -> Result<()> {
+        for attempt in 1..=MAX_APPLY_ATTEMPTS {
+            let events = {
+                if let Some(events) = self
+                    .aggregate
+                    .handle(command.clone())
+                    .await
+                    .map_err(|err| anyhow!("failed to handle aggregate command: {:?}", err))?
+                {
+                    events
+                } else {
+                    return Ok(());
+                }
+            };
+
+            let to_store = events.clone();
+
+            // Send events to the store, expecting that no other events were
+            // written since the latest applied revision.
+            let written = self
+                .store
+                .append(
+                    <A as Aggregate>::Id::default().as_ref(),
+                    ExpectedRevision::from_latest(self.revision),
+                    to_store
+                        .into_iter()
+                        .map(|event| {
+                            event.try_into().map_err(|_| {
+                                anyhow!("Failed to convert event into eventstore native format")
+                            })
+                        })
+                        .collect::<Result<Vec<EventData>>>()?,
+                )
                 .await
-                .map_err(|err| anyhow!("failed to send snapshot to the eventstore: {:?}", err))?;
+                .map_err(|err| {
+                    anyhow!(
+                        "failed to send aggregate events to the eventstore: {:?}",
+                        err
+                    )
+                })?;
 
-            info!(
-                "Created snapshot on stream '{}'",
-                <A as Snapshot>::Id::default().as_ref()
-            );
+            let revision = match written {
+                Ok(revision) => revision,
+                Err(err) => {
+                    warn!(
+                        "Conflict on attempt {}/{}, catching up: {}",
+                        attempt, MAX_APPLY_ATTEMPTS, err
+                    );
+
+                    self.catch_up().await?;
+                    continue;
+                }
+            };
+
+            // Apply events locally.
+            for event in events {
+                self.aggregate.apply(event).await.map_err(|err| {
+                    anyhow!(
+                        "Failed to apply aggregate events to the local state: {:?}",
+                        err
+                    )
+                })?;
+            }
+
+            self.revision = Some(revision);
+
+            // Create a snapshot, if dictated.
+            if self.aggregate.qualifies() {
+                let state = self.aggregate.snapshot(revision).await;
+                let event = state.try_into().map_err(|_| {
+                    anyhow!("Failed to convert native snapshot into evenstore event")
+                })?;
+
+                let _ = self
+                    .store
+                    .append(
+                        <A as Snapshot>::Id::default().as_ref(),
+                        ExpectedRevision::Any,
+                        vec![event],
+                    )
+                    .await
+                    .map_err(|err| anyhow!("failed to send snapshot to the eventstore: {:?}", err))?
+                    .map_err(|err| {
+                        anyhow!("failed to send snapshot to the eventstore: {:?}", err)
+                    })?;
+
+                info!(
+                    "Created snapshot on stream '{}'",
+                    <A as Snapshot>::Id::default().as_ref()
+                );
+            }
+
+            return Ok(());
         }
 
-        Ok(())
+        Err(Error::ConcurrencyConflict(
+            <A as Aggregate>::Id::default().as_ref().to_string(),
+            MAX_APPLY_ATTEMPTS,
+        )
+        .into())
     }
 }
 
@@ -194,6 +271,15 @@ pub trait Snapshot: Sized {
     type Error;
 
     fn qualifies(&self) -> bool;
-    async fn snapshot(&self) -> Self::State;
-    async fn restore(state: Self::State) -> std::result::Result<Self, Self::Error>;
+    /// Creates a snapshot which covers the aggregate stream up to (and
+    /// including) the given revision.
+    async fn snapshot(&self, revision: u64) -> Self::State;
+    /// Restores the state of the (freshly configured) aggregate from the
+    /// snapshot and returns the covered revision of the aggregate stream. If
+    /// the snapshot does not record a revision, the aggregate must be returned
+    /// unchanged alongside `None`.
+    async fn restore(
+        self,
+        state: Self::State,
+    ) -> std::result::Result<(Self, Option<u64>), Self::Error>;
 }
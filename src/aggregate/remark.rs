@@ -1,21 +1,44 @@
-use super::Aggregate;
-use crate::event::{Event, OnChainRemark, RemarkFound};
+use super::{Aggregate, Snapshot};
+use crate::event::{BlankNetwork, Event, EventType, ExtrinsicPosition, OnChainRemark, RemarkFound};
 use crate::manager::NetworkAddress;
 use crate::Result;
+use std::collections::HashMap;
 
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Default)]
 pub struct RemarkWatcherId;
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Default)]
+pub struct RemarkWatcherSnapshotsId;
+
+impl AsRef<str> for RemarkWatcherId {
+    fn as_ref(&self) -> &str {
+        "on_chain_remarks"
+    }
+}
+
+// No snapshots are ever written to this stream.
+impl AsRef<str> for RemarkWatcherSnapshotsId {
+    fn as_ref(&self) -> &str {
+        "on_chain_remarks_snapshots"
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum RemarkWatcherCommand {
-    AddRemark {
+    /// Adds the remarks of a single extrinsic. Extrinsics at or before the
+    /// latest extrinsic with remarks on the same network were already added
+    /// and are ignored, since blocks are scanned again after a restart.
+    AddRemarks {
         net_address: NetworkAddress,
-        remark: OnChainRemark,
+        remarks: Vec<OnChainRemark>,
+        extrinsic: ExtrinsicPosition,
     },
 }
 
-#[derive(Debug, Clone)]
-pub struct RemarkWatcher;
+#[derive(Debug, Clone, Default)]
+pub struct RemarkWatcher {
+    // The latest extrinsic with remarks of each network.
+    latest: HashMap<BlankNetwork, ExtrinsicPosition>,
+}
 
 #[async_trait]
 impl Aggregate for RemarkWatcher {
@@ -26,26 +49,87 @@ impl Aggregate for RemarkWatcher {
     type Error = anyhow::Error;
 
     #[cfg(test)]
-    fn wipe(&mut self) {}
+    fn wipe(&mut self) {
+        self.latest.clear();
+    }
 
     fn state(&self) -> &Self::State {
-        unimplemented!()
+        &()
     }
 
-    async fn apply(&mut self, _event: Self::Event) -> Result<()> {
-        // No state is managed for remarks.
+    async fn apply(&mut self, event: Self::Event) -> Result<()> {
+        // Only the position of the remarks is tracked.
+        if let EventType::RemarkFound(found) = event.body {
+            if let Some(extrinsic) = found.extrinsic {
+                let latest = self
+                    .latest
+                    .entry(found.net_address.net())
+                    .or_insert(extrinsic);
+                *latest = extrinsic.max(*latest);
+            }
+        }
+
         Ok(())
     }
 
     async fn handle(&self, command: Self::Command) -> Result<Option<Vec<Self::Event>>> {
         match command {
-            RemarkWatcherCommand::AddRemark {
+            RemarkWatcherCommand::AddRemarks {
                 net_address,
-                remark,
-            } => Ok(Some(vec![Event::from(RemarkFound {
-                net_address: net_address,
-                remark: remark,
-            })])),
+                remarks,
+                extrinsic,
+            } => {
+                if self
+                    .latest
+                    .get(&net_address.net())
+                    .map(|latest| extrinsic <= *latest)
+                    .unwrap_or(false)
+                {
+                    debug!(
+                        "Remarks of extrinsic {}-{} on {} were already added, ignoring",
+                        extrinsic.block,
+                        extrinsic.index,
+                        net_address.net_str()
+                    );
+                    return Ok(None);
+                }
+
+                if remarks.is_empty() {
+                    return Ok(None);
+                }
+
+                Ok(Some(
+                    remarks
+                        .into_iter()
+                        .map(|remark| {
+                            Event::from(RemarkFound {
+                                net_address: net_address.clone(),
+                                remark: remark,
+                                extrinsic: Some(extrinsic),
+                            })
+                        })
+                        .collect(),
+                ))
+            }
         }
     }
 }
+
+// Snapshots are never created, the positions are rebuilt from the stream.
+// Restoring just returns the aggregate.
+#[async_trait]
+impl Snapshot for RemarkWatcher {
+    type Id = RemarkWatcherSnapshotsId;
+    type State = Event;
+    type Error = anyhow::Error;
+
+    fn qualifies(&self) -> bool {
+        false
+    }
+    async fn snapshot(&self, _revision: u64) -> Self::State {
+        unreachable!()
+    }
+    async fn restore(self, _state: Self::State) -> Result<(Self, Option<u64>)> {
+        Ok((self, None))
+    }
+}
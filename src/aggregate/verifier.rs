@@ -1,10 +1,13 @@
 use super::{Aggregate, Snapshot};
 use crate::event::{
-    self, DisplayNamePersisted, Event, EventType, ExternalMessage, FieldStatusVerified,
-    IdentityFullyVerified, IdentityInserted,
+    self, ChallengeRegenerated, DisplayNamePersisted, Event, EventType, ExternalMessage,
+    FieldStatusVerified, IdentityExpired, IdentityFullyVerified, IdentityInserted, IdentityLocked,
+    IdentityRemoved, IdentityStateSnapshot, IdentityUnlocked, JudgementGiven, JudgementKind,
+    JudgementRequestCancelled, JudgementSubmissionFailed, JudgementSubmitted, Timestamp, TTL,
 };
 use crate::manager::{
-    DisplayName, IdentityField, IdentityManager, IdentityState, NetworkAddress, UpdateChanges,
+    AttemptLimits, DisplayName, IdentityField, IdentityFieldType, IdentityManager, IdentityState,
+    NetworkAddress, UpdateChanges,
 };
 use crate::Result;
 use futures::future::BoxFuture;
@@ -34,7 +37,18 @@ impl AsRef<str> for VerifierAggregateSnapshotsId {
 #[allow(dead_code)]
 pub enum VerifierCommand {
     InsertIdentity(IdentityState),
+    /// Inserts the identity like `InsertIdentity` when a judgement is
+    /// requested (again), which also unlocks a locked identity.
+    RequestJudgement(IdentityState),
     VerifyMessage(ExternalMessage),
+    /// Verifies a message of the message watcher stream like `VerifyMessage`.
+    /// Messages at or below the latest verified revision are ignored, so
+    /// messages which are verified again after a restart do not count as
+    /// failed attempts twice.
+    VerifyRecordedMessage {
+        message: ExternalMessage,
+        revision: u64,
+    },
     VerifyDisplayName {
         net_address: NetworkAddress,
         display_name: DisplayName,
@@ -43,6 +57,44 @@ pub enum VerifierCommand {
         net_address: NetworkAddress,
         display_name: DisplayName,
     },
+    /// Expires all identities which were not verified within their TTL.
+    ExpireIdentities,
+    /// Removes a pending judgement request. Unknown identities are ignored.
+    RemoveIdentity {
+        net_address: NetworkAddress,
+        reason: RemovalReason,
+    },
+    /// Records that the judgement was given on-chain, which completes the
+    /// judgement request. Unknown identities are ignored.
+    ConfirmJudgement {
+        net_address: NetworkAddress,
+        judgement: JudgementKind,
+    },
+    /// Records that the judgement is about to be submitted on-chain. Ignored
+    /// if the identity is unknown or a judgement was already submitted.
+    SubmitJudgement {
+        net_address: NetworkAddress,
+        judgement: JudgementKind,
+    },
+    /// Records that the submitted judgement was not included on-chain, so it
+    /// can be submitted again.
+    FailJudgementSubmission {
+        net_address: NetworkAddress,
+    },
+    /// Replaces the challenge of the field with a new one, as requested by
+    /// the user. Requests which cannot be fulfilled are ignored.
+    RegenerateChallenge {
+        net_address: NetworkAddress,
+        field: IdentityFieldType,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RemovalReason {
+    /// The identity was cleared on-chain.
+    IdentityCleared,
+    /// The judgement request was cancelled, the identity itself remains.
+    JudgementRequestCancelled,
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +103,9 @@ pub struct VerifierAggregate {
     state: IdentityManager,
     events_generated: usize,
     snapshot_every: usize,
+    // The TTL attached to newly inserted identities.
+    identity_ttl: TTL,
+    attempt_limits: AttemptLimits,
 }
 
 impl Default for VerifierAggregate {
@@ -59,6 +114,8 @@ impl Default for VerifierAggregate {
             state: Default::default(),
             events_generated: 0,
             snapshot_every: 50,
+            identity_ttl: TTL::immortal(),
+            attempt_limits: AttemptLimits::default(),
         }
     }
 }
@@ -67,13 +124,37 @@ impl VerifierAggregate {
     pub fn set_snapshot_every(self, snapshot_every: usize) -> Self {
         VerifierAggregate {
             snapshot_every: snapshot_every,
-            ..Default::default()
+            ..self
+        }
+    }
+    /// Identities which are not verified within the TTL are expired by
+    /// `VerifierCommand::ExpireIdentities`.
+    pub fn set_identity_ttl(self, identity_ttl: TTL) -> Self {
+        VerifierAggregate {
+            identity_ttl: identity_ttl,
+            ..self
+        }
+    }
+    /// Limits the failed challenge responses of each field and identity.
+    /// There are no limits by default.
+    pub fn set_attempt_limits(self, attempt_limits: AttemptLimits) -> Self {
+        VerifierAggregate {
+            attempt_limits: attempt_limits,
+            ..self
         }
     }
     fn handle_verify_message(
         &self,
         external_message: ExternalMessage,
+        revision: Option<u64>,
     ) -> Result<Option<Vec<Event>>> {
+        if let (Some(revision), Some(verified)) = (revision, self.state.message_revision()) {
+            if revision <= verified {
+                debug!("Message {} was already verified, ignoring", revision);
+                return Ok(None);
+            }
+        }
+
         let (identity_field, provided_message) = (
             IdentityField::from((external_message.origin, external_message.field_address)),
             external_message.message,
@@ -85,16 +166,37 @@ impl VerifierAggregate {
         let mut c_net_address = None;
         self.state
             .verify_message(&identity_field, &provided_message)
-            .map(|outcome| {
+            .map(|mut outcome| {
                 c_net_address = Some(outcome.net_address.clone());
 
+                let lock = self
+                    .state
+                    .limit_attempts(&mut outcome, &self.attempt_limits);
+
+                if lock {
+                    warn!(
+                        "Too many failed attempts, locking identity {}",
+                        outcome.net_address.address_str()
+                    );
+                }
+
                 events.push(
                     FieldStatusVerified {
-                        net_address: outcome.net_address,
+                        net_address: outcome.net_address.clone(),
                         field_status: outcome.field_status,
+                        message_revision: revision,
                     }
                     .into(),
                 );
+
+                if lock {
+                    events.push(
+                        IdentityLocked {
+                            net_address: outcome.net_address,
+                        }
+                        .into(),
+                    );
+                }
             });
 
         // If a message has been successfully verified (and `c_net_address` is
@@ -145,6 +247,7 @@ impl VerifierAggregate {
                     FieldStatusVerified {
                         net_address: outcome.net_address,
                         field_status: outcome.field_status,
+                        message_revision: None,
                     }
                     .into(),
                 );
@@ -181,9 +284,13 @@ impl VerifierAggregate {
         }
     }
     fn apply_state_changes(&mut self, event: Event) -> Result<()> {
+        let expires_at = event.expires_at();
+
         match event.body {
             EventType::IdentityInserted(identity) => {
+                let net_address = identity.identity.net_address.clone();
                 self.state.insert_identity(identity);
+                self.state.set_expiry(&net_address, expires_at);
             }
             EventType::FieldStatusVerified(field_status_verified) => {
                 if let Some(change) = self.state.update_field(field_status_verified)? {
@@ -199,6 +306,34 @@ impl VerifierAggregate {
             EventType::DisplayNamePersisted(persisted) => {
                 self.state.persist_display_name(persisted)?;
             }
+            EventType::IdentityExpired(expired) => {
+                self.state.remove_identity(&expired.net_address);
+            }
+            EventType::IdentityRemoved(removed) => {
+                self.state.clear_identity(&removed.net_address);
+            }
+            EventType::JudgementRequestCancelled(cancelled) => {
+                self.state.remove_identity(&cancelled.net_address);
+            }
+            EventType::JudgementGiven(given) => {
+                self.state.remove_identity(&given.net_address);
+            }
+            EventType::IdentityLocked(locked) => {
+                self.state.lock_identity(&locked.net_address);
+            }
+            EventType::IdentityUnlocked(unlocked) => {
+                self.state.unlock_identity(&unlocked.net_address);
+            }
+            EventType::ChallengeRegenerated(regenerated) => {
+                self.state.replace_challenge(regenerated)?;
+            }
+            EventType::JudgementSubmitted(submitted) => {
+                self.state
+                    .submit_judgement(&submitted.net_address, submitted.judgement);
+            }
+            EventType::JudgementSubmissionFailed(failed) => {
+                self.state.submission_failed(&failed.net_address);
+            }
             _ => warn!("Received unrecognized event type when applying changes"),
         }
 
@@ -231,15 +366,46 @@ impl Aggregate for VerifierAggregate {
     async fn handle(&self, command: Self::Command) -> Result<Option<Vec<Self::Event>>> {
         match command {
             VerifierCommand::InsertIdentity(identity) => {
-                if !self.state.contains(&identity) {
-                    Ok(Some(vec![Event::from(IdentityInserted {
+                match self.state.merge_identity(identity) {
+                    Some(identity) => Ok(Some(vec![Event::from(IdentityInserted {
                         identity: identity,
-                    })]))
-                } else {
+                    })
+                    .with_ttl(self.identity_ttl.clone())])),
+                    None => Ok(None),
+                }
+            }
+            VerifierCommand::RequestJudgement(identity) => {
+                let net_address = identity.net_address.clone();
+                let mut events = vec![];
+
+                if let Some(identity) = self.state.merge_identity(identity) {
+                    events.push(
+                        Event::from(IdentityInserted { identity: identity })
+                            .with_ttl(self.identity_ttl.clone()),
+                    );
+                }
+
+                if self.state.is_locked(&net_address) {
+                    info!(
+                        "Judgement request renewed, unlocking identity {}",
+                        net_address.address_str()
+                    );
+
+                    events.push(Event::from(IdentityUnlocked {
+                        net_address: net_address,
+                    }));
+                }
+
+                if events.is_empty() {
                     Ok(None)
+                } else {
+                    Ok(Some(events))
                 }
             }
-            VerifierCommand::VerifyMessage(message) => self.handle_verify_message(message),
+            VerifierCommand::VerifyMessage(message) => self.handle_verify_message(message, None),
+            VerifierCommand::VerifyRecordedMessage { message, revision } => {
+                self.handle_verify_message(message, Some(revision))
+            }
             VerifierCommand::VerifyDisplayName {
                 net_address,
                 display_name,
@@ -251,6 +417,118 @@ impl Aggregate for VerifierAggregate {
                 net_address: net_address,
                 display_name: display_name,
             })])),
+            VerifierCommand::ExpireIdentities => {
+                let events: Vec<Event> = self
+                    .state
+                    .expired_identities(&Timestamp::unix_time())
+                    .into_iter()
+                    .map(|net_address| {
+                        info!(
+                            "Judgement request of {} has expired",
+                            net_address.address_str()
+                        );
+                        Event::from(IdentityExpired {
+                            net_address: net_address,
+                        })
+                    })
+                    .collect();
+
+                if events.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(events))
+                }
+            }
+            VerifierCommand::RemoveIdentity {
+                net_address,
+                reason,
+            } => {
+                let event = match reason {
+                    RemovalReason::IdentityCleared => {
+                        if !self.state.is_pending(&net_address)
+                            && self.state.display_name(&net_address).is_none()
+                        {
+                            return Ok(None);
+                        }
+
+                        Event::from(IdentityRemoved {
+                            net_address: net_address,
+                        })
+                    }
+                    RemovalReason::JudgementRequestCancelled => {
+                        if !self.state.is_pending(&net_address) {
+                            return Ok(None);
+                        }
+
+                        Event::from(JudgementRequestCancelled {
+                            net_address: net_address,
+                        })
+                    }
+                };
+
+                Ok(Some(vec![event]))
+            }
+            VerifierCommand::ConfirmJudgement {
+                net_address,
+                judgement,
+            } => {
+                if !self.state.is_pending(&net_address) {
+                    return Ok(None);
+                }
+
+                Ok(Some(vec![Event::from(JudgementGiven {
+                    net_address: net_address,
+                    judgement: judgement,
+                })]))
+            }
+            VerifierCommand::SubmitJudgement {
+                net_address,
+                judgement,
+            } => {
+                if !self.state.is_pending(&net_address)
+                    || self.state.submitted_judgement(&net_address).is_some()
+                {
+                    return Ok(None);
+                }
+
+                Ok(Some(vec![Event::from(JudgementSubmitted {
+                    net_address: net_address,
+                    judgement: judgement,
+                })]))
+            }
+            VerifierCommand::FailJudgementSubmission { net_address } => {
+                if self.state.submitted_judgement(&net_address).is_none() {
+                    return Ok(None);
+                }
+
+                Ok(Some(vec![Event::from(JudgementSubmissionFailed {
+                    net_address: net_address,
+                })]))
+            }
+            VerifierCommand::RegenerateChallenge { net_address, field } => {
+                let field_status = match self.state.regenerate_challenge(&net_address, &field) {
+                    Ok(field_status) => field_status,
+                    Err(err) => {
+                        debug!(
+                            "Not regenerating challenge of {}: {}",
+                            net_address.address_str(),
+                            err
+                        );
+                        return Ok(None);
+                    }
+                };
+
+                info!(
+                    "Regenerating the challenge of the {} field of {}",
+                    field_status.field,
+                    net_address.address_str()
+                );
+
+                Ok(Some(vec![Event::from(ChallengeRegenerated {
+                    net_address: net_address,
+                    field_status: field_status,
+                })]))
+            }
         }
     }
 }
@@ -268,28 +546,29 @@ impl Snapshot for VerifierAggregate {
             false
         }
     }
-    async fn snapshot(&self) -> Self::State {
-        Event::from(EventType::ExportedIdentityState(self.state.export_state()))
+    async fn snapshot(&self, revision: u64) -> Self::State {
+        Event::from(IdentityStateSnapshot {
+            revision: revision,
+            events_generated: self.events_generated,
+            state: self.state.snapshot(),
+        })
     }
-    async fn restore(state: Self::State) -> Result<Self> {
-        let state = match state.body {
-            EventType::ExportedIdentityState(state) => state,
-            _ => {
-                return Err(anyhow!(
-                    "expected 'EventType::ExportedIdentityState' type to restore state"
-                ))
-            }
-        };
-
-        let mut manager = IdentityManager::default();
-
-        for entry in state {
-            manager.insert_identity(IdentityInserted { identity: entry });
+    async fn restore(self, state: Self::State) -> Result<(Self, Option<u64>)> {
+        match state.body {
+            EventType::IdentityStateSnapshot(snapshot) => Ok((
+                VerifierAggregate {
+                    state: IdentityManager::restore(snapshot.state)?,
+                    events_generated: snapshot.events_generated,
+                    ..self
+                },
+                Some(snapshot.revision),
+            )),
+            // Legacy snapshots do not record the covered revision, so the
+            // state is rebuilt from the full stream instead.
+            EventType::ExportedIdentityState(_) => Ok((self, None)),
+            _ => Err(anyhow!(
+                "expected 'EventType::IdentityStateSnapshot' type to restore state"
+            )),
         }
-
-        Ok(VerifierAggregate {
-            state: manager,
-            ..Default::default()
-        })
     }
 }
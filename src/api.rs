@@ -1,8 +1,11 @@
+use crate::aggregate::verifier::{VerifierAggregate, VerifierCommand};
+use crate::aggregate::Repository;
+use crate::chain::{verify_signature, Networks};
 use crate::event::{BlankNetwork, ErrorMessage, StateWrapper};
-use crate::manager::{IdentityAddress, IdentityManager, NetworkAddress};
+use crate::manager::{IdentityAddress, IdentityFieldType, IdentityManager, NetworkAddress};
 use futures::select_biased;
-use futures::FutureExt;
-use jsonrpc_core::Result;
+use futures::{future, FutureExt};
+use jsonrpc_core::{BoxFuture, Error as RpcError, ErrorCode, Result};
 use jsonrpc_derive::rpc;
 use jsonrpc_pubsub::{
     manager::{IdProvider, NumericIdProvider},
@@ -12,11 +15,57 @@ use jsonrpc_pubsub::{
 use parking_lot::RwLock;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 // TODO: Move to `broadcast` rather than `watch`?
 use tokio_02::sync::watch::{channel, Receiver, Sender};
 
-const REGISTRAR_IDX: usize = 0;
+// The error code of requests which are rejected due to the cooldown.
+const COOLDOWN_ERROR_CODE: i64 = -32000;
+// The error code of requests which are not signed by the identity.
+const SIGNATURE_ERROR_CODE: i64 = -32001;
+// The maximum difference in seconds between the signed timestamp and the time
+// at which the request is received.
+const MAX_SIGNATURE_AGE: u64 = 300;
+
+/// The message which must be signed by the account of the identity in order
+/// to regenerate the challenge of the field. The timestamp is given in seconds
+/// since the UNIX epoch.
+pub fn regenerate_message(field: &IdentityFieldType, timestamp: u64) -> String {
+    let field = serde_json::to_value(field)
+        .ok()
+        .and_then(|field| field.as_str().map(|field| field.to_string()))
+        .unwrap_or_default();
+
+    format!("w3f_registrar:regenerate:{}:{}", field, timestamp)
+}
+
+/// Checks that the request is recent and signed by the identity itself.
+fn verify_regenerate_request(
+    net_address: &NetworkAddress,
+    field: &IdentityFieldType,
+    timestamp: u64,
+    signature: &str,
+) -> crate::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let age = if now > timestamp {
+        now - timestamp
+    } else {
+        timestamp - now
+    };
+
+    if age > MAX_SIGNATURE_AGE {
+        return Err(anyhow!(
+            "the signed timestamp must not differ by more than {} seconds from the current time",
+            MAX_SIGNATURE_AGE
+        ));
+    }
+
+    verify_signature(
+        net_address,
+        &regenerate_message(field, timestamp),
+        signature,
+    )
+}
 
 // TODO: Remove this type since it is no longer necessary.
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
@@ -31,16 +80,42 @@ impl From<u64> for SubId {
 #[derive(Default, Clone)]
 pub struct ConnectionPool {
     pool: Arc<RwLock<HashMap<NetworkAddress, ConnectionInfo>>>,
+    networks: Networks,
 }
 
 impl ConnectionPool {
+    pub fn new(networks: Networks) -> Self {
+        ConnectionPool {
+            pool: Default::default(),
+            networks: networks,
+        }
+    }
     pub fn broadcast(&self, net_address: &NetworkAddress, state: StateWrapper) {
+        self.send(net_address, AccountStatusResponse::Ok(state));
+    }
+    /// Notifies the subscribers of the identity about an error, such as an
+    /// expired judgement request.
+    pub fn broadcast_error(&self, net_address: &NetworkAddress, error: ErrorMessage) {
+        self.send(net_address, AccountStatusResponse::Err(error));
+    }
+    /// Notifies the subscribers that there is no longer a pending judgement
+    /// request for the identity.
+    pub fn broadcast_removed(&self, net_address: &NetworkAddress) {
+        self.broadcast_error(
+            net_address,
+            self.networks.no_pending_judgement_request(net_address),
+        );
+    }
+    fn send(&self, net_address: &NetworkAddress, response: AccountStatusResponse) {
         self.pool
             .read()
             .get(net_address)
-            .map(|info| info.sender.broadcast(Some(state)));
+            .map(|info| info.sender.broadcast(Some(response)));
     }
-    fn watch_net_address(&self, net_address: &NetworkAddress) -> Receiver<Option<StateWrapper>> {
+    fn watch_net_address(
+        &self,
+        net_address: &NetworkAddress,
+    ) -> Receiver<Option<AccountStatusResponse>> {
         let mut writer = self.pool.write();
         if let Some(info) = writer.get(net_address) {
             info.receiver.clone()
@@ -54,8 +129,8 @@ impl ConnectionPool {
 }
 
 struct ConnectionInfo {
-    sender: Sender<Option<StateWrapper>>,
-    receiver: Receiver<Option<StateWrapper>>,
+    sender: Sender<Option<AccountStatusResponse>>,
+    receiver: Receiver<Option<AccountStatusResponse>>,
 }
 
 impl ConnectionInfo {
@@ -69,6 +144,93 @@ impl ConnectionInfo {
     }
 }
 
+/// Regenerates the challenges requested by API clients. Each identity can
+/// only request a new challenge once per cooldown.
+#[derive(Clone)]
+pub struct ChallengeRegenerator {
+    repo: Arc<tokio::sync::Mutex<Repository<VerifierAggregate>>>,
+    // The commands are applied on the runtime of the event store, not the one
+    // of the JSON-RPC server.
+    runtime: tokio::runtime::Handle,
+    cooldown: Duration,
+    last_requests: Arc<RwLock<HashMap<NetworkAddress, Instant>>>,
+}
+
+impl ChallengeRegenerator {
+    /// Must be called from within the tokio runtime of the event store.
+    pub fn new(repo: Repository<VerifierAggregate>, cooldown: Duration) -> Self {
+        ChallengeRegenerator {
+            repo: Arc::new(tokio::sync::Mutex::new(repo)),
+            runtime: tokio::runtime::Handle::current(),
+            cooldown: cooldown,
+            last_requests: Default::default(),
+        }
+    }
+    /// Records the request of the identity, unless it is still in its
+    /// cooldown. Concurrent requests of the same identity are rejected, too.
+    fn try_start(&self, net_address: &NetworkAddress) -> bool {
+        let now = Instant::now();
+        let mut last_requests = self.last_requests.write();
+
+        // Forget the requests whose cooldown has passed.
+        let cooldown = self.cooldown;
+        last_requests.retain(|_, requested| now.duration_since(*requested) < cooldown);
+
+        if last_requests.contains_key(net_address) {
+            return false;
+        }
+
+        last_requests.insert(net_address.clone(), now);
+        true
+    }
+    /// Removes the request of the identity, so a failed request does not
+    /// start the cooldown.
+    fn cancel(&self, net_address: &NetworkAddress) {
+        self.last_requests.write().remove(net_address);
+    }
+    async fn regenerate(
+        self,
+        net_address: NetworkAddress,
+        field: IdentityFieldType,
+    ) -> Result<bool> {
+        if !self.try_start(&net_address) {
+            return Err(RpcError {
+                code: ErrorCode::ServerError(COOLDOWN_ERROR_CODE),
+                message: format!(
+                    "a new challenge can only be requested every {} seconds",
+                    self.cooldown.as_secs()
+                ),
+                data: None,
+            });
+        }
+
+        let repo = Arc::clone(&self.repo);
+        let t_net_address = net_address.clone();
+        let result = self
+            .runtime
+            .spawn(async move {
+                let mut repo = repo.lock().await;
+                // Other services modify the identities, too.
+                repo.catch_up().await?;
+                repo.apply(VerifierCommand::RegenerateChallenge {
+                    net_address: t_net_address,
+                    field: field,
+                })
+                .await
+            })
+            .await;
+
+        match result.map_err(|err| anyhow!(err)).and_then(|result| result) {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                error!("Failed to regenerate challenge: {:?}", err);
+                self.cancel(&net_address);
+                Err(RpcError::internal_error())
+            }
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum RpcResponse<T, E> {
@@ -104,19 +266,37 @@ pub trait PublicRpc {
         _: Option<Self::Metadata>,
         _: SubscriptionId,
     ) -> Result<bool>;
+    /// Replaces the challenge of the field with a new one. The subscribers
+    /// of the identity receive the new state. The request must be signed by
+    /// the account of the identity (see `regenerate_message`).
+    #[rpc(name = "account_regenerateChallenge")]
+    fn regenerate_challenge(
+        &self,
+        network: BlankNetwork,
+        address: IdentityAddress,
+        field: IdentityFieldType,
+        timestamp: u64,
+        signature: String,
+    ) -> BoxFuture<Result<bool>>;
 }
 
 pub struct PublicRpcApi {
     connection_pool: ConnectionPool,
     manager: Arc<RwLock<IdentityManager>>,
+    regenerator: ChallengeRegenerator,
     active_sessions: Arc<RwLock<HashSet<SubscriptionId>>>,
 }
 
 impl PublicRpcApi {
-    pub fn new(pool: ConnectionPool, manager: Arc<RwLock<IdentityManager>>) -> Self {
+    pub fn new(
+        pool: ConnectionPool,
+        manager: Arc<RwLock<IdentityManager>>,
+        regenerator: ChallengeRegenerator,
+    ) -> Self {
         PublicRpcApi {
             connection_pool: pool,
             manager: manager,
+            regenerator: regenerator,
             active_sessions: Arc::new(RwLock::new(HashSet::new())),
         }
     }
@@ -132,6 +312,24 @@ impl PublicRpc for PublicRpcApi {
         network: BlankNetwork,
         address: IdentityAddress,
     ) {
+        // Invalid addresses are rejected before any state is created.
+        let net_address = match self
+            .connection_pool
+            .networks
+            .parse_address(&network, address.as_str())
+        {
+            Ok(net_address) => net_address,
+            Err(err) => {
+                debug!("Rejecting subscription: {}", err);
+                let _ = subscriber.reject(RpcError {
+                    code: ErrorCode::InvalidParams,
+                    message: err.to_string(),
+                    data: serde_json::to_value(ErrorMessage::invalid_address(&err)).ok(),
+                });
+                return;
+            }
+        };
+
         // Assign an ID to the subscriber.
         let sub_id = SubscriptionId::Number(NumericIdProvider::new().next_id());
         self.active_sessions.write().insert(sub_id.clone());
@@ -143,10 +341,10 @@ impl PublicRpc for PublicRpcApi {
             }
         };
 
-        let net_address = NetworkAddress::from(network, address);
         let mut watcher = self.connection_pool.watch_net_address(&net_address);
 
         let manager = Arc::clone(&self.manager);
+        let networks = self.connection_pool.networks.clone();
         let active_sessions = Arc::clone(&self.active_sessions);
 
         // Remove tracker of subscriber if session drops.
@@ -176,7 +374,7 @@ impl PublicRpc for PublicRpcApi {
                     }
                 } else {
                     if let Err(_) = sink.notify(Ok(AccountStatusResponse::Err(
-                        ErrorMessage::no_pending_judgement_request(REGISTRAR_IDX),
+                        networks.no_pending_judgement_request(&net_address),
                     ))) {
                         debug!("Connection closed");
                         return Ok(());
@@ -187,7 +385,9 @@ impl PublicRpc for PublicRpcApi {
                 let mut session_active = async {
                     tokio_02::time::delay_for(Duration::from_secs(1)).await;
                     active_sessions.read().contains(&sub_id)
-                }.boxed().fuse();
+                }
+                .boxed()
+                .fuse();
 
                 // Start event loop and keep the subscriber informed about any state changes.
                 loop {
@@ -200,7 +400,7 @@ impl PublicRpc for PublicRpcApi {
                                 };
 
                                 // Notify client.
-                                if let Err(_) = sink.notify(Ok(AccountStatusResponse::Ok(current_state))) {
+                                if let Err(_) = sink.notify(Ok(current_state)) {
                                     debug!("Connection closed");
                                     return Ok(());
                                 }
@@ -228,4 +428,49 @@ impl PublicRpc for PublicRpcApi {
         self.active_sessions.write().remove(&id);
         Ok(true)
     }
+    fn regenerate_challenge(
+        &self,
+        network: BlankNetwork,
+        address: IdentityAddress,
+        field: IdentityFieldType,
+        timestamp: u64,
+        signature: String,
+    ) -> BoxFuture<Result<bool>> {
+        let net_address = match self
+            .connection_pool
+            .networks
+            .parse_address(&network, address.as_str())
+        {
+            Ok(net_address) => net_address,
+            Err(err) => {
+                return Box::pin(future::err(RpcError {
+                    code: ErrorCode::InvalidParams,
+                    message: err.to_string(),
+                    data: serde_json::to_value(ErrorMessage::invalid_address(&err)).ok(),
+                }))
+            }
+        };
+
+        // Only the identity itself can request new challenges.
+        if let Err(err) = verify_regenerate_request(&net_address, &field, timestamp, &signature) {
+            debug!("Rejecting challenge regeneration: {:?}", err);
+            return Box::pin(future::err(RpcError {
+                code: ErrorCode::ServerError(SIGNATURE_ERROR_CODE),
+                message: err.to_string(),
+                data: None,
+            }));
+        }
+
+        // Requests which cannot be fulfilled are rejected before the cooldown
+        // starts, so the client learns the reason.
+        if let Err(err) = self
+            .manager
+            .read()
+            .regenerate_challenge(&net_address, &field)
+        {
+            return Box::pin(future::err(RpcError::invalid_params(err.to_string())));
+        }
+
+        Box::pin(self.regenerator.clone().regenerate(net_address, field))
+    }
 }
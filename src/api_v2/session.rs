@@ -1,12 +1,11 @@
+use crate::chain::Networks;
 use crate::event::{ErrorMessage, StateWrapper};
 use crate::manager::{IdentityState, NetworkAddress};
 use actix::prelude::*;
 use actix_broker::{BrokerIssue, BrokerSubscribe};
 use actix_web_actors::ws;
 use std::collections::HashMap;
-
-// TODO: Set via config.
-const REGISTRAR_IDX: usize = 0;
+use tokio::sync::broadcast;
 
 #[derive(Debug, Clone, Serialize, Message)]
 #[rtype(result = "()")]
@@ -16,8 +15,15 @@ enum MessageResult<T> {
     Err(ErrorMessage),
 }
 
-#[derive(Default)]
-pub struct WsAccountStatusSession;
+pub struct WsAccountStatusSession {
+    networks: Networks,
+}
+
+impl WsAccountStatusSession {
+    pub fn new(networks: Networks) -> Self {
+        WsAccountStatusSession { networks: networks }
+    }
+}
 
 impl Actor for WsAccountStatusSession {
     type Context = ws::WebsocketContext<Self>;
@@ -49,10 +55,24 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsAccountStatusSe
         match msg {
             ws::Message::Text(txt) => {
                 if let Ok(net_address) = serde_json::from_str::<NetworkAddress>(txt.as_str()) {
-                    self.issue_system_async(SubscribeAccountStatus {
-                        recipient: ctx.address().recipient(),
-                        net_address: net_address,
-                    });
+                    // Only valid addresses, in their canonical form, are
+                    // subscribed to.
+                    match self
+                        .networks
+                        .parse_address(&net_address.net(), net_address.address_str())
+                    {
+                        Ok(net_address) => self.issue_system_async(SubscribeAccountStatus {
+                            recipient: ctx.address().recipient(),
+                            net_address: net_address,
+                        }),
+                        Err(err) => {
+                            let msg: MessageResult<StateWrapper> =
+                                MessageResult::Err(ErrorMessage::invalid_address(&err));
+                            if let Ok(payload) = serde_json::to_string(&msg) {
+                                ctx.text(payload)
+                            }
+                        }
+                    }
                 } else {
                     ctx.text("Invalid message type");
                 }
@@ -76,6 +96,7 @@ struct SubscribeAccountStatus {
     net_address: NetworkAddress,
 }
 
+#[derive(Default)]
 pub struct WsAccountStatusServer {
     subscribers: HashMap<
         NetworkAddress,
@@ -84,6 +105,16 @@ pub struct WsAccountStatusServer {
             Vec<Recipient<MessageResult<StateWrapper>>>,
         ),
     >,
+    networks: Networks,
+}
+
+impl WsAccountStatusServer {
+    pub fn new(networks: Networks) -> Self {
+        WsAccountStatusServer {
+            subscribers: HashMap::new(),
+            networks: networks,
+        }
+    }
 }
 
 impl Actor for WsAccountStatusServer {
@@ -100,6 +131,7 @@ impl Handler<SubscribeAccountStatus> for WsAccountStatusServer {
 
     fn handle(&mut self, msg: SubscribeAccountStatus, _ctx: &mut Self::Context) -> Self::Result {
         let (recipient, net_address) = (msg.recipient, msg.net_address);
+        let no_pending = self.networks.no_pending_judgement_request(&net_address);
 
         self.subscribers
             .entry(net_address)
@@ -112,12 +144,7 @@ impl Handler<SubscribeAccountStatus> for WsAccountStatusServer {
                         return;
                     };
                 } else {
-                    if recipient
-                        .do_send(MessageResult::Err(
-                            ErrorMessage::no_pending_judgement_request(REGISTRAR_IDX),
-                        ))
-                        .is_err()
-                    {
+                    if recipient.do_send(MessageResult::Err(no_pending)).is_err() {
                         return;
                     };
                 }
@@ -130,6 +157,36 @@ impl Handler<SubscribeAccountStatus> for WsAccountStatusServer {
     }
 }
 
+/// A change of an account state, forwarded to the `WsAccountStatusServer`.
+#[derive(Debug, Clone)]
+pub enum AccountStateChange {
+    Add(StateWrapper),
+    Delete(NetworkAddress),
+}
+
+impl WsAccountStatusServer {
+    /// Forwards the received account state changes to the server.
+    pub async fn forward_changes(
+        addr: Addr<WsAccountStatusServer>,
+        mut changes: broadcast::Receiver<AccountStateChange>,
+    ) {
+        loop {
+            match changes.recv().await {
+                Ok(AccountStateChange::Add(state)) => {
+                    addr.do_send(AddAccountState { state: state })
+                }
+                Ok(AccountStateChange::Delete(net_address)) => addr.do_send(DeleteAccountState {
+                    net_address: net_address,
+                }),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Account status server skipped {} state changes", skipped)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Message)]
 #[rtype(result = "()")]
 struct AddAccountState {
@@ -168,7 +225,31 @@ impl Handler<AddAccountState> for WsAccountStatusServer {
 
 #[derive(Debug, Clone, Message)]
 #[rtype(result = "()")]
-// TODO
 struct DeleteAccountState {
-    state: IdentityState,
+    net_address: NetworkAddress,
+}
+
+// Handle removed account states, e.g. cancelled judgement requests. The
+// subscribers receive a final message and remain subscribed, in case a new
+// judgement is requested.
+impl Handler<DeleteAccountState> for WsAccountStatusServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteAccountState, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some((state, recipients)) = self.subscribers.get_mut(&msg.net_address) {
+            *state = None;
+
+            let to_notify = std::mem::take(recipients);
+            for recipient in to_notify {
+                if recipient
+                    .do_send(MessageResult::Err(
+                        self.networks.no_pending_judgement_request(&msg.net_address),
+                    ))
+                    .is_ok()
+                {
+                    recipients.push(recipient);
+                }
+            }
+        }
+    }
 }
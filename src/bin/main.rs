@@ -1 +1,89 @@
-fn main() {}
+#[macro_use]
+extern crate log;
+
+use registrar::{export_state, init_env, inspect, open_config, replay, run, Result};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "registrar-bot", about = "Polkadot registrar challenger")]
+struct Opt {
+    /// Path to the config file.
+    #[structopt(long, global = true, default_value = "/etc/registrar/config.json")]
+    config: String,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Runs the registrar service. This is the default if no command is
+    /// specified.
+    Serve {
+        /// Ignore the persisted projection checkpoints and process all events
+        /// from the very beginning.
+        #[structopt(long)]
+        rebuild_projections: bool,
+    },
+    /// Prints all events of the specified stream as JSON, one event per line.
+    Replay {
+        #[structopt(long)]
+        stream: String,
+    },
+    /// Prints the state of all pending judgement requests as JSON.
+    ExportState,
+    /// Prints the state of the specified identity as JSON.
+    Inspect { network: String, address: String },
+    /// Checks the config and reports every invalid or missing field.
+    CheckConfig,
+}
+
+async fn run_command(config_path: &str, command: Command) -> Result<()> {
+    match command {
+        Command::Serve {
+            rebuild_projections,
+        } => {
+            let config = init_env(config_path)?;
+            info!("Starting registrar service");
+            run(config, rebuild_projections).await?;
+        }
+        Command::Replay { stream } => {
+            replay(init_env(config_path)?, &stream).await?;
+        }
+        Command::ExportState => {
+            println!("{}", export_state(init_env(config_path)?).await?);
+        }
+        Command::Inspect { network, address } => {
+            match inspect(init_env(config_path)?, &network, &address).await? {
+                Some(state) => println!("{}", state),
+                None => println!("No pending judgement request found for {}", address),
+            }
+        }
+        // The config is only checked, the environment is not initialized.
+        Command::CheckConfig => match open_config(config_path) {
+            Ok(_) => println!("Config at '{}' is valid", config_path),
+            Err(errors) => {
+                eprint!("{}", errors);
+                std::process::exit(1);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+
+    if let Err(err) = run_command(
+        &opt.config,
+        opt.command.unwrap_or(Command::Serve {
+            rebuild_projections: false,
+        }),
+    )
+    .await
+    {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,60 @@
+//! The latest processed block of the watchers, so blocks which were finalized
+//! while the service was down are processed on startup.
+
+use super::BlockNumber;
+use crate::store::{EventData, EventStore};
+use crate::Result;
+use std::sync::Arc;
+
+/// The latest processed block, persisted in the event store.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct BlockCheckpoint {
+    block: BlockNumber,
+}
+
+/// Persists the latest processed block of a watcher on its own stream.
+pub struct BlockCheckpoints {
+    store: Arc<dyn EventStore>,
+    stream: String,
+}
+
+impl BlockCheckpoints {
+    pub fn new(store: Arc<dyn EventStore>, stream: String) -> Self {
+        BlockCheckpoints {
+            store: store,
+            stream: stream,
+        }
+    }
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+    pub async fn load(&self) -> Result<Option<BlockNumber>> {
+        match self
+            .store
+            .read_from_end(&self.stream, 1)
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(recorded) => Ok(Some(
+                recorded
+                    .as_json::<BlockCheckpoint>()
+                    .map_err(|err| anyhow!("failed to parse block checkpoint: {:?}", err))?
+                    .block,
+            )),
+            None => Ok(None),
+        }
+    }
+    pub async fn save(&self, block: BlockNumber) -> Result<()> {
+        // Only the latest checkpoint is kept.
+        self.store
+            .replace(
+                &self.stream,
+                EventData::json("registrar-checkpoint", BlockCheckpoint { block: block })?,
+            )
+            .await
+            .map_err(|err| anyhow!("failed to persist block checkpoint: {:?}", err))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use super::{
+    AccountId, BlockClient, BlockNumber, ChainClient, IdentityEvent, Judgement, JudgementSigner,
+    NotSubmitted, ProvideJudgementCall, RegistrarIndex, Registration,
+};
+use crate::Result;
+use parity_scale_codec::{Decode, Encode, Error as CodecError, Input};
+use substrate_subxt::events::Raw;
+use substrate_subxt::sp_core::storage::StorageKey;
+use substrate_subxt::sp_core::{sr25519, twox_128, twox_64, Pair};
+use substrate_subxt::{
+    Call, Client, ClientBuilder, DefaultNodeRuntime, Error as SubxtError, EventsDecoder, PairSigner,
+};
+
+async fn build_client(endpoint: &str) -> Result<Client<DefaultNodeRuntime>> {
+    ClientBuilder::<DefaultNodeRuntime>::new()
+        .set_url(endpoint)
+        .skip_type_sizes_check()
+        .build()
+        .await
+        .map_err(|err| anyhow!("failed to connect to {}: {:?}", endpoint, err))
+}
+
+/// A storage value which is not decoded, e.g. the events of a block, which
+/// are decoded with the `EventsDecoder` instead.
+struct RawStorage(Vec<u8>);
+
+impl Decode for RawStorage {
+    fn decode<I: Input>(input: &mut I) -> std::result::Result<Self, CodecError> {
+        let len = input
+            .remaining_len()?
+            .ok_or_else(|| CodecError::from("unknown length of storage value"))?;
+
+        let mut data = vec![0; len];
+        input.read(&mut data)?;
+
+        Ok(RawStorage(data))
+    }
+}
+
+/// Fetches the finalized blocks, their events and the identities from the
+/// storage of a node.
+pub struct SubxtClient {
+    client: Client<DefaultNodeRuntime>,
+    decoder: EventsDecoder<DefaultNodeRuntime>,
+}
+
+impl SubxtClient {
+    pub async fn connect(endpoint: &str) -> Result<Self> {
+        let client = build_client(endpoint).await?;
+
+        // Required to decode the events of the identity pallet.
+        let mut decoder = client.events_decoder().clone();
+        decoder.register_type_size::<RegistrarIndex>("RegistrarIndex");
+
+        Ok(SubxtClient {
+            client: client,
+            decoder: decoder,
+        })
+    }
+    async fn finalized_number(&self) -> Result<BlockNumber> {
+        let hash = self
+            .client
+            .finalized_head()
+            .await
+            .map_err(|err| anyhow!("failed to fetch finalized head: {:?}", err))?;
+
+        let header = self
+            .client
+            .header(Some(hash))
+            .await
+            .map_err(|err| anyhow!("failed to fetch block header: {:?}", err))?
+            .ok_or(anyhow!("finalized block header not found"))?;
+
+        Ok(header.number)
+    }
+}
+
+#[async_trait]
+impl ChainClient for SubxtClient {
+    async fn finalized_block(&self) -> Result<BlockNumber> {
+        self.finalized_number().await
+    }
+    async fn events(&self, number: BlockNumber) -> Result<Vec<IdentityEvent>> {
+        let hash = self
+            .client
+            .block_hash(Some(number.into()))
+            .await
+            .map_err(|err| anyhow!("failed to fetch hash of block {}: {:?}", number, err))?
+            .ok_or(anyhow!("block {} not found", number))?;
+
+        // `System::Events`.
+        let mut key = twox_128(b"System").to_vec();
+        key.extend(&twox_128(b"Events"));
+
+        let data = match self
+            .client
+            .fetch_unhashed::<RawStorage>(StorageKey(key), Some(hash))
+            .await
+            .map_err(|err| anyhow!("failed to fetch events of block {}: {:?}", number, err))?
+        {
+            Some(data) => data.0,
+            None => return Ok(vec![]),
+        };
+
+        let raw_events = self
+            .decoder
+            .decode_events(&mut data.as_slice())
+            .map_err(|err| anyhow!("failed to decode events: {:?}", err))?;
+
+        let mut events = vec![];
+        for (_, raw) in raw_events {
+            if let Raw::Event(raw) = raw {
+                if let Some(event) = IdentityEvent::decode(&raw.module, &raw.variant, &raw.data)? {
+                    events.push(event);
+                }
+            }
+        }
+
+        Ok(events)
+    }
+    async fn registration(&self, who: &AccountId) -> Result<Option<Registration>> {
+        // `Identity::IdentityOf`, hashed with `Twox64Concat`.
+        let mut key = twox_128(b"Identity").to_vec();
+        key.extend(&twox_128(b"IdentityOf"));
+        key.extend(&twox_64(who));
+        key.extend(who);
+
+        self.client
+            .fetch_unhashed::<Registration>(StorageKey(key), None)
+            .await
+            .map_err(|err| anyhow!("failed to fetch identity: {:?}", err))
+    }
+}
+
+#[async_trait]
+impl BlockClient for SubxtClient {
+    async fn finalized_block(&self) -> Result<BlockNumber> {
+        self.finalized_number().await
+    }
+    async fn extrinsics(&self, number: BlockNumber) -> Result<Vec<Vec<u8>>> {
+        let hash = self
+            .client
+            .block_hash(Some(number.into()))
+            .await
+            .map_err(|err| anyhow!("failed to fetch hash of block {}: {:?}", number, err))?
+            .ok_or(anyhow!("block {} not found", number))?;
+
+        let block = self
+            .client
+            .block(Some(hash))
+            .await
+            .map_err(|err| anyhow!("failed to fetch block {}: {:?}", number, err))?
+            .ok_or(anyhow!("block {} not found", number))?;
+
+        Ok(block
+            .block
+            .extrinsics
+            .iter()
+            .map(|extrinsic| extrinsic.encode())
+            .collect())
+    }
+}
+
+impl Call<DefaultNodeRuntime> for ProvideJudgementCall {
+    const MODULE: &'static str = "Identity";
+    const FUNCTION: &'static str = "provide_judgement";
+}
+
+/// Signs the judgements with the registrar key, derived from the configured
+/// seed.
+pub struct KeystoreSigner {
+    client: Client<DefaultNodeRuntime>,
+    signer: PairSigner<DefaultNodeRuntime, sr25519::Pair>,
+}
+
+impl KeystoreSigner {
+    pub async fn connect(endpoint: &str, seed: &str) -> Result<Self> {
+        let pair = sr25519::Pair::from_string(seed, None)
+            .map_err(|err| anyhow!("invalid registrar seed: {:?}", err))?;
+
+        Ok(KeystoreSigner {
+            client: build_client(endpoint).await?,
+            signer: PairSigner::new(pair),
+        })
+    }
+}
+
+#[async_trait]
+impl JudgementSigner for KeystoreSigner {
+    async fn provide_judgement(
+        &self,
+        registrar_index: RegistrarIndex,
+        target: &AccountId,
+        judgement: Judgement,
+    ) -> Result<()> {
+        let call = ProvideJudgementCall {
+            registrar_index: registrar_index,
+            target: *target,
+            judgement: judgement,
+        };
+
+        // Nothing was sent to the node yet, e.g. the nonce could not be
+        // fetched.
+        let extrinsic = self
+            .client
+            .create_signed(call, &self.signer)
+            .await
+            .map_err(|err| NotSubmitted(format!("failed to sign judgement: {:?}", err)))?;
+
+        // Fails if the extrinsic is not finalized or its dispatch failed.
+        let success = self
+            .client
+            .submit_and_watch_extrinsic(extrinsic)
+            .await
+            .map_err(submission_error)?;
+
+        debug!("Judgement finalized in block {:?}", success.block);
+
+        Ok(())
+    }
+}
+
+/// Classifies the error of a submitted extrinsic. Errors which certainly left
+/// the judgement unapplied are reported as `NotSubmitted`.
+fn submission_error(err: SubxtError) -> anyhow::Error {
+    let not_submitted = match &err {
+        // The node could not be reached or rejected the extrinsic before it
+        // entered the pool, e.g. because of a bad nonce or insufficient
+        // fees. Only a dropped subscription leaves the outcome open.
+        SubxtError::Rpc(rpc) => !format!("{:?}", rpc).contains("subscription dropped"),
+        // The extrinsic was included, but its dispatch failed.
+        SubxtError::Runtime(_) => true,
+        // The extrinsic was removed from the pool without being included.
+        SubxtError::Other(reason) => {
+            reason.contains("Invalid") || reason.contains("Usurped") || reason.contains("Dropped")
+        }
+        _ => false,
+    };
+
+    if not_submitted {
+        NotSubmitted(format!("failed to submit judgement: {:?}", err)).into()
+    } else {
+        anyhow!("failed to submit judgement: {:?}", err)
+    }
+}
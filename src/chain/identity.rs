@@ -0,0 +1,299 @@
+//! The types of the identity pallet, as stored on-chain. Only the parts
+//! required by the registrar are decoded.
+
+use crate::event::JudgementKind;
+use crate::manager::{DisplayName, FieldAddress, IdentityField};
+use crate::Result;
+use parity_scale_codec::{Compact, Decode, Encode, Error as CodecError, Input, Output};
+
+pub type AccountId = [u8; 32];
+pub type RegistrarIndex = u32;
+pub type Balance = u128;
+
+/// A value of an identity field. Only raw values can be verified, hashed
+/// values are never disclosed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Data {
+    None,
+    Raw(Vec<u8>),
+    BlakeTwo256([u8; 32]),
+    Sha256([u8; 32]),
+    Keccak256([u8; 32]),
+    ShaThree256([u8; 32]),
+}
+
+impl Data {
+    fn is_none(&self) -> bool {
+        self == &Data::None
+    }
+    /// Returns the raw value as a string, if it's valid UTF-8.
+    fn as_string(&self) -> Option<String> {
+        match self {
+            Data::Raw(raw) => String::from_utf8(raw.clone()).ok(),
+            _ => None,
+        }
+    }
+}
+
+// The variant index of raw values encodes the length, so this cannot be
+// derived.
+impl Decode for Data {
+    fn decode<I: Input>(input: &mut I) -> std::result::Result<Self, CodecError> {
+        let data = match input.read_byte()? {
+            0 => Data::None,
+            n @ 1..=33 => {
+                let mut raw = vec![0; n as usize - 1];
+                input.read(&mut raw)?;
+                Data::Raw(raw)
+            }
+            34 => Data::BlakeTwo256(Decode::decode(input)?),
+            35 => Data::Sha256(Decode::decode(input)?),
+            36 => Data::Keccak256(Decode::decode(input)?),
+            37 => Data::ShaThree256(Decode::decode(input)?),
+            _ => return Err("invalid identity data".into()),
+        };
+
+        Ok(data)
+    }
+}
+
+impl Encode for Data {
+    fn encode_to<T: Output>(&self, dest: &mut T) {
+        match self {
+            Data::None => dest.push_byte(0),
+            Data::Raw(raw) => {
+                dest.push_byte(raw.len() as u8 + 1);
+                dest.write(raw);
+            }
+            Data::BlakeTwo256(hash) => {
+                dest.push_byte(34);
+                dest.write(hash);
+            }
+            Data::Sha256(hash) => {
+                dest.push_byte(35);
+                dest.write(hash);
+            }
+            Data::Keccak256(hash) => {
+                dest.push_byte(36);
+                dest.write(hash);
+            }
+            Data::ShaThree256(hash) => {
+                dest.push_byte(37);
+                dest.write(hash);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub enum Judgement {
+    Unknown,
+    FeePaid(Balance),
+    Reasonable,
+    KnownGood,
+    OutOfDate,
+    LowQuality,
+    Erroneous,
+}
+
+impl From<JudgementKind> for Judgement {
+    fn from(val: JudgementKind) -> Self {
+        match val {
+            JudgementKind::Reasonable => Judgement::Reasonable,
+            JudgementKind::LowQuality => Judgement::LowQuality,
+            JudgementKind::Erroneous => Judgement::Erroneous,
+        }
+    }
+}
+
+impl Judgement {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Judgement::Unknown => "unknown",
+            Judgement::FeePaid(_) => "fee_paid",
+            Judgement::Reasonable => "reasonable",
+            Judgement::KnownGood => "known_good",
+            Judgement::OutOfDate => "out_of_date",
+            Judgement::LowQuality => "low_quality",
+            Judgement::Erroneous => "erroneous",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct IdentityInfo {
+    pub additional: Vec<(Data, Data)>,
+    pub display: Data,
+    pub legal: Data,
+    pub web: Data,
+    pub riot: Data,
+    pub email: Data,
+    pub pgp_fingerprint: Option<[u8; 20]>,
+    pub image: Data,
+    pub twitter: Data,
+}
+
+impl IdentityInfo {
+    /// Converts the on-chain fields into identity fields. Empty fields are
+    /// skipped, as are hashed values and values which are not valid UTF-8,
+    /// since those cannot be verified. Fields without a challenge, such as the
+    /// legal name or the website, are kept to be shown to the user, but are
+    /// not required for the judgement.
+    pub fn fields(&self) -> Vec<IdentityField> {
+        let mut fields = vec![];
+
+        let text_fields: [(&Data, fn(String) -> IdentityField); 6] = [
+            (&self.display, |val| {
+                IdentityField::DisplayName(DisplayName::from(val))
+            }),
+            (&self.legal, |val| {
+                IdentityField::LegalName(FieldAddress::from(val))
+            }),
+            (&self.web, |val| IdentityField::Web(FieldAddress::from(val))),
+            (&self.riot, |val| {
+                IdentityField::Matrix(FieldAddress::from(val))
+            }),
+            (&self.email, |val| {
+                IdentityField::Email(FieldAddress::from(val))
+            }),
+            (&self.twitter, |val| {
+                IdentityField::Twitter(FieldAddress::from(val))
+            }),
+        ];
+
+        for (data, field) in text_fields.iter() {
+            if data.is_none() {
+                continue;
+            }
+
+            match data.as_string() {
+                Some(val) => fields.push(field(val)),
+                None => warn!("Skipping identity field which is hashed or not valid UTF-8"),
+            }
+        }
+
+        if let Some(fingerprint) = self.pgp_fingerprint {
+            fields.push(IdentityField::PGPFingerprint(FieldAddress::from(
+                hex::encode(fingerprint),
+            )));
+        }
+
+        if !self.image.is_none() {
+            fields.push(IdentityField::Image);
+        }
+
+        if !self.additional.is_empty() {
+            fields.push(IdentityField::Additional);
+        }
+
+        fields
+    }
+}
+
+/// The on-chain identity of an account, including the judgements.
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct Registration {
+    pub judgements: Vec<(RegistrarIndex, Judgement)>,
+    pub deposit: Balance,
+    pub info: IdentityInfo,
+}
+
+impl Registration {
+    /// Whether a judgement was requested from the registrar and not given
+    /// yet.
+    pub fn is_pending(&self, registrar_index: RegistrarIndex) -> bool {
+        self.judgements
+            .iter()
+            .any(|(index, judgement)| match judgement {
+                Judgement::FeePaid(_) => *index == registrar_index,
+                _ => false,
+            })
+    }
+    /// The judgement given by the registrar, if any.
+    pub fn judgement(&self, registrar_index: RegistrarIndex) -> Option<&Judgement> {
+        self.judgements
+            .iter()
+            .find(|(index, judgement)| {
+                *index == registrar_index && !matches!(judgement, Judgement::FeePaid(_))
+            })
+            .map(|(_, judgement)| judgement)
+    }
+}
+
+/// The `identity.provideJudgement` call of the registrar.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProvideJudgementCall {
+    pub registrar_index: RegistrarIndex,
+    pub target: AccountId,
+    pub judgement: Judgement,
+}
+
+// The target is a `MultiAddress`, of which only the `Id` variant is used.
+impl Encode for ProvideJudgementCall {
+    fn encode_to<T: Output>(&self, dest: &mut T) {
+        Compact(self.registrar_index).encode_to(dest);
+        dest.push_byte(0);
+        self.target.encode_to(dest);
+        self.judgement.encode_to(dest);
+    }
+}
+
+/// The events of the identity pallet which are relevant to the registrar.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum IdentityEvent {
+    IdentitySet {
+        who: AccountId,
+    },
+    /// The identity was cleared by the user or killed by governance.
+    IdentityCleared {
+        who: AccountId,
+    },
+    JudgementRequested {
+        who: AccountId,
+        registrar_index: RegistrarIndex,
+    },
+    JudgementUnrequested {
+        who: AccountId,
+        registrar_index: RegistrarIndex,
+    },
+}
+
+impl IdentityEvent {
+    /// Decodes the event of the given module and variant. Returns `None` for
+    /// all irrelevant events.
+    pub fn decode(module: &str, variant: &str, mut data: &[u8]) -> Result<Option<Self>> {
+        if module != "Identity" {
+            return Ok(None);
+        }
+
+        let input = &mut data;
+        let event = match variant {
+            "IdentitySet" => IdentityEvent::IdentitySet {
+                who: Decode::decode(input)?,
+            },
+            // The deposit which follows is not relevant.
+            "IdentityCleared" | "IdentityKilled" => IdentityEvent::IdentityCleared {
+                who: Decode::decode(input)?,
+            },
+            "JudgementRequested" => IdentityEvent::JudgementRequested {
+                who: Decode::decode(input)?,
+                registrar_index: Decode::decode(input)?,
+            },
+            "JudgementUnrequested" => IdentityEvent::JudgementUnrequested {
+                who: Decode::decode(input)?,
+                registrar_index: Decode::decode(input)?,
+            },
+            _ => return Ok(None),
+        };
+
+        Ok(Some(event))
+    }
+    pub fn who(&self) -> &AccountId {
+        match self {
+            IdentityEvent::IdentitySet { who }
+            | IdentityEvent::IdentityCleared { who }
+            | IdentityEvent::JudgementRequested { who, .. }
+            | IdentityEvent::JudgementUnrequested { who, .. } => who,
+        }
+    }
+}
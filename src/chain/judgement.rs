@@ -0,0 +1,134 @@
+//! Submission of the judgements, either signed locally or delegated to an
+//! external watcher which holds the registrar keys.
+
+use super::{AccountId, Judgement, RegistrarIndex};
+use crate::event::BlankNetwork;
+use crate::Result;
+use tokio::time::{self, Duration};
+use tungstenite::Message;
+
+/// Seconds to wait for the watcher to report the finalized judgement.
+const WATCHER_TIMEOUT: u64 = 300;
+
+/// The judgement was certainly not submitted on-chain, so it can safely be
+/// submitted again. All other errors leave it open whether the extrinsic is
+/// included eventually.
+#[derive(Debug, Clone, Error)]
+#[error("judgement was not submitted: {0}")]
+pub struct NotSubmitted(pub String);
+
+/// Submits the judgements of the registrar on a single network.
+#[async_trait]
+pub trait JudgementSigner: Send + Sync {
+    /// Submits `identity.provideJudgement` and returns once the extrinsic is
+    /// finalized. Returns `NotSubmitted` if the extrinsic was certainly not
+    /// submitted.
+    async fn provide_judgement(
+        &self,
+        registrar_index: RegistrarIndex,
+        target: &AccountId,
+        judgement: Judgement,
+    ) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "camelCase")]
+enum WatcherRequest {
+    ProvideJudgement {
+        network: BlankNetwork,
+        registrar_index: RegistrarIndex,
+        target: String,
+        judgement: String,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "camelCase")]
+enum WatcherResponse {
+    JudgementFinalized { target: String },
+    Error { message: String },
+}
+
+/// Delegates the judgements to an external watcher over websocket. A new
+/// connection is opened for each judgement.
+pub struct WatcherSigner {
+    url: String,
+    network: BlankNetwork,
+}
+
+impl WatcherSigner {
+    pub fn new(url: String, network: BlankNetwork) -> Self {
+        WatcherSigner {
+            url: url,
+            network: network,
+        }
+    }
+}
+
+#[async_trait]
+impl JudgementSigner for WatcherSigner {
+    async fn provide_judgement(
+        &self,
+        registrar_index: RegistrarIndex,
+        target: &AccountId,
+        judgement: Judgement,
+    ) -> Result<()> {
+        let target = format!("0x{}", hex::encode(target));
+        let request = serde_json::to_string(&WatcherRequest::ProvideJudgement {
+            network: self.network.clone(),
+            registrar_index: registrar_index,
+            target: target.clone(),
+            judgement: judgement.as_str().to_string(),
+        })?;
+
+        // The websocket client is blocking.
+        let url = self.url.clone();
+        let submission = tokio::task::spawn_blocking(move || -> Result<()> {
+            let (mut socket, _) = tungstenite::connect(url.as_str()).map_err(|err| {
+                NotSubmitted(format!(
+                    "failed to connect to watcher at {}: {:?}",
+                    url, err
+                ))
+            })?;
+
+            socket
+                .write_message(Message::Text(request))
+                .map_err(|err| {
+                    NotSubmitted(format!("failed to send judgement to watcher: {:?}", err))
+                })?;
+
+            loop {
+                let text = match socket
+                    .read_message()
+                    .map_err(|err| anyhow!("failed to receive response of watcher: {:?}", err))?
+                {
+                    Message::Text(text) => text,
+                    Message::Close(_) => return Err(anyhow!("watcher closed the connection")),
+                    _ => continue,
+                };
+
+                match serde_json::from_str::<WatcherResponse>(&text)
+                    .map_err(|err| anyhow!("failed to parse response of watcher: {:?}", err))?
+                {
+                    WatcherResponse::JudgementFinalized { target: finalized }
+                        if finalized == target =>
+                    {
+                        return Ok(())
+                    }
+                    WatcherResponse::JudgementFinalized { .. } => continue,
+                    WatcherResponse::Error { message } => {
+                        return Err(NotSubmitted(format!(
+                            "watcher failed to provide judgement: {}",
+                            message
+                        ))
+                        .into())
+                    }
+                }
+            }
+        });
+
+        time::timeout(Duration::from_secs(WATCHER_TIMEOUT), submission)
+            .await
+            .map_err(|_| anyhow!("watcher did not finalize the judgement in time"))??
+    }
+}
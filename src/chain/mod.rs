@@ -0,0 +1,423 @@
+use crate::aggregate::verifier::{RemovalReason, VerifierAggregate, VerifierCommand};
+use crate::aggregate::Repository;
+use crate::event::{BlankNetwork, ErrorMessage};
+use crate::manager::{
+    FieldAddress, FieldStatus, IdentityAddress, IdentityField, IdentityState, NetworkAddress,
+    RegistrarIdentityField,
+};
+use crate::store::EventStore;
+use crate::{NetworkConfig, RegistrarConfig, Result};
+use checkpoint::BlockCheckpoints;
+use std::collections::HashMap;
+use std::sync::Arc;
+use substrate_subxt::sp_core::crypto::{AccountId32, Ss58AddressFormat, Ss58Codec};
+use substrate_subxt::sp_core::{sr25519, Pair};
+use tokio::time::{self, Duration};
+
+mod checkpoint;
+mod client;
+mod identity;
+mod judgement;
+mod remark;
+pub use client::{KeystoreSigner, SubxtClient};
+pub use identity::{
+    AccountId, Data, IdentityEvent, IdentityInfo, Judgement, ProvideJudgementCall, RegistrarIndex,
+    Registration,
+};
+pub use judgement::{JudgementSigner, NotSubmitted, WatcherSigner};
+pub use remark::{decode_remarks, BlockClient, BlockNumber, RemarkScanner};
+
+/// Seconds between the checks for newly finalized blocks with judgement
+/// requests.
+const WATCH_INTERVAL: u64 = 6;
+/// How many blocks are processed before the checkpoint is persisted, while
+/// catching up on older blocks.
+const CHECKPOINT_EVERY: BlockNumber = 100;
+
+/// The source of the identity events and the on-chain identities. Implemented
+/// by `SubxtClient` for live networks, and by recorded fixtures in tests.
+#[async_trait]
+pub trait ChainClient: Send + Sync {
+    /// The number of the latest finalized block.
+    async fn finalized_block(&self) -> Result<BlockNumber>;
+    /// The identity events of the block with the given number.
+    async fn events(&self, number: BlockNumber) -> Result<Vec<IdentityEvent>>;
+    /// Fetches the current on-chain identity of the account, if any.
+    async fn registration(&self, who: &AccountId) -> Result<Option<Registration>>;
+}
+
+/// An address sent by a client which does not belong to the network.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum AddressError {
+    #[error("invalid address '{address}': {reason}")]
+    Invalid { address: String, reason: String },
+    #[error("address '{address}' is not a {network} address")]
+    WrongNetwork {
+        address: String,
+        network: BlankNetwork,
+    },
+    #[error("network '{0}' is not supported")]
+    UnknownNetwork(BlankNetwork),
+}
+
+// The SS58 prefix and the index of the utility pallet of the networks which
+// do not need to be specified in the config.
+const KNOWN_NETWORKS: [(&str, u8, Option<u8>); 2] =
+    [("polkadot", 0, Some(26)), ("kusama", 2, Some(24))];
+
+/// The chain parameters of a network.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChainSpec {
+    pub network: BlankNetwork,
+    /// The SS58 prefix of the addresses.
+    pub ss58_prefix: u8,
+    /// The index of the utility pallet. Without it, remarks inside of
+    /// batches are not detected.
+    pub utility_pallet: Option<u8>,
+}
+
+impl ChainSpec {
+    /// The chain parameters of Polkadot or Kusama.
+    pub fn known(network: &str) -> Option<Self> {
+        KNOWN_NETWORKS
+            .iter()
+            .find(|(name, _, _)| *name == network)
+            .map(|(name, ss58_prefix, utility_pallet)| ChainSpec {
+                network: BlankNetwork::from(*name),
+                ss58_prefix: *ss58_prefix,
+                utility_pallet: *utility_pallet,
+            })
+    }
+}
+
+/// Encodes the account as an address of the network.
+pub fn net_address(spec: &ChainSpec, who: &AccountId) -> NetworkAddress {
+    NetworkAddress::from(
+        spec.network.clone(),
+        IdentityAddress::from(
+            AccountId32::from(*who)
+                .to_ss58check_with_version(Ss58AddressFormat::Custom(spec.ss58_prefix)),
+        ),
+    )
+}
+
+/// Validates the SS58 address (including its checksum) and re-encodes it
+/// with the prefix of the network, so each account has a single spelling.
+/// Generic Substrate addresses are accepted for any network, while addresses
+/// of other networks are rejected.
+pub fn parse_address(
+    spec: &ChainSpec,
+    address: &str,
+) -> std::result::Result<NetworkAddress, AddressError> {
+    let (account, format) =
+        AccountId32::from_ss58check_with_version(address).map_err(|err| AddressError::Invalid {
+            address: address.to_string(),
+            reason: format!("{:?}", err),
+        })?;
+
+    if u8::from(format) != spec.ss58_prefix && format != Ss58AddressFormat::SubstrateAccount {
+        return Err(AddressError::WrongNetwork {
+            address: address.to_string(),
+            network: spec.network.clone(),
+        });
+    }
+
+    Ok(net_address(spec, &account.into()))
+}
+
+/// The configured networks, as served by the APIs. Without any configured
+/// networks, Polkadot and Kusama are served with registrar index zero.
+#[derive(Debug, Clone)]
+pub struct Networks {
+    networks: Arc<HashMap<BlankNetwork, (ChainSpec, RegistrarIndex)>>,
+}
+
+impl Default for Networks {
+    fn default() -> Self {
+        Networks {
+            networks: Arc::new(
+                KNOWN_NETWORKS
+                    .iter()
+                    .filter_map(|(name, _, _)| ChainSpec::known(name))
+                    .map(|spec| (spec.network.clone(), (spec, 0)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Networks {
+    pub fn new(networks: &[NetworkConfig]) -> Self {
+        if networks.is_empty() {
+            return Self::default();
+        }
+
+        Networks {
+            networks: Arc::new(
+                networks
+                    .iter()
+                    .map(|network| {
+                        (
+                            network.network.clone(),
+                            (network.chain_spec(), network.registrar_index),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+    pub fn registrar_index(&self, network: &BlankNetwork) -> RegistrarIndex {
+        self.networks
+            .get(network)
+            .map(|(_, index)| *index)
+            .unwrap_or(0)
+    }
+    pub fn no_pending_judgement_request(&self, net_address: &NetworkAddress) -> ErrorMessage {
+        ErrorMessage::no_pending_judgement_request(self.registrar_index(&net_address.net()))
+    }
+    /// Validates the address of a client, see `parse_address`.
+    pub fn parse_address(
+        &self,
+        network: &BlankNetwork,
+        address: &str,
+    ) -> std::result::Result<NetworkAddress, AddressError> {
+        let (spec, _) = self
+            .networks
+            .get(network)
+            .ok_or_else(|| AddressError::UnknownNetwork(network.clone()))?;
+
+        parse_address(spec, address)
+    }
+}
+
+/// Decodes the account of the address.
+pub fn account_id(net_address: &NetworkAddress) -> Result<AccountId> {
+    let account = AccountId32::from_ss58check(net_address.address_str())
+        .map_err(|err| anyhow!("invalid address {}: {:?}", net_address.address_str(), err))?;
+
+    Ok(account.into())
+}
+
+/// Verifies the hex encoded sr25519 signature of the message by the account.
+/// Browser extensions wrap the message in `<Bytes>..</Bytes>` before signing,
+/// which is accepted as well.
+pub fn verify_signature(
+    net_address: &NetworkAddress,
+    message: &str,
+    signature: &str,
+) -> Result<()> {
+    let raw = hex::decode(signature.trim_start_matches("0x"))
+        .map_err(|err| anyhow!("signature is not hex encoded: {:?}", err))?;
+
+    if raw.len() != 64 {
+        return Err(anyhow!(
+            "signature must be 64 bytes long, got {}",
+            raw.len()
+        ));
+    }
+
+    let mut bytes = [0; 64];
+    bytes.copy_from_slice(&raw);
+    let signature = sr25519::Signature::from_raw(bytes);
+    let public = sr25519::Public::from_raw(account_id(net_address)?);
+
+    let wrapped = format!("<Bytes>{}</Bytes>", message);
+    if sr25519::Pair::verify(&signature, message, &public)
+        || sr25519::Pair::verify(&signature, wrapped, &public)
+    {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "invalid signature of {}",
+            net_address.address_str()
+        ))
+    }
+}
+
+/// Watches the judgement requests of the registrar on a single network and
+/// inserts, updates or removes the corresponding identities. Blocks which
+/// were finalized while the service was down are processed on startup.
+pub struct ChainWatcher<C> {
+    client: C,
+    repo: Repository<VerifierAggregate>,
+    spec: ChainSpec,
+    registrar_index: RegistrarIndex,
+    registrar: RegistrarConfig,
+    checkpoints: BlockCheckpoints,
+    // The latest processed block, once known.
+    last_block: Option<BlockNumber>,
+}
+
+impl<C: ChainClient> ChainWatcher<C> {
+    pub fn new(
+        client: C,
+        repo: Repository<VerifierAggregate>,
+        store: Arc<dyn EventStore>,
+        config: &NetworkConfig,
+    ) -> Self {
+        let checkpoint_stream = format!("chain_watcher_{}_checkpoints", config.network);
+
+        ChainWatcher {
+            client: client,
+            repo: repo,
+            spec: config.chain_spec(),
+            registrar_index: config.registrar_index,
+            registrar: config.registrar.clone(),
+            checkpoints: BlockCheckpoints::new(store, checkpoint_stream),
+            last_block: None,
+        }
+    }
+    /// Processes the new blocks every `WATCH_INTERVAL` seconds. Only returns
+    /// on error.
+    pub async fn run_blocking(mut self) -> Result<()> {
+        loop {
+            self.scan().await?;
+            time::sleep(Duration::from_secs(WATCH_INTERVAL)).await;
+        }
+    }
+    /// Processes the events of all blocks up to the latest finalized block,
+    /// starting after the persisted checkpoint. Without a checkpoint, only
+    /// the latest finalized block is processed.
+    pub async fn scan(&mut self) -> Result<()> {
+        let finalized = self.client.finalized_block().await?;
+
+        let start = match self.last_block {
+            Some(block) => block + 1,
+            None => match self.checkpoints.load().await? {
+                Some(block) => {
+                    info!(
+                        "Resuming chain watcher on stream '{}' after block {}",
+                        self.checkpoints.stream(),
+                        block
+                    );
+                    block + 1
+                }
+                None => finalized,
+            },
+        };
+
+        for number in start..=finalized {
+            let events = self.client.events(number).await?;
+            let changed = !events.is_empty();
+
+            for event in events {
+                self.handle(event).await?;
+            }
+
+            // Blocks with identity events are not processed twice.
+            self.last_block = Some(number);
+            if changed || number == finalized || number % CHECKPOINT_EVERY == 0 {
+                self.checkpoints.save(number).await?;
+            }
+        }
+
+        Ok(())
+    }
+    async fn handle(&mut self, event: IdentityEvent) -> Result<()> {
+        let net_address = net_address(&self.spec, event.who());
+
+        let command = match event {
+            IdentityEvent::JudgementRequested {
+                who,
+                registrar_index,
+            } if registrar_index == self.registrar_index => {
+                info!(
+                    "Judgement requested by {} on {}",
+                    net_address.address_str(),
+                    net_address.net_str()
+                );
+
+                match self.pending_identity(&who, net_address).await? {
+                    Some(identity) => VerifierCommand::RequestJudgement(identity),
+                    None => return Ok(()),
+                }
+            }
+            IdentityEvent::JudgementUnrequested {
+                registrar_index, ..
+            } if registrar_index == self.registrar_index => {
+                info!(
+                    "Judgement request cancelled by {} on {}",
+                    net_address.address_str(),
+                    net_address.net_str()
+                );
+
+                VerifierCommand::RemoveIdentity {
+                    net_address: net_address,
+                    reason: RemovalReason::JudgementRequestCancelled,
+                }
+            }
+            // Pending judgement requests remain when the identity is changed,
+            // so the state must be updated.
+            IdentityEvent::IdentitySet { who } => {
+                match self.pending_identity(&who, net_address).await? {
+                    Some(identity) => VerifierCommand::InsertIdentity(identity),
+                    None => return Ok(()),
+                }
+            }
+            IdentityEvent::IdentityCleared { .. } => VerifierCommand::RemoveIdentity {
+                net_address: net_address,
+                reason: RemovalReason::IdentityCleared,
+            },
+            // Requests to other registrars.
+            _ => return Ok(()),
+        };
+
+        // Other services modify the identities, too.
+        self.repo.catch_up().await?;
+        self.repo.apply(command).await
+    }
+    /// Fetches the on-chain identity and converts it into the identity state,
+    /// if a judgement request to the registrar is pending.
+    async fn pending_identity(
+        &self,
+        who: &AccountId,
+        net_address: NetworkAddress,
+    ) -> Result<Option<IdentityState>> {
+        let registration = match self.client.registration(who).await? {
+            Some(registration) if registration.is_pending(self.registrar_index) => registration,
+            Some(_) => return Ok(None),
+            None => {
+                warn!(
+                    "No on-chain identity found for {} on {}",
+                    net_address.address_str(),
+                    net_address.net_str()
+                );
+                return Ok(None);
+            }
+        };
+
+        let fields = registration
+            .info
+            .fields()
+            .into_iter()
+            .map(|field| {
+                if !self.registrar.supports(&field) {
+                    return FieldStatus::not_permitted(field);
+                }
+
+                let to = self.registrar_account(&field);
+                FieldStatus::new(field, to)
+            })
+            .collect();
+
+        Ok(Some(IdentityState::new(net_address, fields)))
+    }
+    /// The account of the registrar which receives the challenge of the
+    /// field, if configured.
+    fn registrar_account(&self, field: &IdentityField) -> Option<RegistrarIdentityField> {
+        let to = match field {
+            IdentityField::Email(_) => {
+                IdentityField::Email(FieldAddress::from(self.registrar.email.clone()?))
+            }
+            IdentityField::Twitter(_) => {
+                IdentityField::Twitter(FieldAddress::from(self.registrar.twitter.clone()?))
+            }
+            IdentityField::Matrix(_) => {
+                IdentityField::Matrix(FieldAddress::from(self.registrar.matrix.clone()?))
+            }
+            _ => return None,
+        };
+
+        Some(RegistrarIdentityField::from(to))
+    }
+}
@@ -0,0 +1,248 @@
+//! Detection of the on-chain challenges, which users submit as
+//! `system.remark`, either directly or inside `utility.batch`.
+
+use super::checkpoint::BlockCheckpoints;
+use super::{net_address, AccountId, ChainSpec, CHECKPOINT_EVERY};
+use crate::aggregate::remark::{RemarkWatcher, RemarkWatcherCommand};
+use crate::aggregate::Repository;
+use crate::event::{ExtrinsicPosition, OnChainRemark};
+use crate::store::EventStore;
+use crate::Result;
+use parity_scale_codec::{Compact, Decode};
+use std::sync::Arc;
+use tokio::time::{self, Duration};
+
+pub type BlockNumber = u32;
+
+/// Seconds between the checks for newly finalized blocks.
+const POLL_INTERVAL: u64 = 30;
+
+const SYSTEM_PALLET: u8 = 0;
+const REMARK_CALL: u8 = 1;
+// `utility.batch` and `utility.batch_all`.
+const BATCH_CALLS: [u8; 2] = [0, 2];
+
+/// The source of the finalized blocks. Implemented by `SubxtClient` for live
+/// networks, and by recorded fixtures in tests.
+#[async_trait]
+pub trait BlockClient: Send + Sync {
+    /// The number of the latest finalized block.
+    async fn finalized_block(&self) -> Result<BlockNumber>;
+    /// The encoded extrinsics of the block with the given number.
+    async fn extrinsics(&self, number: BlockNumber) -> Result<Vec<Vec<u8>>>;
+}
+
+/// Decodes the signer and the remarks of a signed extrinsic. Returns `None`
+/// if the extrinsic is unsigned or does not contain any remarks.
+pub fn decode_remarks(
+    spec: &ChainSpec,
+    mut extrinsic: &[u8],
+) -> Result<Option<(AccountId, Vec<Vec<u8>>)>> {
+    let input = &mut extrinsic;
+
+    // The length prefix is not relevant.
+    let _ = <Compact<u32>>::decode(input)?;
+
+    let version = u8::decode(input)?;
+    if version & 0b1000_0000 == 0 {
+        return Ok(None);
+    }
+
+    if version & 0b0111_1111 != 4 {
+        return Err(anyhow!("unsupported extrinsic version {}", version));
+    }
+
+    // Only `MultiAddress::Id` identifies the signer directly.
+    let signer = match u8::decode(input)? {
+        0 => AccountId::decode(input)?,
+        _ => return Ok(None),
+    };
+
+    // Skip signature.
+    let signature_len = match u8::decode(input)? {
+        // Ed25519 and Sr25519.
+        0 | 1 => 64,
+        // Ecdsa.
+        2 => 65,
+        other => return Err(anyhow!("unknown signature type {}", other)),
+    };
+    let rest: &[u8] = *input;
+    *input = rest
+        .get(signature_len..)
+        .ok_or(anyhow!("extrinsic signature is truncated"))?;
+
+    // Skip era, nonce and tip.
+    if u8::decode(input)? != 0 {
+        let _ = u8::decode(input)?;
+    }
+    let _ = <Compact<u32>>::decode(input)?;
+    let _ = <Compact<u128>>::decode(input)?;
+
+    let mut remarks = vec![];
+    decode_call(input, spec.utility_pallet, &mut remarks)?;
+
+    if remarks.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some((signer, remarks)))
+    }
+}
+
+/// Collects the remarks of the call, including those nested in batches.
+/// Returns `false` if the call is unknown. Since the length of unknown calls
+/// is not known, any subsequent calls of a batch cannot be decoded.
+fn decode_call(
+    input: &mut &[u8],
+    utility_pallet: Option<u8>,
+    remarks: &mut Vec<Vec<u8>>,
+) -> Result<bool> {
+    let (pallet, call) = (u8::decode(input)?, u8::decode(input)?);
+
+    if pallet == SYSTEM_PALLET && call == REMARK_CALL {
+        remarks.push(Decode::decode(input)?);
+    } else if Some(pallet) == utility_pallet && BATCH_CALLS.contains(&call) {
+        let count = <Compact<u32>>::decode(input)?.0;
+        for _ in 0..count {
+            if !decode_call(input, utility_pallet, remarks)? {
+                return Ok(false);
+            }
+        }
+    } else {
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Scans the finalized blocks of a single network for on-chain challenges.
+/// Blocks which were finalized while the service was down are processed on
+/// startup.
+pub struct RemarkScanner<C> {
+    client: C,
+    repo: Repository<RemarkWatcher>,
+    spec: ChainSpec,
+    checkpoints: BlockCheckpoints,
+    // The latest processed block, once known.
+    last_block: Option<BlockNumber>,
+}
+
+impl<C: BlockClient> RemarkScanner<C> {
+    pub fn new(
+        client: C,
+        repo: Repository<RemarkWatcher>,
+        store: Arc<dyn EventStore>,
+        spec: ChainSpec,
+    ) -> Self {
+        let checkpoint_stream = format!("remark_watcher_{}_checkpoints", spec.network);
+
+        RemarkScanner {
+            client: client,
+            repo: repo,
+            spec: spec,
+            checkpoints: BlockCheckpoints::new(store, checkpoint_stream),
+            last_block: None,
+        }
+    }
+    /// Scans for new blocks every `POLL_INTERVAL` seconds. Only returns on
+    /// error.
+    pub async fn run_blocking(mut self) -> Result<()> {
+        loop {
+            self.scan().await?;
+            time::sleep(Duration::from_secs(POLL_INTERVAL)).await;
+        }
+    }
+    /// Processes all blocks up to the latest finalized block, starting after
+    /// the persisted checkpoint. Without a checkpoint, only the latest
+    /// finalized block is processed.
+    pub async fn scan(&mut self) -> Result<()> {
+        let finalized = self.client.finalized_block().await?;
+
+        let start = match self.last_block {
+            Some(block) => block + 1,
+            None => match self.checkpoints.load().await? {
+                Some(block) => {
+                    info!(
+                        "Resuming remark watcher on stream '{}' after block {}",
+                        self.checkpoints.stream(),
+                        block
+                    );
+                    block + 1
+                }
+                None => finalized,
+            },
+        };
+
+        for number in start..=finalized {
+            for (index, extrinsic) in self.client.extrinsics(number).await?.iter().enumerate() {
+                let position = ExtrinsicPosition {
+                    block: number,
+                    index: index as u32,
+                };
+
+                match decode_remarks(&self.spec, extrinsic) {
+                    Ok(Some((signer, remarks))) => {
+                        self.add_remarks(&signer, remarks, position).await?
+                    }
+                    Ok(None) => {}
+                    Err(err) => debug!(
+                        "Skipping undecodable extrinsic in block {}: {:?}",
+                        number, err
+                    ),
+                }
+            }
+
+            self.last_block = Some(number);
+            if number == finalized || number % CHECKPOINT_EVERY == 0 {
+                self.checkpoints.save(number).await?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Adds the on-chain challenges among the remarks of the extrinsic. The
+    /// remarks of an extrinsic are added at once, so an extrinsic which was
+    /// already processed before a restart is skipped as a whole.
+    async fn add_remarks(
+        &mut self,
+        signer: &AccountId,
+        remarks: Vec<Vec<u8>>,
+        extrinsic: ExtrinsicPosition,
+    ) -> Result<()> {
+        let net_address = net_address(&self.spec, signer);
+
+        let mut challenges = vec![];
+        for remark in remarks {
+            // Only remarks which look like an on-chain challenge are relevant.
+            let remark = match String::from_utf8(remark) {
+                Ok(remark) => OnChainRemark::from(remark),
+                Err(_) => continue,
+            };
+
+            if !remark.is_challenge() {
+                continue;
+            }
+
+            info!(
+                "Found on-chain remark of {} on {}",
+                net_address.address_str(),
+                net_address.net_str()
+            );
+
+            challenges.push(remark);
+        }
+
+        if challenges.is_empty() {
+            return Ok(());
+        }
+
+        self.repo
+            .apply(RemarkWatcherCommand::AddRemarks {
+                net_address: net_address,
+                remarks: challenges,
+                extrinsic: extrinsic,
+            })
+            .await?;
+
+        Ok(())
+    }
+}
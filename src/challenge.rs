@@ -0,0 +1,130 @@
+//! Matching of the challenges which users send back via email, Matrix or
+//! Twitter. Messages are written by hand or by mail clients, so the challenge
+//! is searched for as a sequence of tokens anywhere in the message, ignoring
+//! whitespace, punctuation, letter case, HTML markup and quote markers (`>`)
+//! of replies.
+
+/// Challenges with fewer alphanumeric characters are never matched, since
+/// those could be guessed or appear in unrelated messages.
+pub const MIN_CHALLENGE_LEN: usize = 16;
+
+/// Whether the text contains all tokens of the challenge, in order and
+/// without any other tokens in between. Every window of tokens is compared,
+/// even after a match, and tokens of equal length are compared without
+/// stopping at the first mismatching byte. This is not constant time overall:
+/// the duration depends on the number of tokens in the text and on which
+/// tokens have the length of the expected ones.
+pub fn matches(challenge: &str, text: &str) -> bool {
+    let expected = tokens(challenge);
+    if expected.iter().map(|token| token.len()).sum::<usize>() < MIN_CHALLENGE_LEN {
+        return false;
+    }
+
+    let provided = tokens(&normalize(text));
+    if provided.len() < expected.len() {
+        return false;
+    }
+
+    let mut found = false;
+    for window in provided.windows(expected.len()) {
+        let mut equal = true;
+        for (provided, expected) in window.iter().zip(&expected) {
+            equal &= constant_time_eq(provided.as_bytes(), expected.as_bytes());
+        }
+
+        found |= equal;
+    }
+
+    found
+}
+
+/// Splits the text into its lowercase alphanumeric tokens.
+fn tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+/// Replaces the markup of HTML bodies with whitespace and decodes the
+/// entities. Plain text is returned as is, since it can contain `<` and `>`
+/// on its own (e.g. in quoted replies).
+fn normalize(text: &str) -> String {
+    let lower = text.to_lowercase();
+    if !lower.contains("</") && !lower.contains("<br") {
+        return text.to_string();
+    }
+
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                stripped.push(' ');
+            }
+            _ if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+
+    decode_entities(&stripped)
+}
+
+/// Decodes the named entities which commonly appear in emails, as well as
+/// numeric entities. Unknown entities are kept.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| (&rest[1..end], end));
+
+        let c = entity.and_then(|(name, _)| match name {
+            "nbsp" => Some(' '),
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if name.starts_with("#x") || name.starts_with("#X") => {
+                u32::from_str_radix(&name[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            _ if name.starts_with('#') => name[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        });
+
+        match (c, entity) {
+            (Some(c), Some((_, end))) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Compares slices of equal length without an early exit on the first
+/// mismatching byte. Slices of different length are rejected immediately.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -1,36 +1,110 @@
+use crate::chain::{AddressError, BlockNumber, RegistrarIndex};
 use crate::manager::{
-    DisplayName, FieldAddress, FieldStatus, IdentityField, IdentityState, NetworkAddress,
-    OnChainChallenge, ProvidedMessage, UpdateChanges,
+    DisplayName, FieldAddress, FieldStatus, IdentityField, IdentityManagerSnapshot, IdentityState,
+    NetworkAddress, OnChainChallenge, ProvidedMessage, UpdateChanges, ON_CHAIN_CHALLENGE_PREFIX,
 };
+use crate::store::{EventData, RecordedEvent};
 use crate::Result;
+use serde_json::Value;
 
 use std::convert::TryFrom;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The current schema version of the stored events. When the format of an
+/// event changes, this must be increased and an upcaster must be added to
+/// `UPCASTERS`, which migrates the payload of the previous version.
+pub const EVENT_SCHEMA_VERSION: u32 = 2;
+
+/// Migrates the JSON payload of an event to the next schema version.
+type Upcaster = fn(Value) -> Result<Value>;
+
+/// The upcaster at index `n` migrates version `n` to version `n + 1`.
+const UPCASTERS: [Upcaster; EVENT_SCHEMA_VERSION as usize] = [upcast_v0, upcast_v1];
+
+// Version 0 refers to events written before the schema version was
+// introduced. Apart from the version, the payload is identical to version 1.
+fn upcast_v0(event: Value) -> Result<Value> {
+    Ok(event)
+}
+
+// Version 1 did not record the kind of the given judgement, which was always
+// `Reasonable`.
+fn upcast_v1(mut event: Value) -> Result<Value> {
+    if event.pointer("/body/type") == Some(&Value::from("judgement_given")) {
+        if let Some(content) = event
+            .pointer_mut("/body/content")
+            .and_then(|content| content.as_object_mut())
+        {
+            content
+                .entry("judgement")
+                .or_insert(Value::from(JudgementKind::Reasonable.as_str()));
+        }
+    }
+
+    Ok(event)
+}
+
+/// Migrates the JSON payload of a stored event to the current schema version.
+pub fn upcast(mut event: Value) -> Result<Value> {
+    let version = match event.get("header").and_then(|header| header.get("version")) {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow!("invalid event schema version: {}", version))?,
+        None => 0,
+    };
+
+    if version > EVENT_SCHEMA_VERSION as u64 {
+        return Err(anyhow!(
+            "event schema version {} is not supported, latest version: {}",
+            version,
+            EVENT_SCHEMA_VERSION
+        ));
+    }
+
+    for upcaster in &UPCASTERS[version as usize..] {
+        event = upcaster(event)?;
+    }
+
+    event
+        .get_mut("header")
+        .and_then(|header| header.as_object_mut())
+        .ok_or_else(|| anyhow!("event has no header"))?
+        .insert("version".to_string(), Value::from(EVENT_SCHEMA_VERSION));
+
+    Ok(event)
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Event {
     header: EventHeader,
     pub body: EventType,
 }
 
-impl TryFrom<eventstore::RecordedEvent> for Event {
+impl TryFrom<RecordedEvent> for Event {
     type Error = anyhow::Error;
 
-    fn try_from(val: eventstore::RecordedEvent) -> Result<Self> {
-        val.as_json::<Event>().map_err(|err| {
-            anyhow!(
-                "failed to deserialize 'RecordedEvent' to 'Event': {:?}",
-                err
-            )
-        })
+    fn try_from(val: RecordedEvent) -> Result<Self> {
+        let (stream, revision) = (val.stream, val.revision);
+
+        upcast(val.data)
+            .and_then(|data| serde_json::from_value::<Event>(data).map_err(|err| err.into()))
+            .map_err(|err| {
+                anyhow!(
+                    "failed to deserialize event {} of stream '{}' to 'Event': {:?}",
+                    revision,
+                    stream,
+                    err
+                )
+            })
     }
 }
 
-impl TryFrom<Event> for eventstore::EventData {
+impl TryFrom<Event> for EventData {
     type Error = anyhow::Error;
 
     fn try_from(val: Event) -> Result<Self> {
-        eventstore::EventData::json("registrar-event", val)
+        EventData::json(val.body.name(), val)
             .map_err(|err| anyhow!("failed to serialize 'Event' to 'EventData': {:?}", err))
     }
 }
@@ -44,15 +118,69 @@ pub enum EventType {
     FieldStatusVerified(FieldStatusVerified),
     IdentityFullyVerified(IdentityFullyVerified),
     DisplayNamePersisted(DisplayNamePersisted),
+    // Legacy snapshot format, which does not record the covered revision.
     ExportedIdentityState(Vec<IdentityState>),
+    IdentityStateSnapshot(IdentityStateSnapshot),
     RemarkFound(RemarkFound),
     JudgementGiven(JudgementGiven),
+    IdentityExpired(IdentityExpired),
+    IdentityRemoved(IdentityRemoved),
+    JudgementRequestCancelled(JudgementRequestCancelled),
+    IdentityLocked(IdentityLocked),
+    ChallengeRegenerated(ChallengeRegenerated),
+    JudgementSubmitted(JudgementSubmitted),
+    JudgementSubmissionFailed(JudgementSubmissionFailed),
+    IdentityUnlocked(IdentityUnlocked),
+}
+
+impl EventType {
+    /// The name of the variant, which is used as the event type in the event
+    /// store. Events written before schema versioning was introduced all
+    /// share the type `registrar-event`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventType::IdentityInserted(_) => "identity_inserted",
+            EventType::ExternalMessage(_) => "external_message",
+            EventType::FieldStatusVerified(_) => "field_status_verified",
+            EventType::IdentityFullyVerified(_) => "identity_fully_verified",
+            EventType::DisplayNamePersisted(_) => "display_name_persisted",
+            EventType::ExportedIdentityState(_) => "exported_identity_state",
+            EventType::IdentityStateSnapshot(_) => "identity_state_snapshot",
+            EventType::RemarkFound(_) => "remark_found",
+            EventType::JudgementGiven(_) => "judgement_given",
+            EventType::IdentityExpired(_) => "identity_expired",
+            EventType::IdentityRemoved(_) => "identity_removed",
+            EventType::JudgementRequestCancelled(_) => "judgement_request_cancelled",
+            EventType::IdentityLocked(_) => "identity_locked",
+            EventType::ChallengeRegenerated(_) => "challenge_regenerated",
+            EventType::JudgementSubmitted(_) => "judgement_submitted",
+            EventType::JudgementSubmissionFailed(_) => "judgement_submission_failed",
+            EventType::IdentityUnlocked(_) => "identity_unlocked",
+        }
+    }
+}
+
+impl Event {
+    /// Sets the time span after which the event expires.
+    pub fn with_ttl(mut self, ttl: TTL) -> Self {
+        self.header.ttl = ttl;
+        self
+    }
+    /// The time at which the event expires, or `None` if it never does.
+    pub fn expires_at(&self) -> Option<Timestamp> {
+        if self.header.ttl.is_immortal() {
+            None
+        } else {
+            Some(Timestamp(self.header.timestamp.0 + self.header.ttl.0))
+        }
+    }
 }
 
 impl From<EventType> for Event {
     fn from(val: EventType) -> Self {
         Event {
             header: EventHeader {
+                version: EVENT_SCHEMA_VERSION,
                 timestamp: Timestamp::unix_time(),
                 ttl: TTL::immortal(),
             },
@@ -66,18 +194,33 @@ impl From<EventType> for Event {
 #[serde(rename_all = "snake_case", tag = "type", content = "message")]
 pub enum ErrorMessage {
     NoPendingJudgementRequest(String),
+    JudgementRequestExpired(String),
+    InvalidAddress(String),
 }
 
 impl ErrorMessage {
-    pub fn no_pending_judgement_request(registrar_idx: usize) -> Self {
+    pub fn no_pending_judgement_request(registrar_idx: RegistrarIndex) -> Self {
         ErrorMessage::NoPendingJudgementRequest(format!(
             "This identity does not have a pending judgement request for registrar #{}",
             registrar_idx
         ))
     }
+    pub fn judgement_request_expired(net_address: &NetworkAddress) -> Self {
+        ErrorMessage::JudgementRequestExpired(format!(
+            "The judgement request of identity {} has expired, since it was not verified in time. \
+            Please request a new judgement",
+            net_address.address_str()
+        ))
+    }
+    pub fn invalid_address(err: &AddressError) -> Self {
+        ErrorMessage::InvalidAddress(format!(
+            "The address is not valid: {}. Please check the address and the selected network",
+            err
+        ))
+    }
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Timestamp(u128);
 
 impl Timestamp {
@@ -102,10 +245,14 @@ impl TTL {
     pub fn immortal() -> Self {
         TTL(0)
     }
+    pub fn is_immortal(&self) -> bool {
+        self.0 == 0
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct EventHeader {
+    version: u32,
     timestamp: Timestamp,
     ttl: TTL,
 }
@@ -190,17 +337,38 @@ pub enum Notification {
     Error(String),
 }
 
+/// The name of a network, as specified in the `networks` config, e.g.
+/// `polkadot`.
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum BlankNetwork {
-    Polkadot,
-    Kusama,
+#[serde(transparent)]
+pub struct BlankNetwork(String);
+
+impl BlankNetwork {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl From<&str> for BlankNetwork {
+    fn from(val: &str) -> Self {
+        BlankNetwork(val.to_string())
+    }
+}
+
+impl fmt::Display for BlankNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct FieldStatusVerified {
     pub net_address: NetworkAddress,
     pub field_status: FieldStatus,
+    /// The revision of the verified `ExternalMessage` in the message watcher
+    /// stream, if the status results from such a message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_revision: Option<u64>,
 }
 
 impl From<FieldStatusVerified> for Event {
@@ -247,6 +415,21 @@ impl From<DisplayNamePersisted> for Event {
     }
 }
 
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityStateSnapshot {
+    /// The revision of the latest identity state change covered by this
+    /// snapshot.
+    pub revision: u64,
+    pub events_generated: usize,
+    pub state: IdentityManagerSnapshot,
+}
+
+impl From<IdentityStateSnapshot> for Event {
+    fn from(val: IdentityStateSnapshot) -> Self {
+        EventType::IdentityStateSnapshot(val).into()
+    }
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct OnChainRemark(String);
 
@@ -254,12 +437,34 @@ impl OnChainRemark {
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+    /// Whether the remark looks like an on-chain challenge.
+    pub fn is_challenge(&self) -> bool {
+        self.0.starts_with(ON_CHAIN_CHALLENGE_PREFIX)
+    }
+}
+
+impl From<String> for OnChainRemark {
+    fn from(val: String) -> Self {
+        OnChainRemark(val)
+    }
+}
+
+/// The position of an extrinsic on-chain. Positions are ordered by block,
+/// then by the index of the extrinsic within the block.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ExtrinsicPosition {
+    pub block: BlockNumber,
+    pub index: u32,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct RemarkFound {
     pub net_address: NetworkAddress,
     pub remark: OnChainRemark,
+    /// The extrinsic which contains the remark, not recorded by older
+    /// versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extrinsic: Option<ExtrinsicPosition>,
 }
 
 impl RemarkFound {
@@ -274,9 +479,141 @@ impl From<RemarkFound> for Event {
     }
 }
 
+/// The judgements given by the registrar.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JudgementKind {
+    Reasonable,
+    LowQuality,
+    Erroneous,
+}
+
+impl JudgementKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JudgementKind::Reasonable => "reasonable",
+            JudgementKind::LowQuality => "low_quality",
+            JudgementKind::Erroneous => "erroneous",
+        }
+    }
+}
+
+/// The judgement was submitted on-chain and the extrinsic was finalized.
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct JudgementGiven {
     pub net_address: NetworkAddress,
+    pub judgement: JudgementKind,
+}
+
+impl From<JudgementGiven> for Event {
+    fn from(val: JudgementGiven) -> Self {
+        EventType::JudgementGiven(val).into()
+    }
+}
+
+/// The judgement is about to be submitted on-chain. Until the judgement is
+/// given or the submission failed, it must not be submitted again.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct JudgementSubmitted {
+    pub net_address: NetworkAddress,
+    pub judgement: JudgementKind,
+}
+
+impl From<JudgementSubmitted> for Event {
+    fn from(val: JudgementSubmitted) -> Self {
+        EventType::JudgementSubmitted(val).into()
+    }
+}
+
+/// The submitted judgement was certainly not included on-chain, so it can be
+/// submitted again.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct JudgementSubmissionFailed {
+    pub net_address: NetworkAddress,
+}
+
+impl From<JudgementSubmissionFailed> for Event {
+    fn from(val: JudgementSubmissionFailed) -> Self {
+        EventType::JudgementSubmissionFailed(val).into()
+    }
+}
+
+/// The judgement request was not verified before the TTL of the inserted
+/// identity elapsed.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityExpired {
+    pub net_address: NetworkAddress,
+}
+
+impl From<IdentityExpired> for Event {
+    fn from(val: IdentityExpired) -> Self {
+        EventType::IdentityExpired(val).into()
+    }
+}
+
+/// The identity was cleared on-chain. Any pending judgement request and the
+/// persisted display name are removed.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityRemoved {
+    pub net_address: NetworkAddress,
+}
+
+impl From<IdentityRemoved> for Event {
+    fn from(val: IdentityRemoved) -> Self {
+        EventType::IdentityRemoved(val).into()
+    }
+}
+
+/// The user cancelled the pending judgement request.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct JudgementRequestCancelled {
+    pub net_address: NetworkAddress,
+}
+
+impl From<JudgementRequestCancelled> for Event {
+    fn from(val: JudgementRequestCancelled) -> Self {
+        EventType::JudgementRequestCancelled(val).into()
+    }
+}
+
+/// The identity reached the maximum of failed challenge responses. Messages
+/// are no longer verified until the judgement request is renewed.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityLocked {
+    pub net_address: NetworkAddress,
+}
+
+impl From<IdentityLocked> for Event {
+    fn from(val: IdentityLocked) -> Self {
+        EventType::IdentityLocked(val).into()
+    }
+}
+
+/// The locked identity renewed its judgement request, so its messages are
+/// verified again and its failed attempts are reset.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityUnlocked {
+    pub net_address: NetworkAddress,
+}
+
+impl From<IdentityUnlocked> for Event {
+    fn from(val: IdentityUnlocked) -> Self {
+        EventType::IdentityUnlocked(val).into()
+    }
+}
+
+/// A new challenge was generated for the field on request of the user,
+/// replacing the previous one.
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct ChallengeRegenerated {
+    pub net_address: NetworkAddress,
+    pub field_status: FieldStatus,
+}
+
+impl From<ChallengeRegenerated> for Event {
+    fn from(val: ChallengeRegenerated) -> Self {
+        EventType::ChallengeRegenerated(val).into()
+    }
 }
 
 #[cfg(test)]
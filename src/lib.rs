@@ -11,7 +11,6 @@ extern crate async_trait;
 
 use manager::NetworkAddress;
 use std::env;
-use std::fs;
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
@@ -19,99 +18,78 @@ mod adapters;
 mod aggregate;
 mod api;
 mod api_v2;
+mod chain;
+mod challenge;
+mod config;
 mod event;
 mod manager;
 mod projection;
-mod remark_watcher;
+mod store;
 mod system;
 #[cfg(test)]
 mod tests;
 
+pub use config::{
+    open_config, AccountsConfig, ApiConfig, Config, ConfigError, ConfigErrors, EmailConfig,
+    EventStoreBackend, EventStoreConfig, JudgementConfig, MatrixConfig, NetworkConfig,
+    PolicyConfig, PolicyRule, ProjectionConfig, ProjectionsConfig, RegistrarConfig, SignerConfig,
+    TwitterConfig, VerifierConfig,
+};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("The target address was not found ({}): {:?}", _1, _0)]
     TargetAddressNotFound(NetworkAddress, String),
+    #[error(
+        "Stream '{}' was modified concurrently, gave up after {} attempts",
+        _0,
+        _1
+    )]
+    ConcurrencyConflict(String, usize),
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub accounts: AccountsConfig,
-    pub log_level: log::LevelFilter,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct AccountsConfig {
-    matrix: MatrixConfig,
-    twitter: TwitterConfig,
-    email: EmailConfig,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct MatrixConfig {
-    pub enabled: bool,
-    pub homeserver: String,
-    pub username: String,
-    pub password: String,
-    pub db_path: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct TwitterConfig {
-    pub enabled: bool,
-    pub api_key: String,
-    pub api_secret: String,
-    pub token: String,
-    pub token_secret: String,
-    pub request_interval: u64,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct EmailConfig {
-    pub enabled: bool,
-    pub smtp_server: String,
-    pub imap_server: String,
-    pub inbox: String,
-    pub user: String,
-    pub password: String,
-    pub request_interval: u64,
-}
-
-fn open_config() -> Result<Config> {
-    // Open config file.
-    let content = fs::read_to_string("config.json")
-        .or_else(|_| fs::read_to_string("/etc/registrar/config.json"))
-        .map_err(|_| {
-            eprintln!("Failed to open config at 'config.json' or '/etc/registrar/config.json'.");
-            std::process::exit(1);
-        })
-        .unwrap();
-
-    // Parse config file as JSON.
-    let config = serde_yaml::from_str::<Config>(&content)
-        .map_err(|err| {
-            eprintln!("Failed to parse config: {}", err);
-            std::process::exit(1);
-        })
-        .unwrap();
-
-    Ok(config)
-}
-
-pub fn init_env() -> Result<Config> {
-    let config = open_config()?;
+pub fn init_env(config_path: &str) -> Result<Config> {
+    let config = open_config(config_path)?;
 
     // Env variables for log level overwrites config.
     if let Ok(_) = env::var("RUST_LOG") {
-        println!("Env variable 'RUST_LOG' found, overwriting logging level from config.");
+        eprintln!("Env variable 'RUST_LOG' found, overwriting logging level from config.");
         env_logger::init();
     } else {
-        println!("Setting log level to '{}' from config.", config.log_level);
+        eprintln!("Setting log level to '{}' from config.", config.log_level);
         env_logger::builder()
             .filter_module("registrar", config.log_level)
             .init();
     }
 
-    println!("Logger initiated");
+    eprintln!("Logger initiated");
 
     Ok(config)
 }
+
+/// Runs all services of the registrar, as specified in the config. This
+/// function only returns if the event store is unreachable on startup. If
+/// `rebuild_projections` is set, the projections ignore their persisted
+/// checkpoints and process all events again.
+pub async fn run(config: Config, rebuild_projections: bool) -> Result<()> {
+    system::run_service_blocking(config, rebuild_projections).await
+}
+
+/// Prints all events of the specified stream as JSON, one event per line.
+pub async fn replay(config: Config, stream: &str) -> Result<()> {
+    let store = system::connect_event_store(&config.event_store).await?;
+    system::replay_stream(store, stream).await
+}
+
+/// Returns the state of all pending judgement requests as JSON.
+pub async fn export_state(config: Config) -> Result<String> {
+    let store = system::connect_event_store(&config.event_store).await?;
+    system::export_state(store).await
+}
+
+/// Returns the state of the specified identity as JSON, or `None` if the
+/// identity has no pending judgement request.
+pub async fn inspect(config: Config, network: &str, address: &str) -> Result<Option<String>> {
+    let store = system::connect_event_store(&config.event_store).await?;
+    system::inspect_state(store, &config.networks, network, address).await
+}
@@ -1,7 +1,8 @@
 use crate::aggregate::display_name::DisplayNameHandler;
+use crate::challenge;
 use crate::event::{
-    BlankNetwork, DisplayNamePersisted, FieldStatusVerified, IdentityInserted, Notification,
-    RemarkFound,
+    BlankNetwork, ChallengeRegenerated, DisplayNamePersisted, FieldStatusVerified,
+    IdentityInserted, JudgementKind, Notification, RemarkFound, Timestamp,
 };
 use crate::Result;
 use rand::{thread_rng, Rng};
@@ -13,11 +14,17 @@ use std::{
 };
 
 // TODO: Rename to `ChangeLog`.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum UpdateChanges {
     NewIdentityInserted(NetworkAddress),
     VerificationValid(IdentityField),
     VerificationInvalid(IdentityField),
     BackAndForthExpected(IdentityField),
+    ChallengeRegenerated(IdentityField),
+    ChallengeRequested(IdentityField),
+    FieldLocked(IdentityField),
+    IdentityLocked(NetworkAddress),
+    IdentityUnlocked(NetworkAddress),
 }
 
 impl From<UpdateChanges> for Notification {
@@ -38,20 +45,160 @@ impl From<UpdateChanges> for Notification {
                 An additional challenge has been sent directly to {0}",
                 field
             )),
+            UpdateChanges::ChallengeRegenerated(field) => Notification::Warn(format!(
+                "Too many failed attempts for the {} field, a new challenge has been generated.",
+                field
+            )),
+            UpdateChanges::ChallengeRequested(field) => Notification::Info(format!(
+                "A new challenge has been generated for the {} field.",
+                field
+            )),
+            UpdateChanges::FieldLocked(field) => Notification::Error(format!(
+                "Too many failed attempts, the {} field has been locked.",
+                field
+            )),
+            UpdateChanges::IdentityLocked(net_address) => Notification::Error(format!(
+                "Too many failed attempts, the verification of identity {} has been locked. \
+                Please request a new judgement",
+                net_address.address_str()
+            )),
+            UpdateChanges::IdentityUnlocked(net_address) => Notification::Info(format!(
+                "The judgement request has been renewed, the verification of identity {} \
+                has been unlocked.",
+                net_address.address_str()
+            )),
         }
     }
 }
 
-#[derive(Debug, Clone, Default)]
+/// The limits of failed challenge responses. Zero disables a limit.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct AttemptLimits {
+    /// Failed responses to the challenge of a single field, after which the
+    /// `action` applies. Applies again after each further `field` attempts.
+    pub field: u32,
+    pub action: LimitAction,
+    /// Failed responses to the challenges of all fields of an identity, after
+    /// which the identity is locked.
+    pub identity: u32,
+}
+
+/// What happens once a field reaches its limit of failed attempts.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// A new challenge is generated, which the user must fetch again.
+    Regenerate,
+    /// The field no longer accepts responses.
+    Lock,
+}
+
+impl Default for LimitAction {
+    fn default() -> Self {
+        LimitAction::Regenerate
+    }
+}
+
+/// The reasons why the challenge of a field cannot be regenerated.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum RegenerateError {
+    #[error("there is no pending judgement request")]
+    NotPending,
+    #[error("the identity is locked, please request a new judgement")]
+    IdentityLocked,
+    #[error("the field is not part of the identity")]
+    UnknownField,
+    #[error("the field does not have a challenge")]
+    NoChallenge,
+    #[error("the field is already verified")]
+    AlreadyVerified,
+}
+
+/// The current version of the `IdentityManagerSnapshot` format. Version 1
+/// does not contain the expirations, version 2 does not contain the failed
+/// attempts and locked identities, version 3 does not contain the submitted
+/// judgements and version 4 does not contain the latest verified message,
+/// which are empty in that case.
+const MANAGER_SNAPSHOT_VERSION: u32 = 5;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct IdentityManager {
     identities: HashMap<NetworkAddress, HashMap<IdentityFieldType, FieldStatus>>,
     lookup_addresses: HashMap<IdentityField, HashSet<NetworkAddress>>,
     display_names: HashMap<NetworkAddress, DisplayName>,
     on_chain_challenges: HashMap<NetworkAddress, OnChainChallenge>,
+    // The time at which an unverified identity expires, if at all.
+    expirations: HashMap<NetworkAddress, Timestamp>,
+    // The failed challenge responses of each field, including those to
+    // previous challenges of the field.
+    failed_attempts: HashMap<NetworkAddress, HashMap<IdentityFieldType, u32>>,
+    // Identities which reached the maximum of failed attempts.
+    locked: HashSet<NetworkAddress>,
+    // Judgements which were submitted, but not yet confirmed on-chain.
+    submitted: HashMap<NetworkAddress, JudgementKind>,
+    // The revision of the latest verified message of the message watcher
+    // stream.
+    message_revision: Option<u64>,
+}
+
+/// The full internal state of the `IdentityManager`. The maps are stored as
+/// lists of entries, since JSON only supports strings as keys.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct IdentityManagerSnapshot {
+    version: u32,
+    identities: Vec<(NetworkAddress, HashMap<IdentityFieldType, FieldStatus>)>,
+    lookup_addresses: Vec<(IdentityField, HashSet<NetworkAddress>)>,
+    display_names: Vec<(NetworkAddress, DisplayName)>,
+    on_chain_challenges: Vec<(NetworkAddress, OnChainChallenge)>,
+    #[serde(default)]
+    expirations: Vec<(NetworkAddress, Timestamp)>,
+    #[serde(default)]
+    failed_attempts: Vec<(NetworkAddress, HashMap<IdentityFieldType, u32>)>,
+    #[serde(default)]
+    locked: Vec<NetworkAddress>,
+    #[serde(default)]
+    submitted: Vec<(NetworkAddress, JudgementKind)>,
+    #[serde(default)]
+    message_revision: Option<u64>,
 }
 
 // TODO: Should logs be printed if users are not found?
 impl IdentityManager {
+    pub fn snapshot(&self) -> IdentityManagerSnapshot {
+        IdentityManagerSnapshot {
+            version: MANAGER_SNAPSHOT_VERSION,
+            identities: self.identities.clone().into_iter().collect(),
+            lookup_addresses: self.lookup_addresses.clone().into_iter().collect(),
+            display_names: self.display_names.clone().into_iter().collect(),
+            on_chain_challenges: self.on_chain_challenges.clone().into_iter().collect(),
+            expirations: self.expirations.clone().into_iter().collect(),
+            failed_attempts: self.failed_attempts.clone().into_iter().collect(),
+            locked: self.locked.clone().into_iter().collect(),
+            submitted: self.submitted.clone().into_iter().collect(),
+            message_revision: self.message_revision,
+        }
+    }
+    pub fn restore(snapshot: IdentityManagerSnapshot) -> Result<Self> {
+        if snapshot.version == 0 || snapshot.version > MANAGER_SNAPSHOT_VERSION {
+            return Err(anyhow!(
+                "unsupported identity manager snapshot version {}, latest version: {}",
+                snapshot.version,
+                MANAGER_SNAPSHOT_VERSION
+            ));
+        }
+
+        Ok(IdentityManager {
+            identities: snapshot.identities.into_iter().collect(),
+            lookup_addresses: snapshot.lookup_addresses.into_iter().collect(),
+            display_names: snapshot.display_names.into_iter().collect(),
+            on_chain_challenges: snapshot.on_chain_challenges.into_iter().collect(),
+            expirations: snapshot.expirations.into_iter().collect(),
+            failed_attempts: snapshot.failed_attempts.into_iter().collect(),
+            locked: snapshot.locked.into_iter().collect(),
+            submitted: snapshot.submitted.into_iter().collect(),
+            message_revision: snapshot.message_revision,
+        })
+    }
     pub fn export_state(&self) -> Vec<IdentityState> {
         self.identities
             .iter()
@@ -62,6 +209,42 @@ impl IdentityManager {
             })
             .collect()
     }
+    /// Prepares the (re-)insertion of the identity, whose challenges are
+    /// freshly generated. Returns `None` if the identity is already pending
+    /// with the same field addresses. Otherwise, the status of the unchanged
+    /// fields and the on-chain challenge are kept, so users can continue
+    /// answering the challenges they already received.
+    pub fn merge_identity(&self, mut identity: IdentityState) -> Option<IdentityState> {
+        let current = match self.identities.get(&identity.net_address) {
+            Some(current) => current,
+            None => return Some(identity),
+        };
+
+        let unchanged = current.len() == identity.fields.len()
+            && identity.fields.iter().all(|(field_ty, status)| {
+                current
+                    .get(field_ty)
+                    .map(|current| current.field == status.field)
+                    .unwrap_or(false)
+            });
+
+        if unchanged {
+            return None;
+        }
+
+        for (field_ty, status) in identity.fields.iter_mut() {
+            match current.get(field_ty) {
+                Some(current) if current.field == status.field => *status = current.clone(),
+                _ => {}
+            }
+        }
+
+        if let Some(challenge) = self.on_chain_challenges.get(&identity.net_address) {
+            identity.on_chain_challenge = challenge.clone();
+        }
+
+        Some(identity)
+    }
     pub fn contains(&self, identity: &IdentityState) -> bool {
         self.identities
             .get(&identity.net_address)
@@ -123,9 +306,227 @@ impl IdentityManager {
             .entry(net_address)
             .or_insert(identity.on_chain_challenge.clone());
     }
+    /// Sets the time at which the identity expires. `None` means that the
+    /// identity never expires.
+    pub fn set_expiry(&mut self, net_address: &NetworkAddress, expires_at: Option<Timestamp>) {
+        match expires_at {
+            Some(expires_at) => {
+                self.expirations.insert(net_address.clone(), expires_at);
+            }
+            None => {
+                self.expirations.remove(net_address);
+            }
+        }
+    }
+    /// Returns all identities which are not fully verified and have expired
+    /// at the given time.
+    pub fn expired_identities(&self, now: &Timestamp) -> Vec<NetworkAddress> {
+        self.expirations
+            .iter()
+            .filter(|(_, expires_at)| *expires_at <= now)
+            .filter(|(net_address, _)| !self.is_fully_verified(net_address).unwrap_or(true))
+            .map(|(net_address, _)| net_address.clone())
+            .collect()
+    }
+    /// Removes the identity, including its on-chain challenge and all
+    /// references in the lookup tables. Returns the removed state, if the
+    /// identity was found.
+    pub fn remove_identity(&mut self, net_address: &NetworkAddress) -> Option<IdentityState> {
+        let state = self.lookup_full_state(net_address)?;
+
+        // Fields which were replaced by an updated judgement request might
+        // still reference the identity, so all entries are checked.
+        self.lookup_addresses.retain(|_, addresses| {
+            addresses.remove(net_address);
+            !addresses.is_empty()
+        });
+
+        self.identities.remove(net_address);
+        self.on_chain_challenges.remove(net_address);
+        self.expirations.remove(net_address);
+        self.failed_attempts.remove(net_address);
+        self.locked.remove(net_address);
+        self.submitted.remove(net_address);
+
+        Some(state)
+    }
+    /// Removes the identity like `remove_identity` and additionally its
+    /// persisted display name, which can then be claimed by other identities.
+    /// Returns whether anything was removed.
+    pub fn clear_identity(&mut self, net_address: &NetworkAddress) -> bool {
+        let removed = self.remove_identity(net_address).is_some();
+        self.display_names.remove(net_address).is_some() || removed
+    }
+    /// Whether a judgement request of the identity is pending.
+    pub fn is_pending(&self, net_address: &NetworkAddress) -> bool {
+        self.identities.contains_key(net_address)
+    }
+    /// Stops the verification of messages for the identity, until it is
+    /// removed.
+    pub fn lock_identity(&mut self, net_address: &NetworkAddress) {
+        if self.is_pending(net_address) {
+            self.locked.insert(net_address.clone());
+        }
+    }
+    pub fn is_locked(&self, net_address: &NetworkAddress) -> bool {
+        self.locked.contains(net_address)
+    }
+    /// Resumes the verification of messages for the identity once the
+    /// judgement request was renewed. The failed attempts are reset and the
+    /// locked fields accept responses to their challenges again.
+    pub fn unlock_identity(&mut self, net_address: &NetworkAddress) {
+        self.locked.remove(net_address);
+        self.failed_attempts.remove(net_address);
+
+        if let Some(fields) = self.identities.get_mut(net_address) {
+            for field_status in fields.values_mut() {
+                field_status.is_locked = false;
+            }
+        }
+    }
+    /// Records that the judgement is being submitted, until the judgement
+    /// request is completed or the submission failed.
+    pub fn submit_judgement(&mut self, net_address: &NetworkAddress, judgement: JudgementKind) {
+        if self.is_pending(net_address) {
+            self.submitted.insert(net_address.clone(), judgement);
+        }
+    }
+    pub fn submission_failed(&mut self, net_address: &NetworkAddress) {
+        self.submitted.remove(net_address);
+    }
+    /// The judgement which was submitted, but not yet confirmed on-chain.
+    pub fn submitted_judgement(&self, net_address: &NetworkAddress) -> Option<JudgementKind> {
+        self.submitted.get(net_address).copied()
+    }
+    /// All judgements which were submitted, but not yet confirmed on-chain.
+    pub fn submitted_judgements(&self) -> Vec<(NetworkAddress, JudgementKind)> {
+        self.submitted
+            .iter()
+            .map(|(net_address, judgement)| (net_address.clone(), *judgement))
+            .collect()
+    }
+    /// The revision of the latest verified message of the message watcher
+    /// stream, if any.
+    pub fn message_revision(&self) -> Option<u64> {
+        self.message_revision
+    }
+    /// The failed challenge responses of all fields of the identity.
+    pub fn failed_attempts(&self, net_address: &NetworkAddress) -> u32 {
+        self.failed_attempts
+            .get(net_address)
+            .map(|fields| fields.values().sum())
+            .unwrap_or(0)
+    }
+    /// The failed challenge responses of the field.
+    pub fn field_failed_attempts(
+        &self,
+        net_address: &NetworkAddress,
+        field: &IdentityField,
+    ) -> u32 {
+        self.failed_attempts
+            .get(net_address)
+            .and_then(|fields| fields.get(&field.as_type()))
+            .copied()
+            .unwrap_or(0)
+    }
+    /// Counts the failed attempt of an invalid outcome and applies the
+    /// limits: the challenge of the field is regenerated or the field is
+    /// locked. Returns whether the identity must be locked.
+    pub fn limit_attempts(
+        &self,
+        outcome: &mut VerificationOutcome,
+        limits: &AttemptLimits,
+    ) -> bool {
+        let field_status = &mut outcome.field_status;
+        if !field_status.is_invalid() {
+            return false;
+        }
+
+        let net_address = &outcome.net_address;
+        let field_attempts = self.field_failed_attempts(net_address, &field_status.field) + 1;
+        let identity_attempts = self.failed_attempts(net_address) + 1;
+
+        if limits.identity > 0 && identity_attempts >= limits.identity {
+            field_status.is_locked = true;
+            return true;
+        }
+
+        if limits.field > 0 && field_attempts % limits.field == 0 {
+            match limits.action {
+                LimitAction::Regenerate => field_status.regenerate(),
+                LimitAction::Lock => field_status.is_locked = true,
+            }
+        }
+
+        false
+    }
+    /// The status of the field with a new challenge, as requested by the
+    /// user. The new challenge has not been attempted yet and the field is
+    /// unlocked, while locked identities remain locked.
+    pub fn regenerate_challenge(
+        &self,
+        net_address: &NetworkAddress,
+        field: &IdentityFieldType,
+    ) -> std::result::Result<FieldStatus, RegenerateError> {
+        let fields = self
+            .identities
+            .get(net_address)
+            .ok_or(RegenerateError::NotPending)?;
+
+        if self.is_locked(net_address) {
+            return Err(RegenerateError::IdentityLocked);
+        }
+
+        let mut field_status = fields
+            .get(field)
+            .ok_or(RegenerateError::UnknownField)?
+            .clone();
+
+        if field_status.is_valid() {
+            return Err(RegenerateError::AlreadyVerified);
+        }
+
+        if !field_status.renew() {
+            return Err(RegenerateError::NoChallenge);
+        }
+
+        Ok(field_status)
+    }
+    /// Replaces the status of the field with the regenerated one.
+    pub fn replace_challenge(
+        &mut self,
+        regenerated: ChallengeRegenerated,
+    ) -> Result<UpdateChanges> {
+        let current_status = self
+            .identities
+            .get_mut(&regenerated.net_address)
+            .ok_or(anyhow!("network address not found"))?
+            .get_mut(&regenerated.field_status.field.as_type())
+            .ok_or(anyhow!("field not found"))?;
+
+        let field = regenerated.field_status.field.clone();
+        *current_status = regenerated.field_status;
+
+        Ok(UpdateChanges::ChallengeRequested(field))
+    }
+    pub fn display_name(&self, net_address: &NetworkAddress) -> Option<&DisplayName> {
+        self.display_names.get(net_address)
+    }
     // TODO: This should return the full identity, too.
     pub fn update_field(&mut self, verified: FieldStatusVerified) -> Result<Option<UpdateChanges>> {
-        self.identities
+        let net_address = verified.net_address.clone();
+        let field_ty = verified.field_status.field.as_type();
+        // Display names are not sent by the user, so only responses to
+        // message challenges count as failed attempts.
+        let failed_attempt = verified.field_status.is_invalid()
+            && !verified.field_status.is_display_name_violation();
+
+        if verified.message_revision > self.message_revision {
+            self.message_revision = verified.message_revision;
+        }
+
+        let changes = self
+            .identities
             .get_mut(&verified.net_address)
             .ok_or(anyhow!("network address not found"))
             .and_then(|statuses| {
@@ -144,7 +545,18 @@ impl IdentityManager {
                             None
                         }
                     })
-            })
+            })?;
+
+        if changes.is_some() && failed_attempt {
+            *self
+                .failed_attempts
+                .entry(net_address)
+                .or_default()
+                .entry(field_ty)
+                .or_insert(0) += 1;
+        }
+
+        Ok(changes)
     }
     // TODO: Should return Vec<UpdateChanges>
     // TODO: Should return Result
@@ -166,6 +578,16 @@ impl IdentityManager {
         else if current_status.is_not_valid() {
             let field = verified_status.field.clone();
 
+            // The limits of failed attempts were reached.
+            if verified_status.is_locked && !current_status.is_locked {
+                return Some(UpdateChanges::FieldLocked(field));
+            }
+            if verified_status.is_invalid()
+                && verified_status.expected_messages() != current_status.expected_messages()
+            {
+                return Some(UpdateChanges::ChallengeRegenerated(field));
+            }
+
             match &verified_status.challenge {
                 ChallengeStatus::ExpectMessage(challenge) => {
                     match challenge.status {
@@ -347,7 +769,15 @@ impl IdentityManager {
         if let Some(net_addresses) = self.lookup_addresses(field) {
             // For each address, verify the field.
             for net_address in net_addresses {
+                if self.is_locked(net_address) {
+                    continue;
+                }
+
                 if let Some(field_status) = self.lookup_field_status(&net_address, field) {
+                    if field_status.is_locked {
+                        continue;
+                    }
+
                     // Variables must be cloned, since those are later converted
                     // into events (which require ownership) and sent to the
                     // event store.
@@ -479,11 +909,19 @@ impl IdentityManager {
 
         None
     }
+    /// Whether all fields which the registrar verifies are valid. Other
+    /// fields, such as the legal name or the website, are not required, but
+    /// at least one field must be verified.
     pub fn is_fully_verified(&self, net_address: &NetworkAddress) -> Result<bool> {
         self.identities
             .get(net_address)
             .map(|field_statuses| {
-                field_statuses.iter().all(|(_, field)| field.is_valid())
+                let mut required = field_statuses
+                    .values()
+                    .filter(|field| field.is_required())
+                    .peekable();
+
+                required.peek().is_some() && required.all(|field| field.is_valid())
             })
             .ok_or(anyhow!(
                 "failed to check the full verification status of unknown target: {:?}. This is a bug",
@@ -498,32 +936,29 @@ pub struct VerificationOutcome {
     pub field_status: FieldStatus,
 }
 
+/// An address on a specific network. Addresses received from clients are
+/// validated and re-encoded by `chain::Networks::parse_address`.
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "network", content = "address")]
-#[serde(rename_all = "snake_case")]
-pub enum NetworkAddress {
-    Polkadot(IdentityAddress),
-    Kusama(IdentityAddress),
+pub struct NetworkAddress {
+    network: BlankNetwork,
+    address: IdentityAddress,
 }
 
 impl NetworkAddress {
     pub fn from(network: BlankNetwork, address: IdentityAddress) -> Self {
-        match network {
-            BlankNetwork::Polkadot => NetworkAddress::Polkadot(address),
-            BlankNetwork::Kusama => NetworkAddress::Kusama(address),
+        NetworkAddress {
+            network: network,
+            address: address,
         }
     }
+    pub fn net(&self) -> BlankNetwork {
+        self.network.clone()
+    }
     pub fn net_str(&self) -> &str {
-        match self {
-            NetworkAddress::Polkadot(_) => "polkadot",
-            NetworkAddress::Kusama(_) => "kusama",
-        }
+        self.network.as_str()
     }
     pub fn address_str(&self) -> &str {
-        match self {
-            NetworkAddress::Polkadot(address) => address.0.as_str(),
-            NetworkAddress::Kusama(address) => address.0.as_str(),
-        }
+        self.address.as_str()
     }
 }
 
@@ -535,9 +970,30 @@ pub struct IdentityState {
     pub fields: HashMap<IdentityFieldType, FieldStatus>,
 }
 
+impl IdentityState {
+    /// Creates the state of a new judgement request, including a fresh
+    /// on-chain challenge.
+    pub fn new(net_address: NetworkAddress, fields: Vec<FieldStatus>) -> Self {
+        IdentityState {
+            net_address: net_address,
+            on_chain_challenge: OnChainChallenge::gen(),
+            fields: fields
+                .into_iter()
+                .map(|field| (field.field.as_type(), field))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct IdentityAddress(String);
 
+impl IdentityAddress {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
 impl From<String> for IdentityAddress {
     fn from(val: String) -> Self {
         IdentityAddress(val)
@@ -551,6 +1007,10 @@ pub struct FieldStatus {
     pub field: IdentityField,
     is_permitted: bool,
     challenge: ChallengeStatus,
+    // Locked fields no longer accept responses. Only serialized if set, so
+    // the format of unlocked fields is unchanged.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_locked: bool,
 }
 
 impl FieldStatus {
@@ -580,6 +1040,107 @@ impl FieldStatus {
     pub fn is_not_valid(&self) -> bool {
         !self.is_valid()
     }
+    /// Whether the latest verification attempt of the field failed.
+    pub fn is_invalid(&self) -> bool {
+        match &self.challenge {
+            ChallengeStatus::ExpectMessage(state) => state.status == Validity::Invalid,
+            ChallengeStatus::BackAndForth(state) => {
+                state.first_check_status == Validity::Invalid
+                    || state.second_check_status == Validity::Invalid
+            }
+            ChallengeStatus::CheckDisplayName(state) => state.status == Validity::Invalid,
+            ChallengeStatus::Unsupported => false,
+        }
+    }
+    /// Whether the field must be verified for the judgement. Fields which
+    /// the registrar does not verify are shown to the user, but not required.
+    pub fn is_required(&self) -> bool {
+        self.is_permitted
+    }
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+    /// Replaces the challenge which is currently awaited with a new one.
+    pub fn regenerate(&mut self) {
+        match &mut self.challenge {
+            ChallengeStatus::ExpectMessage(state) => {
+                state.expected_message = ExpectedMessage::gen();
+            }
+            ChallengeStatus::BackAndForth(state) => {
+                if state.first_check_status != Validity::Valid {
+                    state.expected_message = ExpectedMessage::gen();
+                } else {
+                    state.expected_message_back = ExpectedMessage::gen();
+                }
+            }
+            ChallengeStatus::CheckDisplayName(_) | ChallengeStatus::Unsupported => {}
+        }
+    }
+    /// Replaces the challenge which is currently awaited with a new one,
+    /// resets its validity and unlocks the field. Returns false if the field
+    /// does not have a challenge which is sent by the user.
+    fn renew(&mut self) -> bool {
+        match &mut self.challenge {
+            ChallengeStatus::ExpectMessage(state) => {
+                state.expected_message = ExpectedMessage::gen();
+                state.status = Validity::Unconfirmed;
+            }
+            ChallengeStatus::BackAndForth(state) => {
+                if state.first_check_status != Validity::Valid {
+                    state.expected_message = ExpectedMessage::gen();
+                    state.first_check_status = Validity::Unconfirmed;
+                } else {
+                    state.expected_message_back = ExpectedMessage::gen();
+                    state.second_check_status = Validity::Unconfirmed;
+                }
+            }
+            ChallengeStatus::CheckDisplayName(_) | ChallengeStatus::Unsupported => return false,
+        }
+
+        self.is_locked = false;
+        true
+    }
+    fn expected_messages(&self) -> Vec<&ExpectedMessage> {
+        match &self.challenge {
+            ChallengeStatus::ExpectMessage(state) => vec![&state.expected_message],
+            ChallengeStatus::BackAndForth(state) => {
+                vec![&state.expected_message, &state.expected_message_back]
+            }
+            ChallengeStatus::CheckDisplayName(_) | ChallengeStatus::Unsupported => vec![],
+        }
+    }
+    /// Whether the display name is too similar to the display name of another
+    /// identity.
+    pub fn is_display_name_violation(&self) -> bool {
+        match &self.challenge {
+            ChallengeStatus::CheckDisplayName(state) => state.status == Validity::Invalid,
+            _ => false,
+        }
+    }
+    /// Creates the status of the field, whose challenge is sent to the given
+    /// account of the registrar. Fields which require such an account are not
+    /// permitted if none is available.
+    pub fn new(field: IdentityField, to: Option<RegistrarIdentityField>) -> Self {
+        match to {
+            Some(to) => FieldStatus::from((field, to)),
+            None => match field {
+                IdentityField::Email(_) | IdentityField::Twitter(_) | IdentityField::Matrix(_) => {
+                    FieldStatus::not_permitted(field)
+                }
+                // The registrar account is not used by any other challenge.
+                _ => FieldStatus::from((field.clone(), RegistrarIdentityField::from(field))),
+            },
+        }
+    }
+    /// Creates the status of a field which is not verified by the registrar.
+    pub fn not_permitted(field: IdentityField) -> Self {
+        FieldStatus {
+            field: field,
+            is_permitted: false,
+            challenge: ChallengeStatus::Unsupported,
+            is_locked: false,
+        }
+    }
 }
 
 impl From<(IdentityField, RegistrarIdentityField)> for FieldStatus {
@@ -596,6 +1157,7 @@ impl From<(IdentityField, RegistrarIdentityField)> for FieldStatus {
                 }
             },
             challenge: challenge,
+            is_locked: false,
         }
     }
 }
@@ -603,11 +1165,14 @@ impl From<(IdentityField, RegistrarIdentityField)> for FieldStatus {
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct OnChainChallenge(String);
 
+/// The prefix of every on-chain challenge.
+pub const ON_CHAIN_CHALLENGE_PREFIX: &str = "w3f_registrar:";
+
 impl OnChainChallenge {
     pub fn gen() -> Self {
         OnChainChallenge({
             let random: [u8; 16] = thread_rng().gen();
-            format!("w3f_registrar:{}", hex::encode(random))
+            format!("{}{}", ON_CHAIN_CHALLENGE_PREFIX, hex::encode(random))
         })
     }
     pub fn as_str(&self) -> &str {
@@ -771,14 +1336,13 @@ impl From<String> for ProvidedMessagePart {
 }
 
 impl ExpectedMessage {
+    /// Returns the part of the message which contains the expected message,
+    /// if any (see `crate::challenge::matches`).
     fn contains<'a>(&self, message: &'a ProvidedMessage) -> Option<&'a ProvidedMessagePart> {
-        for part in &message.parts {
-            if self.0.contains(&part.0) {
-                return Some(part);
-            }
-        }
-
-        None
+        message
+            .parts
+            .iter()
+            .find(|part| challenge::matches(&self.0, &part.0))
     }
 }
 
@@ -793,6 +1357,12 @@ impl RegistrarIdentityField {
     }
 }
 
+impl From<IdentityField> for RegistrarIdentityField {
+    fn from(val: IdentityField) -> Self {
+        RegistrarIdentityField { field: val }
+    }
+}
+
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "address")]
 pub enum IdentityField {
@@ -832,7 +1402,7 @@ impl IdentityField {
 }
 
 impl IdentityField {
-    fn as_type(&self) -> IdentityFieldType {
+    pub fn as_type(&self) -> IdentityFieldType {
         match self {
             IdentityField::LegalName(_) => IdentityFieldType::LegalName,
             IdentityField::DisplayName(_) => IdentityFieldType::DisplayName,
@@ -924,19 +1494,28 @@ mod tests {
 
     impl NetworkAddress {
         pub fn alice() -> Self {
-            NetworkAddress::Polkadot(IdentityAddress::from(
-                "1gfpAmeKYhEoSrEgQ5UDYTiNSeKPvxVfLVWcW73JGnX9L6M".to_string(),
-            ))
+            NetworkAddress::from(
+                BlankNetwork::from("polkadot"),
+                IdentityAddress::from(
+                    "1gfpAmeKYhEoSrEgQ5UDYTiNSeKPvxVfLVWcW73JGnX9L6M".to_string(),
+                ),
+            )
         }
         pub fn bob() -> Self {
-            NetworkAddress::Polkadot(IdentityAddress::from(
-                "15iMSee2Zg3kJBu3HjimR5zVLNdNHvpUeWwrp4iAL4x7KZ8P".to_string(),
-            ))
+            NetworkAddress::from(
+                BlankNetwork::from("polkadot"),
+                IdentityAddress::from(
+                    "15iMSee2Zg3kJBu3HjimR5zVLNdNHvpUeWwrp4iAL4x7KZ8P".to_string(),
+                ),
+            )
         }
         pub fn eve() -> Self {
-            NetworkAddress::Polkadot(IdentityAddress::from(
-                "12sgvwDcEenDwAppRquN8Yh6Bu4um5x2PRyURLwP42XVMg45".to_string(),
-            ))
+            NetworkAddress::from(
+                BlankNetwork::from("polkadot"),
+                IdentityAddress::from(
+                    "12sgvwDcEenDwAppRquN8Yh6Bu4um5x2PRyURLwP42XVMg45".to_string(),
+                ),
+            )
         }
     }
 
@@ -1,31 +1,54 @@
-use super::Projection;
+use super::{Projection, Selection};
 use crate::api::ConnectionPool;
-use crate::event::{Event, EventType, Notification, StateWrapper};
+use crate::api_v2::session::AccountStateChange;
+use crate::event::{ErrorMessage, Event, EventType, JudgementKind, Notification, StateWrapper};
+use crate::manager::{NetworkAddress, UpdateChanges};
 use crate::Result;
 use crate::{aggregate::verifier::VerifierAggregateId, manager::IdentityManager};
 use parking_lot::RwLock;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 
+/// Keeps the subscribers of both websocket APIs informed about state changes.
 pub struct SessionNotifier {
     connection_pool: ConnectionPool,
+    account_changes: broadcast::Sender<AccountStateChange>,
     manager: Arc<RwLock<IdentityManager>>,
 }
 
 impl SessionNotifier {
-    pub fn new(pool: ConnectionPool, manager: Arc<RwLock<IdentityManager>>) -> Self {
+    pub fn new(
+        pool: ConnectionPool,
+        account_changes: broadcast::Sender<AccountStateChange>,
+        manager: Arc<RwLock<IdentityManager>>,
+    ) -> Self {
         SessionNotifier {
             connection_pool: pool,
+            account_changes: account_changes,
             manager: manager,
         }
     }
+    fn notify(&self, net_address: &NetworkAddress, state: StateWrapper) {
+        self.connection_pool.broadcast(net_address, state.clone());
+        // An error only means that there are no receivers.
+        let _ = self.account_changes.send(AccountStateChange::Add(state));
+    }
+    fn notify_removed(&self, net_address: &NetworkAddress) {
+        let _ = self
+            .account_changes
+            .send(AccountStateChange::Delete(net_address.clone()));
+    }
 }
 
 #[async_trait]
 impl Projection for SessionNotifier {
-    type Id = VerifierAggregateId;
     type Event = Event;
     type Error = anyhow::Error;
 
+    fn selection(&self) -> Selection {
+        Selection::stream(VerifierAggregateId)
+    }
+
     async fn project(&mut self, event: Self::Event) -> Result<()> {
         // Clone due to partial move.
         let net_address = match event.body {
@@ -33,13 +56,20 @@ impl Projection for SessionNotifier {
             EventType::FieldStatusVerified(ref field_status) => field_status.net_address.clone(),
             // TODO: Does this need any special handling?
             EventType::IdentityFullyVerified(ref verified) => verified.net_address.clone(),
+            EventType::IdentityExpired(ref expired) => expired.net_address.clone(),
+            EventType::IdentityRemoved(ref removed) => removed.net_address.clone(),
+            EventType::JudgementRequestCancelled(ref cancelled) => cancelled.net_address.clone(),
+            EventType::JudgementGiven(ref given) => given.net_address.clone(),
+            EventType::IdentityLocked(ref locked) => locked.net_address.clone(),
+            EventType::IdentityUnlocked(ref unlocked) => unlocked.net_address.clone(),
+            EventType::ChallengeRegenerated(ref regenerated) => regenerated.net_address.clone(),
             _ => return Ok(()),
         };
 
         match event.body {
             EventType::IdentityInserted(inserted) => {
                 self.manager.write().insert_identity(inserted.clone());
-                self.connection_pool.broadcast(
+                self.notify(
                     &net_address,
                     StateWrapper::newly_inserted_notification(inserted),
                 );
@@ -55,8 +85,71 @@ impl Projection for SessionNotifier {
 
                 if let Some(state) = self.manager.read().lookup_full_state(&net_address) {
                     let state = StateWrapper::with_notifications(state, notifications);
+                    self.notify(&net_address, state);
+                }
+            }
+            EventType::ChallengeRegenerated(regenerated) => {
+                let notification = self.manager.write().replace_challenge(regenerated)?.into();
+
+                if let Some(state) = self.manager.read().lookup_full_state(&net_address) {
+                    let state = StateWrapper::with_notifications(state, vec![notification]);
+                    self.notify(&net_address, state);
+                }
+            }
+            EventType::IdentityLocked(_) => {
+                self.manager.write().lock_identity(&net_address);
+
+                if let Some(state) = self.manager.read().lookup_full_state(&net_address) {
+                    let notification = UpdateChanges::IdentityLocked(net_address.clone()).into();
+                    let state = StateWrapper::with_notifications(state, vec![notification]);
+                    self.notify(&net_address, state);
+                }
+            }
+            EventType::IdentityUnlocked(_) => {
+                self.manager.write().unlock_identity(&net_address);
+
+                if let Some(state) = self.manager.read().lookup_full_state(&net_address) {
+                    let notification = UpdateChanges::IdentityUnlocked(net_address.clone()).into();
+                    let state = StateWrapper::with_notifications(state, vec![notification]);
+                    self.notify(&net_address, state);
+                }
+            }
+            EventType::IdentityExpired(_) => {
+                self.manager.write().remove_identity(&net_address);
+                self.connection_pool.broadcast_error(
+                    &net_address,
+                    ErrorMessage::judgement_request_expired(&net_address),
+                );
+                self.notify_removed(&net_address);
+            }
+            EventType::IdentityRemoved(_) => {
+                self.manager.write().clear_identity(&net_address);
+                self.connection_pool.broadcast_removed(&net_address);
+                self.notify_removed(&net_address);
+            }
+            EventType::JudgementRequestCancelled(_) => {
+                self.manager.write().remove_identity(&net_address);
+                self.connection_pool.broadcast_removed(&net_address);
+                self.notify_removed(&net_address);
+            }
+            EventType::JudgementGiven(ref given) => {
+                let notification = match given.judgement {
+                    JudgementKind::Reasonable => {
+                        Notification::Success("The judgement has been given.".to_string())
+                    }
+                    judgement => Notification::Error(format!(
+                        "The verification failed, the {} judgement has been given.",
+                        judgement.as_str()
+                    )),
+                };
+
+                // Inform the subscribers one last time before the identity is
+                // removed.
+                if let Some(state) = self.manager.write().remove_identity(&net_address) {
+                    let state = StateWrapper::with_notifications(state, vec![notification]);
                     self.connection_pool.broadcast(&net_address, state);
                 }
+                self.notify_removed(&net_address);
             }
             _ => return Ok(()),
         }
@@ -1,76 +1,475 @@
-use super::Projection;
-use crate::event::{Event, EventType, RemarkFound};
+use super::policy::JudgementPolicy;
+use super::{Projection, Selection};
+use crate::aggregate::remark::RemarkWatcherId;
+use crate::aggregate::verifier::{VerifierAggregate, VerifierAggregateId, VerifierCommand};
+use crate::aggregate::Repository;
+use crate::chain::{
+    account_id, ChainClient, Judgement, JudgementSigner, NotSubmitted, RegistrarIndex,
+};
+use crate::event::{BlankNetwork, Event, EventType, JudgementKind, RemarkFound};
 use crate::manager::{NetworkAddress, OnChainChallenge};
+use crate::{NetworkConfig, Result};
 use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
 
+/// Seconds after which a submitted judgement which did not show up on-chain
+/// is considered lost and submitted again.
+const SUBMISSION_TIMEOUT: u64 = 600;
+
+/// Gives the judgements on a single network.
+struct NetworkJudge {
+    registrar_index: RegistrarIndex,
+    client: Box<dyn ChainClient>,
+    // `None` in dry-run mode.
+    signer: Option<Box<dyn JudgementSigner>>,
+    policy: JudgementPolicy,
+}
+
+/// Gives the judgement once an identity is fully verified and the on-chain
+/// challenge was found, or once the policy of the network closes the request.
+/// Judgements are never submitted twice: identities for which the judgement
+/// was already given are no longer pending, the on-chain judgement is checked
+/// before each submission and each submission is recorded beforehand. A
+/// recorded submission is only repeated if it certainly failed, otherwise the
+/// judgement is confirmed once it shows up on-chain (see `reconcile`). Hence
+/// failed submissions can safely be retried by the projector.
 pub struct JudgmentGiver {
+    repository: Repository<VerifierAggregate>,
+    networks: HashMap<BlankNetwork, NetworkJudge>,
     remarks: HashMap<NetworkAddress, RemarkFound>,
     pending: HashMap<NetworkAddress, OnChainChallenge>,
+    // Since when the submitted judgements are known to be missing on-chain.
+    unconfirmed: HashMap<NetworkAddress, Instant>,
+    submission_timeout: Duration,
+}
+
+impl JudgmentGiver {
+    pub fn new(repository: Repository<VerifierAggregate>) -> Self {
+        JudgmentGiver {
+            repository: repository,
+            networks: HashMap::new(),
+            remarks: HashMap::new(),
+            pending: HashMap::new(),
+            unconfirmed: HashMap::new(),
+            submission_timeout: Duration::from_secs(SUBMISSION_TIMEOUT),
+        }
+    }
+    /// Sets after which time a submitted judgement which did not show up
+    /// on-chain is submitted again.
+    pub fn set_submission_timeout(self, submission_timeout: Duration) -> Self {
+        JudgmentGiver {
+            submission_timeout: submission_timeout,
+            ..self
+        }
+    }
+    /// Gives the judgements on the network with the given signer. Without a
+    /// signer, the judgements are only logged (dry-run).
+    pub fn with_network(
+        mut self,
+        config: &NetworkConfig,
+        client: Box<dyn ChainClient>,
+        signer: Option<Box<dyn JudgementSigner>>,
+    ) -> Self {
+        self.networks.insert(
+            config.network.clone(),
+            NetworkJudge {
+                registrar_index: config.registrar_index,
+                client: client,
+                signer: signer,
+                policy: JudgementPolicy::new(config.policy.clone()),
+            },
+        );
+
+        self
+    }
+    fn policy(&mut self, net_address: &NetworkAddress) -> Option<&mut JudgementPolicy> {
+        self.networks
+            .get_mut(&net_address.net())
+            .map(|judge| &mut judge.policy)
+    }
+    async fn remark_mismatch(&mut self, net_address: &NetworkAddress) -> Result<()> {
+        match self
+            .policy(net_address)
+            .and_then(|policy| policy.remark_mismatch(net_address))
+        {
+            Some(judgement) => self.give_judgement(net_address, judgement).await,
+            None => Ok(()),
+        }
+    }
+    async fn give_judgement(
+        &mut self,
+        net_address: &NetworkAddress,
+        judgement: JudgementKind,
+    ) -> Result<()> {
+        let judge = match self.networks.get(&net_address.net()) {
+            Some(judge) => judge,
+            None => {
+                warn!(
+                    "No judgements are given on {}, skipping {}",
+                    net_address.net_str(),
+                    net_address.address_str()
+                );
+                return Ok(());
+            }
+        };
+
+        // The judgement was already given or the request was removed.
+        self.repository.catch_up().await?;
+        if !self.repository.state().is_pending(net_address) {
+            debug!(
+                "No pending judgement request for {}, skipping",
+                net_address.address_str()
+            );
+            return Ok(());
+        }
+
+        // The outcome of a previous submission is unknown, it might still be
+        // included. Only that judgement can show up on-chain.
+        let submitted = self.repository.state().submitted_judgement(net_address);
+        let judgement = submitted.unwrap_or(judgement);
+
+        let target = account_id(net_address)?;
+        let registration = judge.client.registration(&target).await?;
+
+        match registration {
+            Some(registration) if registration.is_pending(judge.registrar_index) => {
+                let signer = match &judge.signer {
+                    Some(signer) => signer,
+                    None => {
+                        info!(
+                            "Dry-run, not providing {} judgement for {} on {}",
+                            judgement.as_str(),
+                            net_address.address_str(),
+                            net_address.net_str()
+                        );
+                        return Ok(());
+                    }
+                };
+
+                // Confirmed or submitted again by `reconcile`.
+                if submitted.is_some() {
+                    debug!(
+                        "{} judgement for {} was already submitted, waiting for it on-chain",
+                        judgement.as_str(),
+                        net_address.address_str()
+                    );
+                    return Ok(());
+                }
+
+                info!(
+                    "Providing {} judgement for {} on {}",
+                    judgement.as_str(),
+                    net_address.address_str(),
+                    net_address.net_str()
+                );
+
+                self.repository
+                    .apply(VerifierCommand::SubmitJudgement {
+                        net_address: net_address.clone(),
+                        judgement: judgement,
+                    })
+                    .await?;
+
+                if let Err(err) = signer
+                    .provide_judgement(judge.registrar_index, &target, Judgement::from(judgement))
+                    .await
+                {
+                    if err.downcast_ref::<NotSubmitted>().is_some() {
+                        self.repository
+                            .apply(VerifierCommand::FailJudgementSubmission {
+                                net_address: net_address.clone(),
+                            })
+                            .await?;
+                    }
+
+                    return Err(err);
+                }
+            }
+            // A previous submission succeeded, even though it was reported
+            // as failed.
+            Some(registration)
+                if registration.judgement(judge.registrar_index)
+                    == Some(&Judgement::from(judgement)) =>
+            {
+                info!(
+                    "Judgement for {} was already provided",
+                    net_address.address_str()
+                );
+            }
+            _ => {
+                warn!(
+                    "No on-chain judgement request found for {} on {}, skipping",
+                    net_address.address_str(),
+                    net_address.net_str()
+                );
+                return Ok(());
+            }
+        }
+
+        self.repository
+            .apply(VerifierCommand::ConfirmJudgement {
+                net_address: net_address.clone(),
+                judgement: judgement,
+            })
+            .await
+    }
+    /// Checks the on-chain state of the judgements which were submitted, but
+    /// whose outcome is unknown. Judgements which showed up on-chain are
+    /// confirmed, while judgements which are still missing after the
+    /// submission timeout are submitted again.
+    pub async fn reconcile(&mut self) -> Result<()> {
+        self.repository.catch_up().await?;
+        let submitted = self.repository.state().submitted_judgements();
+
+        // Forget the submissions which were resolved in the meantime.
+        self.unconfirmed.retain(|net_address, _| {
+            submitted
+                .iter()
+                .any(|(submitted, _)| submitted == net_address)
+        });
+
+        for (net_address, judgement) in submitted {
+            let judge = match self.networks.get(&net_address.net()) {
+                Some(judge) => judge,
+                None => continue,
+            };
+
+            let registration = judge
+                .client
+                .registration(&account_id(&net_address)?)
+                .await?;
+            match registration {
+                Some(registration)
+                    if registration.judgement(judge.registrar_index)
+                        == Some(&Judgement::from(judgement)) =>
+                {
+                    info!(
+                        "Submitted judgement for {} found on-chain",
+                        net_address.address_str()
+                    );
+
+                    self.repository
+                        .apply(VerifierCommand::ConfirmJudgement {
+                            net_address: net_address,
+                            judgement: judgement,
+                        })
+                        .await?;
+                }
+                Some(registration) if registration.is_pending(judge.registrar_index) => {
+                    let since = *self
+                        .unconfirmed
+                        .entry(net_address.clone())
+                        .or_insert_with(Instant::now);
+
+                    if since.elapsed() < self.submission_timeout {
+                        continue;
+                    }
+
+                    warn!(
+                        "Submitted judgement for {} did not show up on-chain within {} seconds, \
+                        submitting it again",
+                        net_address.address_str(),
+                        self.submission_timeout.as_secs()
+                    );
+
+                    self.unconfirmed.remove(&net_address);
+                    self.repository
+                        .apply(VerifierCommand::FailJudgementSubmission {
+                            net_address: net_address.clone(),
+                        })
+                        .await?;
+
+                    if let Err(err) = self.give_judgement(&net_address, judgement).await {
+                        error!(
+                            "Failed to submit judgement for {} again: {:?}",
+                            net_address.address_str(),
+                            err
+                        );
+                    }
+                }
+                // The request was cancelled or the identity cleared, which is
+                // handled by the chain watcher.
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+    /// Whether the event concerns an identity on one of the networks of this
+    /// giver. The identities of other networks are judged by their own giver.
+    fn judges(&self, event: &EventType) -> bool {
+        let net_address = match event {
+            EventType::FieldStatusVerified(verified) => &verified.net_address,
+            EventType::IdentityFullyVerified(identity) => &identity.net_address,
+            EventType::RemarkFound(found) => &found.net_address,
+            EventType::JudgementGiven(given) => &given.net_address,
+            EventType::IdentityExpired(expired) => &expired.net_address,
+            EventType::IdentityRemoved(removed) => &removed.net_address,
+            EventType::JudgementRequestCancelled(cancelled) => &cancelled.net_address,
+            _ => return false,
+        };
+
+        self.networks.contains_key(&net_address.net())
+    }
+    /// Forgets the identity once the judgement request is closed.
+    fn close(&mut self, net_address: &NetworkAddress) {
+        self.remarks.remove(net_address);
+        self.pending.remove(net_address);
+
+        if let Some(policy) = self.policy(net_address) {
+            policy.reset(net_address);
+        }
+    }
 }
 
 #[async_trait]
 impl Projection for JudgmentGiver {
-    type Id = ();
     type Event = Event;
     type Error = anyhow::Error;
 
+    // Verified identities are tracked on the identity state stream, while the
+    // remarks are watched separately.
+    fn selection(&self) -> Selection {
+        Selection::stream(VerifierAggregateId).and_stream(RemarkWatcherId)
+    }
+
     async fn project(&mut self, event: Self::Event) -> std::result::Result<(), Self::Error> {
+        if !self.judges(&event.body) {
+            return Ok(());
+        }
+
         match event.body {
+            EventType::FieldStatusVerified(verified) => {
+                let net_address = verified.net_address;
+                let field_status = verified.field_status;
+
+                if let Some(judgement) = self
+                    .policy(&net_address)
+                    .and_then(|policy| policy.field_verified(&net_address, &field_status))
+                {
+                    warn!(
+                        "Failed verification for {}, closing judgement request",
+                        net_address.address_str()
+                    );
+
+                    self.give_judgement(&net_address, judgement).await?;
+                }
+            }
             EventType::IdentityFullyVerified(identity) => {
                 // It's very unlikely that the remark is set on-chain before the
                 // identity is verified. However, the challenge can be fetched
                 // via the API so this case must be handled.
-                if let Some(remark) = self.remarks.get(&identity.net_address) {
-                    if identity.on_chain_challenge.matches_remark(&remark) {
-                        info!(
-                            "Valid remark found for {}, submitting valid judgement",
-                            identity.net_address.address_str()
-                        );
-
-                        // TODO: Send judgement to watcher.
-                    } else {
+                let valid = match self.remarks.get(&identity.net_address) {
+                    Some(remark) if identity.on_chain_challenge.matches_remark(&remark) => {
+                        Some(true)
+                    }
+                    Some(remark) => {
                         warn!(
                             "Invalid remark challenge for {}, received: {}, expected: {}",
                             identity.net_address.address_str(),
                             remark.as_str(),
                             identity.on_chain_challenge.as_str(),
-                        )
+                        );
+                        Some(false)
                     }
+                    None => None,
+                };
 
-                    // TODO: Notify web session
-                }
+                self.pending.insert(
+                    identity.net_address.clone(),
+                    identity.on_chain_challenge.clone(),
+                );
 
-                self.pending
-                    .insert(identity.net_address, identity.on_chain_challenge);
-            }
-            EventType::RemarkFound(found) => {
-                if let Some(challenge) = self.pending.get(&found.net_address) {
-                    if challenge.matches_remark(&found) {
-                        // TODO: Send judgement to watcher.
+                match valid {
+                    Some(true) => {
                         info!(
                             "Valid remark found for {}, submitting valid judgement",
-                            found.net_address.address_str()
+                            identity.net_address.address_str()
                         );
 
-                        // TODO: Send judgement to watcher.
-                    } else {
+                        self.give_judgement(&identity.net_address, JudgementKind::Reasonable)
+                            .await?;
+                    }
+                    Some(false) => self.remark_mismatch(&identity.net_address).await?,
+                    None => {}
+                }
+            }
+            EventType::RemarkFound(found) => {
+                let valid = match self.pending.get(&found.net_address) {
+                    Some(challenge) if challenge.matches_remark(&found) => true,
+                    Some(challenge) => {
                         warn!(
                             "Invalid remark challenge for {}, received: {}, expected: {}",
                             found.net_address.address_str(),
                             found.remark.as_str(),
                             challenge.as_str(),
-                        )
+                        );
+                        false
                     }
+                    None => {
+                        self.remarks.insert(found.net_address.clone(), found);
+                        return Ok(());
+                    }
+                };
+
+                if valid {
+                    info!(
+                        "Valid remark found for {}, submitting valid judgement",
+                        found.net_address.address_str()
+                    );
 
-                    // TODO: Notify web session
+                    self.give_judgement(&found.net_address, JudgementKind::Reasonable)
+                        .await?;
                 } else {
-                    self.remarks.insert(found.net_address.clone(), found);
+                    self.remark_mismatch(&found.net_address).await?;
+                }
+            }
+            EventType::JudgementGiven(given) => self.close(&given.net_address),
+            EventType::IdentityExpired(expired) => self.close(&expired.net_address),
+            EventType::IdentityRemoved(removed) => self.close(&removed.net_address),
+            EventType::JudgementRequestCancelled(cancelled) => self.close(&cancelled.net_address),
+            _ => {}
+        }
+
+        Ok(())
+    }
+    // Judgements which were due before the checkpoint were already handled,
+    // so only the pending identities, remarks and policy counters are
+    // rebuilt.
+    fn restore(&mut self, event: Self::Event) -> std::result::Result<(), Self::Error> {
+        if !self.judges(&event.body) {
+            return Ok(());
+        }
+
+        match event.body {
+            EventType::FieldStatusVerified(verified) => {
+                if let Some(policy) = self.policy(&verified.net_address) {
+                    policy.field_verified(&verified.net_address, &verified.field_status);
                 }
             }
-            EventType::JudgementGiven(given) => {
-                self.remarks.remove(&given.net_address);
-                self.pending.remove(&given.net_address);
+            EventType::IdentityFullyVerified(identity) => {
+                self.pending
+                    .insert(identity.net_address, identity.on_chain_challenge);
             }
+            EventType::RemarkFound(found) => match self.pending.get(&found.net_address) {
+                Some(challenge) if !challenge.matches_remark(&found) => {
+                    if let Some(policy) = self.policy(&found.net_address) {
+                        policy.remark_mismatch(&found.net_address);
+                    }
+                }
+                Some(_) => {}
+                // The remark stream is restored after the identity state, so
+                // remarks of closed judgement requests are skipped.
+                None => {
+                    if self.repository.state().is_pending(&found.net_address) {
+                        self.remarks.insert(found.net_address.clone(), found);
+                    }
+                }
+            },
+            EventType::JudgementGiven(given) => self.close(&given.net_address),
+            EventType::IdentityExpired(expired) => self.close(&expired.net_address),
+            EventType::IdentityRemoved(removed) => self.close(&removed.net_address),
+            EventType::JudgementRequestCancelled(cancelled) => self.close(&cancelled.net_address),
             _ => {}
         }
 
@@ -1,32 +1,66 @@
-use super::Projection;
+use super::{Projection, Selection};
 use crate::aggregate::message_watcher::MessageWatcherId;
 use crate::aggregate::verifier::{VerifierAggregate, VerifierCommand};
 use crate::aggregate::Repository;
 use crate::event::{Event, EventType};
+use crate::store::RecordedEvent;
 use crate::Result;
+use std::convert::TryFrom;
+
+/// An event of the message watcher stream together with its revision.
+pub struct RecordedMessage {
+    revision: u64,
+    event: Event,
+}
+
+impl TryFrom<RecordedEvent> for RecordedMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(val: RecordedEvent) -> Result<Self> {
+        let revision = val.revision;
+
+        Ok(RecordedMessage {
+            revision: revision,
+            event: Event::try_from(val)?,
+        })
+    }
+}
 
 pub struct MessageVerifier {
     repository: Repository<VerifierAggregate>,
 }
 
+impl MessageVerifier {
+    pub fn new(repository: Repository<VerifierAggregate>) -> Self {
+        MessageVerifier {
+            repository: repository,
+        }
+    }
+}
+
 #[async_trait]
 impl Projection for MessageVerifier {
-    type Id = MessageWatcherId;
-    type Event = Event;
+    type Event = RecordedMessage;
     type Error = anyhow::Error;
 
-    async fn project(&mut self, event: Self::Event) -> Result<()> {
-        let message = match event.body {
+    fn selection(&self) -> Selection {
+        Selection::stream(MessageWatcherId)
+    }
+
+    async fn project(&mut self, recorded: Self::Event) -> Result<()> {
+        // Other events on the stream are not relevant for this projection.
+        let message = match recorded.event.body {
             EventType::ExternalMessage(message) => message,
-            _ => {
-                return Err(
-                    anyhow!("Received unexpected message in MessageVerifier projection").into(),
-                )
-            }
+            _ => return Ok(()),
         };
 
+        // Messages after the checkpoint of the projection are verified again
+        // after a restart, which the aggregate ignores based on the revision.
         self.repository
-            .apply(VerifierCommand::VerifyMessage(message))
+            .apply(VerifierCommand::VerifyRecordedMessage {
+                message: message,
+                revision: recorded.revision,
+            })
             .await?;
 
         Ok(())
@@ -1,109 +1,593 @@
-use crate::Result;
-use eventstore::{Client, EventData, ExpectedVersion, ReadResult, RecordedEvent, ResolvedEvent};
+use crate::store::{EventData, EventStore, ExpectedRevision, RecordedEvent};
+use crate::{ProjectionConfig, Result};
 use futures::join;
-use futures::TryStreamExt;
+use futures::stream::{self, TryStreamExt};
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::error::Error as StdError;
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tokio::time::{interval, Duration};
+use tokio::time::{self, interval, Duration, Instant};
 
 mod identity_change_notifier;
 mod message_verifier;
 pub use identity_change_notifier::SessionNotifier;
+pub use judgment_giver::JudgmentGiver;
+pub use message_verifier::MessageVerifier;
 mod judgment_giver;
+mod policy;
 
 #[async_trait]
 pub trait Projection {
-    type Id;
     type Event;
     type Error;
 
+    /// The events this projection consumes.
+    fn selection(&self) -> Selection;
     async fn project(&mut self, event: Self::Event) -> std::result::Result<(), Self::Error>;
+    /// Rebuilds the in-memory state from an event which was already projected
+    /// before the checkpoint, without any side effects. Only called if
+    /// enabled with `Projector::restore_state`.
+    fn restore(&mut self, _event: Self::Event) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A set of streams, optionally restricted to specific event types. Events of
+/// different streams are projected in the order they are received, there is no
+/// global ordering across streams.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Selection {
+    streams: Vec<String>,
+    event_types: Option<Vec<String>>,
+}
+
+impl Selection {
+    pub fn stream<Id: AsRef<str>>(id: Id) -> Self {
+        Selection {
+            streams: vec![id.as_ref().to_string()],
+            event_types: None,
+        }
+    }
+    pub fn and_stream<Id: AsRef<str>>(mut self, id: Id) -> Self {
+        let stream = id.as_ref().to_string();
+        if !self.streams.contains(&stream) {
+            self.streams.push(stream);
+        }
+
+        self
+    }
+    /// Only consumes events of the given types, all other events are ignored.
+    pub fn event_types(self, event_types: &[&str]) -> Self {
+        Selection {
+            event_types: Some(event_types.iter().map(|ty| ty.to_string()).collect()),
+            ..self
+        }
+    }
+    pub fn streams(&self) -> &[String] {
+        &self.streams
+    }
+    fn accepts(&self, recorded: &RecordedEvent) -> bool {
+        self.event_types
+            .as_ref()
+            .map(|types| types.contains(&recorded.event_type))
+            .unwrap_or(true)
+    }
+}
+
+impl Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.streams.join(", "))
+    }
+}
+
+/// The stream which records the events that failed to be projected.
+pub const DEAD_LETTER_STREAM: &str = "projection_dead_letters";
+/// How many of the latest dead letters are checked for duplicates.
+const DEAD_LETTER_LOOKBACK: usize = 100;
+
+/// The latest processed revision of each stream of a projection, persisted in
+/// the event store.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    #[serde(default)]
+    revisions: BTreeMap<String, u64>,
+    // Legacy format, which only supported a single stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revision: Option<u64>,
+}
+
+/// An event which failed to be projected, including the reason.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The projection, identified by its selected streams. Empty for dead
+    /// letters which were recorded before this field was introduced.
+    #[serde(default)]
+    pub projection: String,
+    pub stream: String,
+    pub revision: u64,
+    pub event_type: String,
+    pub data: serde_json::Value,
+    pub error: String,
+    pub skipped: bool,
 }
 
 pub struct Projector<P> {
     projection: Arc<RwLock<P>>,
-    client: Client,
-    latest_revision: Arc<RwLock<u64>>,
+    store: Arc<dyn EventStore>,
+    // The latest processed revision of each stream.
+    revisions: BTreeMap<String, u64>,
+    // The stream on which the checkpoints are persisted, if enabled.
+    checkpoint_stream: Option<String>,
+    // Whether the events up to the checkpoint are passed to
+    // `Projection::restore` on startup.
+    restore: bool,
+    rebuild: bool,
+    config: ProjectionConfig,
 }
 
 impl<P> Projector<P>
 where
     P: 'static + Send + Sync + Projection,
-    <P as Projection>::Id: Send + Sync + Default + AsRef<str>,
     <P as Projection>::Event: Send + Sync + TryFrom<RecordedEvent>,
     <P as Projection>::Error: 'static + Send + Sync + Debug,
 {
-    pub fn new(projection: Arc<RwLock<P>>, client: Client) -> Self {
+    pub fn new(projection: Arc<RwLock<P>>, store: Arc<dyn EventStore>) -> Self {
         Projector {
             projection: projection,
-            client: client,
-            latest_revision: Arc::new(RwLock::new(0)),
+            store: store,
+            revisions: BTreeMap::new(),
+            checkpoint_stream: None,
+            restore: false,
+            rebuild: false,
+            config: ProjectionConfig::default(),
+        }
+    }
+    /// Sets how events which fail to be processed are retried and whether
+    /// those are skipped eventually.
+    pub fn with_config(self, config: ProjectionConfig) -> Self {
+        Projector {
+            config: config,
+            ..self
+        }
+    }
+    /// Periodically persists the latest processed revision, as configured by
+    /// `ProjectionConfig`, and resumes from it on startup instead of
+    /// processing the full stream again.
+    pub fn with_checkpoint(self, name: &str) -> Self {
+        Projector {
+            checkpoint_stream: Some(format!("{}_checkpoints", name)),
+            ..self
+        }
+    }
+    /// Passes the events up to the checkpoint to `Projection::restore` on
+    /// startup, for projections which keep in-memory state.
+    pub fn restore_state(self) -> Self {
+        Projector {
+            restore: true,
+            ..self
+        }
+    }
+    /// Only processes the events of the stream *after* the given revision.
+    /// Used by projections whose state was already restored up to that
+    /// revision.
+    pub fn start_after<Id: AsRef<str>>(mut self, id: Id, revision: Option<u64>) -> Self {
+        match revision {
+            Some(revision) => self.revisions.insert(id.as_ref().to_string(), revision),
+            None => self.revisions.remove(id.as_ref()),
+        };
+
+        self
+    }
+    /// Ignores the persisted checkpoint and processes the full stream from
+    /// the very first event, if `rebuild` is `true`.
+    pub fn rebuild(self, rebuild: bool) -> Self {
+        Projector {
+            rebuild: rebuild,
+            ..self
+        }
+    }
+    async fn load_checkpoint(&mut self, selection: &Selection) -> Result<()> {
+        let stream = match &self.checkpoint_stream {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        if self.rebuild {
+            info!("Ignoring checkpoint on stream '{}', rebuilding", stream);
+            self.revisions.clear();
+            return Ok(());
+        }
+
+        if let Some(recorded) = self
+            .store
+            .read_from_end(stream, 1)
+            .await?
+            .into_iter()
+            .next()
+        {
+            let mut checkpoint = recorded
+                .as_json::<Checkpoint>()
+                .map_err(|err| anyhow!("failed to parse checkpoint: {:?}", err))?;
+
+            // Legacy checkpoints refer to the first (and only) stream.
+            if let (Some(revision), Some(stream)) =
+                (checkpoint.revision, selection.streams().first())
+            {
+                checkpoint.revisions.insert(stream.clone(), revision);
+            }
+
+            for (stream, revision) in &checkpoint.revisions {
+                info!(
+                    "Resuming projection of stream '{}' after revision {}",
+                    stream, revision
+                );
+            }
+
+            self.revisions = checkpoint.revisions;
         }
+
+        Ok(())
     }
-    pub async fn run_blocking(self) {
+    /// Restores the in-memory state of the projection from the events up to
+    /// the loaded checkpoint. The streams are replayed one after another.
+    async fn restore_state_until_checkpoint(&self, selection: &Selection) -> Result<()> {
+        for id in selection.streams() {
+            let until = match self.revisions.get(id) {
+                Some(revision) => *revision,
+                None => continue,
+            };
+
+            let mut stream =
+                self.store.read_from(id, 0).await.map_err(|err| {
+                    anyhow!("failed to open stream to restore projection: {:?}", err)
+                })?;
+
+            while let Some(recorded) = stream
+                .try_next()
+                .await
+                .map_err(|err| anyhow!("failed to retrieve event from the eventstore: {:?}", err))?
+            {
+                if recorded.revision > until {
+                    break;
+                }
+
+                if !selection.accepts(&recorded) {
+                    continue;
+                }
+
+                let (stream_id, revision) = (recorded.stream.clone(), recorded.revision);
+                let restored = match <P as Projection>::Event::try_from(recorded) {
+                    Ok(event) => (*self.projection.write().await)
+                        .restore(event)
+                        .map_err(|err| anyhow!("failed to restore projection: {:?}", err)),
+                    Err(_) => Err(anyhow!(
+                        "failed to convert eventstore event into native type"
+                    )),
+                };
+
+                // Events before the checkpoint which failed to be projected
+                // were skipped, so they are skipped here as well.
+                if let Err(err) = restored {
+                    if !self.config.skip_failed {
+                        return Err(err);
+                    }
+
+                    warn!(
+                        "Skipping event {} of stream '{}' while restoring the projection: {:?}",
+                        revision, stream_id, err
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+    pub async fn run_blocking(mut self) {
+        let selection = self.projection.read().await.selection();
+
+        if let Err(err) = self.load_checkpoint(&selection).await {
+            error!("Failed to load projection checkpoint: {:?}", err);
+            return;
+        }
+
+        if self.restore {
+            if let Err(err) = self.restore_state_until_checkpoint(&selection).await {
+                error!("Failed to restore projection state: {:?}", err);
+                return;
+            }
+        }
+
         let projection = self.projection;
-        let client = self.client;
-        let latest_revision = Arc::clone(&self.latest_revision);
+        let store = self.store;
+        let mut revisions = self.revisions;
+        let checkpoint_stream = self.checkpoint_stream;
+        let config = self.config;
 
-        let handle = tokio::spawn(async move {
+        // Not spawned as a separate task, so the projection stops once this
+        // future is dropped.
+        let result = async {
             loop {
-                let mut subscribe =
-                // TODO: Why uUse `default` here?
-                    client.subscribe_to_stream_from(<P as Projection>::Id::default());
-
-                // Don't skip the very first event (event `0`).
-                if *latest_revision.read().await > 0 {
-                    subscribe = subscribe.start_position(*latest_revision.read().await);
+                // Subscribe to each stream, continuing after the last
+                // processed event on reconnect.
+                let mut subscriptions = vec![];
+                for id in selection.streams() {
+                    subscriptions.push(
+                        store
+                            .subscribe_from(id, revisions.get(id).copied())
+                            .await
+                            .map_err(|err| {
+                                anyhow!("failed to open stream '{}' to projection: {:?}", id, err)
+                            })?,
+                    );
                 }
 
-                // Create stream.
-                let mut stream = subscribe
-                    .execute_event_appeared_only()
-                    .await
-                    .map_err(|err| anyhow!("failed to open stream to projection: {:?}", err))?;
+                let mut stream = stream::select_all(subscriptions);
+                let checkpoint_interval = Duration::from_millis(config.checkpoint_interval);
+                let mut unsaved = 0;
+                let mut last_checkpoint = Instant::now();
 
                 // Run the projector on each received event.
-                while let Ok(event) = stream.try_next().await {
-                    match event {
-                        Some(resolved) => {
-                            if let Some(recorded) = resolved.event {
-                                *latest_revision.write().await = recorded.revision;
-
-                                // Parse event.
-                                let event =
-                                    <P as Projection>::Event::try_from(recorded).map_err(|_| {
-                                        anyhow!(
-                                            "failed to convert eventstore event into native type"
-                                        )
-                                    })?;
-
-                                // Project event.
-                                (*projection.write().await).project(event).await.map_err(
-                                    |err| anyhow!("failed to run projection: {:?}", err),
-                                )?;
-                            } else {
-                                warn!("Did not receive a recorded event");
+                loop {
+                    let recorded = match time::timeout(checkpoint_interval, stream.try_next()).await
+                    {
+                        Ok(Ok(Some(recorded))) => recorded,
+                        Ok(_) => break,
+                        // No new events, persist the progress so far.
+                        Err(_) => {
+                            if unsaved > 0 {
+                                persist_checkpoint(&store, &checkpoint_stream, &revisions).await?;
+                                unsaved = 0;
+                                last_checkpoint = Instant::now();
+                            }
+
+                            continue;
+                        }
+                    };
+
+                    let revision = recorded.revision;
+
+                    let projected = if selection.accepts(&recorded) {
+                        project_with_retries(&projection, &config, &recorded).await
+                    } else {
+                        Ok(())
+                    };
+
+                    if let Err(err) = projected {
+                        error!(
+                            "Failed to project event {} of stream '{}': {:?}",
+                            revision, recorded.stream, err
+                        );
+
+                        // Record the event in the dead letter stream. A restarted
+                        // projection fails at the same event again, which is
+                        // only recorded once.
+                        if has_dead_letter(&store, &selection, &recorded).await? {
+                            debug!(
+                                "Dead letter for event {} of stream '{}' already recorded",
+                                revision, recorded.stream
+                            );
+                        } else {
+                            store
+                                .append(
+                                    DEAD_LETTER_STREAM,
+                                    ExpectedRevision::Any,
+                                    vec![EventData::json(
+                                        "registrar-dead-letter",
+                                        DeadLetter {
+                                            projection: selection.to_string(),
+                                            stream: recorded.stream.clone(),
+                                            revision: revision,
+                                            event_type: recorded.event_type.clone(),
+                                            data: recorded.data.clone(),
+                                            error: format!("{:?}", err),
+                                            skipped: config.skip_failed,
+                                        },
+                                    )?],
+                                )
+                                .await?
+                                .map_err(|err| anyhow!("failed to write dead letter: {:?}", err))?;
+                        }
+
+                        if !config.skip_failed {
+                            if unsaved > 0 {
+                                persist_checkpoint(&store, &checkpoint_stream, &revisions).await?;
                             }
+
+                            return Err(err);
                         }
-                        _ => {}
+
+                        warn!(
+                            "Skipping event {} of stream '{}'",
+                            revision, recorded.stream
+                        );
+                    }
+
+                    revisions.insert(recorded.stream, revision);
+                    unsaved += 1;
+
+                    if unsaved >= config.checkpoint_every
+                        || last_checkpoint.elapsed() >= checkpoint_interval
+                    {
+                        persist_checkpoint(&store, &checkpoint_stream, &revisions).await?;
+                        unsaved = 0;
+                        last_checkpoint = Instant::now();
                     }
                 }
 
+                if unsaved > 0 {
+                    persist_checkpoint(&store, &checkpoint_stream, &revisions).await?;
+                }
+
                 warn!("Projection stream disconnected, reconnecting...");
             }
 
             // For type inference.
             #[allow(dead_code)]
             Result::Ok(())
-        });
+        }
+        .await;
+
+        if let Err(err) = result {
+            error!("Projection failed: {:?}", err);
+        }
 
-        let _ = handle.await.unwrap();
         error!(
-            "Projection for stream '{}' has exited unexpectedly",
-            <P as Projection>::Id::default().as_ref()
+            "Projection for stream(s) '{}' has exited unexpectedly",
+            selection
         );
     }
+    /// Runs the projection on every event currently in the selected streams,
+    /// starting from the very first event, and returns once the end of the
+    /// streams has been reached. The streams are replayed one after another.
+    /// Unlike `run_blocking`, this does not wait for new events. Events which
+    /// cannot be converted or projected are logged and skipped.
+    pub async fn replay(self) -> Result<()>
+    where
+        <<P as Projection>::Event as TryFrom<RecordedEvent>>::Error: Debug,
+    {
+        let selection = self.projection.read().await.selection();
+
+        // Empty streams are most likely a typo by the operator.
+        let mut found = false;
+        for id in selection.streams() {
+            found |= !self.store.read_from_end(id, 1).await?.is_empty();
+        }
+
+        if !found {
+            return Err(anyhow!("stream(s) '{}' not found", selection));
+        }
+
+        let mut skipped = 0;
+        for id in selection.streams() {
+            let mut stream =
+                self.store.read_from(id, 0).await.map_err(|err| {
+                    anyhow!("failed to open stream to replay projection: {:?}", err)
+                })?;
+
+            while let Some(recorded) = stream
+                .try_next()
+                .await
+                .map_err(|err| anyhow!("failed to retrieve event from the eventstore: {:?}", err))?
+            {
+                if !selection.accepts(&recorded) {
+                    continue;
+                }
+
+                let (stream_id, revision) = (recorded.stream.clone(), recorded.revision);
+                let projected = match <P as Projection>::Event::try_from(recorded) {
+                    Ok(event) => (*self.projection.write().await)
+                        .project(event)
+                        .await
+                        .map_err(|err| anyhow!("failed to run projection: {:?}", err)),
+                    Err(err) => Err(anyhow!(
+                        "failed to convert eventstore event into native type: {:?}",
+                        err
+                    )),
+                };
+
+                if let Err(err) = projected {
+                    error!(
+                        "Skipping event {} of stream '{}' while replaying: {:?}",
+                        revision, stream_id, err
+                    );
+                    skipped += 1;
+                }
+            }
+        }
+
+        if skipped > 0 {
+            warn!("Skipped {} event(s) which could not be replayed", skipped);
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a dead letter was already recorded for the event by the projection.
+async fn has_dead_letter(
+    store: &Arc<dyn EventStore>,
+    selection: &Selection,
+    recorded: &RecordedEvent,
+) -> Result<bool> {
+    let projection = selection.to_string();
+
+    Ok(store
+        .read_from_end(DEAD_LETTER_STREAM, DEAD_LETTER_LOOKBACK)
+        .await?
+        .into_iter()
+        .filter_map(|dead_letter| dead_letter.as_json::<DeadLetter>().ok())
+        .any(|dead_letter| {
+            dead_letter.projection == projection
+                && dead_letter.stream == recorded.stream
+                && dead_letter.revision == recorded.revision
+        }))
+}
+
+/// Persists the latest processed revision of each stream, if checkpoints are
+/// enabled.
+async fn persist_checkpoint(
+    store: &Arc<dyn EventStore>,
+    checkpoint_stream: &Option<String>,
+    revisions: &BTreeMap<String, u64>,
+) -> Result<()> {
+    let checkpoint_stream = match checkpoint_stream {
+        Some(checkpoint_stream) => checkpoint_stream,
+        None => return Ok(()),
+    };
+
+    // Only the latest checkpoint is kept.
+    store
+        .replace(
+            checkpoint_stream,
+            EventData::json(
+                "registrar-checkpoint",
+                Checkpoint {
+                    revisions: revisions.clone(),
+                    revision: None,
+                },
+            )?,
+        )
+        .await
+        .map(|_| ())
+        .map_err(|err| anyhow!("failed to persist checkpoint: {:?}", err))
+}
+
+/// Projects the event, retrying with an exponential backoff on failure. Events
+/// which cannot be parsed are not retried.
+async fn project_with_retries<P>(
+    projection: &RwLock<P>,
+    config: &ProjectionConfig,
+    recorded: &RecordedEvent,
+) -> Result<()>
+where
+    P: Projection,
+    <P as Projection>::Event: TryFrom<RecordedEvent>,
+    <P as Projection>::Error: Debug,
+{
+    let mut backoff = config.initial_backoff;
+    let mut attempt = 0;
+
+    loop {
+        // Parse event.
+        let event = <P as Projection>::Event::try_from(recorded.clone())
+            .map_err(|_| anyhow!("failed to convert eventstore event into native type"))?;
+
+        // Project event.
+        match (*projection.write().await).project(event).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < config.max_retries => {
+                attempt += 1;
+                warn!(
+                    "Failed to run projection, retrying in {} ms ({}/{}): {:?}",
+                    backoff, attempt, config.max_retries, err
+                );
+
+                time::sleep(Duration::from_millis(backoff)).await;
+                backoff = (backoff * 2).min(config.max_backoff);
+            }
+            Err(err) => return Err(anyhow!("failed to run projection: {:?}", err)),
+        }
+    }
 }
@@ -0,0 +1,75 @@
+use crate::event::JudgementKind;
+use crate::manager::{FieldStatus, NetworkAddress};
+use crate::{PolicyConfig, PolicyRule};
+use std::collections::HashMap;
+
+/// Decides when a judgement request is closed without successful
+/// verification, based on the failed verification attempts of each identity.
+pub struct JudgementPolicy {
+    config: PolicyConfig,
+    invalid_challenges: HashMap<NetworkAddress, usize>,
+    remark_mismatches: HashMap<NetworkAddress, usize>,
+}
+
+impl JudgementPolicy {
+    pub fn new(config: PolicyConfig) -> Self {
+        JudgementPolicy {
+            config: config,
+            invalid_challenges: HashMap::new(),
+            remark_mismatches: HashMap::new(),
+        }
+    }
+    /// Records the outcome of a verification attempt of a field. Returns the
+    /// judgement if the request must be closed.
+    pub fn field_verified(
+        &mut self,
+        net_address: &NetworkAddress,
+        field_status: &FieldStatus,
+    ) -> Option<JudgementKind> {
+        if field_status.is_display_name_violation() {
+            if let Some(judgement) = self.config.display_name_violation {
+                return Some(judgement);
+            }
+        }
+
+        if !field_status.is_invalid() {
+            return None;
+        }
+
+        Self::count(
+            &mut self.invalid_challenges,
+            &self.config.invalid_challenges,
+            net_address,
+        )
+    }
+    /// Records a remark which does not match the on-chain challenge. Returns
+    /// the judgement if the request must be closed.
+    pub fn remark_mismatch(&mut self, net_address: &NetworkAddress) -> Option<JudgementKind> {
+        Self::count(
+            &mut self.remark_mismatches,
+            &self.config.remark_mismatches,
+            net_address,
+        )
+    }
+    /// Forgets the failed attempts, once the judgement request is closed.
+    pub fn reset(&mut self, net_address: &NetworkAddress) {
+        self.invalid_challenges.remove(net_address);
+        self.remark_mismatches.remove(net_address);
+    }
+    fn count(
+        counters: &mut HashMap<NetworkAddress, usize>,
+        rule: &Option<PolicyRule>,
+        net_address: &NetworkAddress,
+    ) -> Option<JudgementKind> {
+        let rule = rule.as_ref()?;
+
+        let counter = counters.entry(net_address.clone()).or_insert(0);
+        *counter += 1;
+
+        if *counter >= rule.limit {
+            Some(rule.judgement)
+        } else {
+            None
+        }
+    }
+}
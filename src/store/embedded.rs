@@ -0,0 +1,214 @@
+use super::{
+    catch_up_subscription, EventData, EventStore, EventStream, ExpectedRevision, RecordedEvent,
+    WrongExpectedRevision,
+};
+use crate::Result;
+use futures::stream::StreamExt;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// How many events a live subscriber can fall behind before it must
+/// resubscribe.
+const LIVE_CAPACITY: usize = 1_000;
+
+/// The serialized format of an event in the embedded database.
+#[derive(Serialize, Deserialize)]
+struct StoredEvent {
+    event_type: String,
+    data: Value,
+}
+
+/// File-backed event store, running in the same process as the service.
+/// Each event stream is stored in a separate tree of the database, keyed by
+/// the (big-endian) event revision.
+#[derive(Clone)]
+pub struct EmbeddedStore {
+    db: sled::Db,
+    // Serializes appends, so revisions are assigned without gaps.
+    write_lock: Arc<tokio::sync::Mutex<()>>,
+    live: Arc<Mutex<HashMap<String, broadcast::Sender<RecordedEvent>>>>,
+}
+
+impl EmbeddedStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = sled::open(path.as_ref()).map_err(|err| {
+            anyhow!(
+                "failed to open embedded event store at '{}': {:?}",
+                path.as_ref().display(),
+                err
+            )
+        })?;
+
+        Ok(EmbeddedStore {
+            db: db,
+            write_lock: Default::default(),
+            live: Default::default(),
+        })
+    }
+    fn sender(&self, stream: &str) -> broadcast::Sender<RecordedEvent> {
+        self.live
+            .lock()
+            .entry(stream.to_string())
+            .or_insert_with(|| broadcast::channel(LIVE_CAPACITY).0)
+            .clone()
+    }
+    fn decode(stream: &str, key: &[u8], value: &[u8]) -> Result<RecordedEvent> {
+        let revision = u64::from_be_bytes(
+            key.try_into()
+                .map_err(|_| anyhow!("invalid revision key in stream '{}'", stream))?,
+        );
+
+        let stored: StoredEvent = serde_json::from_slice(value).map_err(|err| {
+            anyhow!(
+                "failed to deserialize event {} of stream '{}': {:?}",
+                revision,
+                stream,
+                err
+            )
+        })?;
+
+        Ok(RecordedEvent {
+            stream: stream.to_string(),
+            revision: revision,
+            event_type: stored.event_type,
+            data: stored.data,
+        })
+    }
+    fn read_range(&self, stream: &str, from: u64) -> Result<Vec<RecordedEvent>> {
+        let tree = self.db.open_tree(stream)?;
+        tree.range(from.to_be_bytes()..)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Self::decode(stream, &key, &value)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl EventStore for EmbeddedStore {
+    async fn append(
+        &self,
+        stream: &str,
+        expected: ExpectedRevision,
+        events: Vec<EventData>,
+    ) -> Result<std::result::Result<u64, WrongExpectedRevision>> {
+        if events.is_empty() {
+            return Err(anyhow!("no events to append to stream '{}'", stream));
+        }
+
+        let _lock = self.write_lock.lock().await;
+
+        let tree = self.db.open_tree(stream)?;
+        let current = match tree.last()? {
+            Some((key, value)) => Some(Self::decode(stream, &key, &value)?.revision),
+            None => None,
+        };
+
+        if !expected.matches(current) {
+            return Ok(Err(WrongExpectedRevision {
+                stream: stream.to_string(),
+                expected: expected,
+                current: current,
+            }));
+        }
+
+        let mut revision = current.map(|r| r + 1).unwrap_or(0);
+
+        let mut batch = sled::Batch::default();
+        let mut recorded = vec![];
+        for event in events {
+            batch.insert(
+                revision.to_be_bytes().to_vec(),
+                serde_json::to_vec(&StoredEvent {
+                    event_type: event.event_type.clone(),
+                    data: event.data.clone(),
+                })?,
+            );
+
+            recorded.push(RecordedEvent {
+                stream: stream.to_string(),
+                revision: revision,
+                event_type: event.event_type,
+                data: event.data,
+            });
+
+            revision += 1;
+        }
+
+        tree.apply_batch(batch)?;
+        tree.flush_async().await?;
+
+        // Notify live subscribers. An error only means that there are no
+        // subscribers.
+        let sender = self.sender(stream);
+        let last = revision - 1;
+        for event in recorded {
+            let _ = sender.send(event);
+        }
+
+        Ok(Ok(last))
+    }
+    async fn replace(&self, stream: &str, event: EventData) -> Result<u64> {
+        let _lock = self.write_lock.lock().await;
+
+        let tree = self.db.open_tree(stream)?;
+        let revision = match tree.last()? {
+            Some((key, value)) => Self::decode(stream, &key, &value)?.revision + 1,
+            None => 0,
+        };
+
+        // Only the written event remains in the tree.
+        let mut batch = sled::Batch::default();
+        for key in tree.iter().keys() {
+            batch.remove(key?);
+        }
+        batch.insert(
+            revision.to_be_bytes().to_vec(),
+            serde_json::to_vec(&StoredEvent {
+                event_type: event.event_type.clone(),
+                data: event.data.clone(),
+            })?,
+        );
+
+        tree.apply_batch(batch)?;
+        tree.flush_async().await?;
+
+        // An error only means that there are no subscribers.
+        let _ = self.sender(stream).send(RecordedEvent {
+            stream: stream.to_string(),
+            revision: revision,
+            event_type: event.event_type,
+            data: event.data,
+        });
+
+        Ok(revision)
+    }
+    async fn read_from_end(&self, stream: &str, count: usize) -> Result<Vec<RecordedEvent>> {
+        let tree = self.db.open_tree(stream)?;
+        tree.iter()
+            .rev()
+            .take(count)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Self::decode(stream, &key, &value)
+            })
+            .collect()
+    }
+    async fn read_from(&self, stream: &str, revision: u64) -> Result<EventStream> {
+        let events = self.read_range(stream, revision)?;
+        Ok(futures::stream::iter(events.into_iter().map(Ok)).boxed())
+    }
+    async fn subscribe_from(&self, stream: &str, revision: Option<u64>) -> Result<EventStream> {
+        // Subscribe to live events *before* reading the existing ones.
+        let live = self.sender(stream).subscribe();
+        let existing = self.read_range(stream, revision.map(|r| r + 1).unwrap_or(0))?;
+
+        Ok(catch_up_subscription(existing, live, revision))
+    }
+}
@@ -0,0 +1,174 @@
+use super::{
+    EventData, EventStore, EventStream, ExpectedRevision, RecordedEvent, WrongExpectedRevision,
+};
+use crate::Result;
+use eventstore::{Client, CurrentRevision, ExpectedVersion, ReadResult, ResolvedEvent};
+use futures::{StreamExt, TryStreamExt};
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Event store backed by an EventStoreDB server (or cluster).
+#[derive(Clone)]
+pub struct EventStoreDb {
+    client: Client,
+    // The streams whose `$maxCount` was set by this instance.
+    bounded: Arc<Mutex<HashSet<String>>>,
+}
+
+impl EventStoreDb {
+    pub fn new(client: Client) -> Self {
+        EventStoreDb {
+            client: client,
+            bounded: Default::default(),
+        }
+    }
+    /// Sets the `$maxCount` of the stream to one, so the server only keeps the
+    /// latest event. The metadata of a stream is written to its `$$` stream.
+    async fn bound(&self, stream: &str) -> Result<()> {
+        if self.bounded.lock().contains(stream) {
+            return Ok(());
+        }
+
+        let metadata =
+            eventstore::EventData::json("$metadata", serde_json::json!({ "$maxCount": 1 }))
+                .map_err(|err| anyhow!("failed to serialize stream metadata: {:?}", err))?;
+
+        self.client
+            .write_events(format!("$${}", stream))
+            .expected_version(ExpectedVersion::Any)
+            .send_iter(vec![metadata])
+            .await
+            .map_err(|err| anyhow!("failed to send events to the eventstore: {:?}", err))?
+            .map_err(|err| {
+                anyhow!(
+                    "failed to set the metadata of stream '{}': {:?}",
+                    stream,
+                    err
+                )
+            })?;
+
+        self.bounded.lock().insert(stream.to_string());
+
+        Ok(())
+    }
+}
+
+fn convert(resolved: ResolvedEvent) -> Option<Result<RecordedEvent>> {
+    resolved.event.map(|recorded| {
+        Ok(RecordedEvent {
+            data: recorded.as_json().map_err(|err| {
+                anyhow!(
+                    "failed to deserialize event {} of stream '{}': {:?}",
+                    recorded.revision,
+                    recorded.stream_id,
+                    err
+                )
+            })?,
+            stream: recorded.stream_id,
+            revision: recorded.revision,
+            event_type: recorded.event_type,
+        })
+    })
+}
+
+#[async_trait]
+impl EventStore for EventStoreDb {
+    async fn append(
+        &self,
+        stream: &str,
+        expected: ExpectedRevision,
+        events: Vec<EventData>,
+    ) -> Result<std::result::Result<u64, WrongExpectedRevision>> {
+        let events = events
+            .into_iter()
+            .map(|event| {
+                eventstore::EventData::json(event.event_type, event.data)
+                    .map_err(|err| anyhow!("failed to serialize event: {:?}", err))
+            })
+            .collect::<Result<Vec<eventstore::EventData>>>()?;
+
+        let expected_version = match expected {
+            ExpectedRevision::Any => ExpectedVersion::Any,
+            ExpectedRevision::NoStream => ExpectedVersion::NoStream,
+            ExpectedRevision::Exact(revision) => ExpectedVersion::Exact(revision),
+        };
+
+        let result = self
+            .client
+            .write_events(stream.to_string())
+            .expected_version(expected_version)
+            .send_iter(events)
+            .await
+            .map_err(|err| anyhow!("failed to send events to the eventstore: {:?}", err))?;
+
+        // The "next expected version" is the revision of the last written
+        // event.
+        Ok(result
+            .map(|written| written.next_expected_version)
+            .map_err(|err| WrongExpectedRevision {
+                stream: stream.to_string(),
+                expected: expected,
+                current: match err.current {
+                    CurrentRevision::Current(revision) => Some(revision),
+                    CurrentRevision::NoStream => None,
+                },
+            }))
+    }
+    async fn replace(&self, stream: &str, event: EventData) -> Result<u64> {
+        self.bound(stream).await?;
+
+        self.append(stream, ExpectedRevision::Any, vec![event])
+            .await?
+            .map_err(|err| anyhow!("failed to append to stream '{}': {:?}", stream, err))
+    }
+    async fn read_from_end(&self, stream: &str, count: usize) -> Result<Vec<RecordedEvent>> {
+        match self
+            .client
+            .read_stream(stream.to_string())
+            .start_from_end_of_stream()
+            .execute(count as u64)
+            .await
+            .map_err(|err| anyhow!("failed to open stream '{}': {:?}", stream, err))?
+        {
+            ReadResult::Ok(events) => {
+                events
+                    .map_err(|err| anyhow!("failed to read from the eventstore: {:?}", err))
+                    .try_filter_map(|resolved| async move { convert(resolved).transpose() })
+                    .try_collect()
+                    .await
+            }
+            ReadResult::StreamNotFound(_) => Ok(vec![]),
+        }
+    }
+    async fn read_from(&self, stream: &str, revision: u64) -> Result<EventStream> {
+        match self
+            .client
+            .read_stream(stream.to_string())
+            .start_position(revision)
+            .read_through()
+            .await
+            .map_err(|err| anyhow!("failed to open stream '{}': {:?}", stream, err))?
+        {
+            ReadResult::Ok(events) => Ok(events
+                .map_err(|err| anyhow!("failed to read from the eventstore: {:?}", err))
+                .try_filter_map(|resolved| async move { convert(resolved).transpose() })
+                .boxed()),
+            ReadResult::StreamNotFound(_) => Ok(futures::stream::empty().boxed()),
+        }
+    }
+    async fn subscribe_from(&self, stream: &str, revision: Option<u64>) -> Result<EventStream> {
+        let mut subscribe = self.client.subscribe_to_stream_from(stream.to_string());
+        if let Some(revision) = revision {
+            subscribe = subscribe.start_position(revision);
+        }
+
+        Ok(subscribe
+            .execute_event_appeared_only()
+            .await
+            .map_err(|err| anyhow!("failed to subscribe to stream '{}': {:?}", stream, err))?
+            .map_err(|err| anyhow!("failed to receive event from the eventstore: {:?}", err))
+            .try_filter_map(|resolved| async move { convert(resolved).transpose() })
+            .boxed())
+    }
+}
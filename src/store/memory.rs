@@ -0,0 +1,138 @@
+use super::{
+    catch_up_subscription, EventData, EventStore, EventStream, ExpectedRevision, RecordedEvent,
+    WrongExpectedRevision,
+};
+use crate::Result;
+use futures::stream::StreamExt;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// How many events a live subscriber can fall behind before it must
+/// resubscribe.
+const LIVE_CAPACITY: usize = 1_000;
+
+#[derive(Default)]
+struct MemoryStream {
+    // Ordered by revision, which has gaps once events were discarded.
+    events: Vec<RecordedEvent>,
+    live: Option<broadcast::Sender<RecordedEvent>>,
+}
+
+impl MemoryStream {
+    fn current(&self) -> Option<u64> {
+        self.events.last().map(|event| event.revision)
+    }
+    /// Records the event at the next revision and notifies the subscribers.
+    fn push(&mut self, stream: &str, event: EventData) -> u64 {
+        let recorded = RecordedEvent {
+            stream: stream.to_string(),
+            revision: self.current().map(|r| r + 1).unwrap_or(0),
+            event_type: event.event_type,
+            data: event.data,
+        };
+
+        let revision = recorded.revision;
+        self.events.push(recorded.clone());
+        // An error only means that there are no subscribers.
+        let _ = self.sender().send(recorded);
+
+        revision
+    }
+    fn since(&self, revision: u64) -> Vec<RecordedEvent> {
+        self.events
+            .iter()
+            .filter(|event| event.revision >= revision)
+            .cloned()
+            .collect()
+    }
+    fn sender(&mut self) -> &broadcast::Sender<RecordedEvent> {
+        self.live
+            .get_or_insert_with(|| broadcast::channel(LIVE_CAPACITY).0)
+    }
+}
+
+/// Event store which keeps all events in memory. Nothing is persisted, so
+/// this is primarily intended for tests.
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    streams: Arc<RwLock<HashMap<String, MemoryStream>>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EventStore for InMemoryStore {
+    async fn append(
+        &self,
+        stream: &str,
+        expected: ExpectedRevision,
+        events: Vec<EventData>,
+    ) -> Result<std::result::Result<u64, WrongExpectedRevision>> {
+        if events.is_empty() {
+            return Err(anyhow!("no events to append to stream '{}'", stream));
+        }
+
+        let mut streams = self.streams.write();
+        let entry = streams.entry(stream.to_string()).or_default();
+
+        let current = entry.current();
+        if !expected.matches(current) {
+            return Ok(Err(WrongExpectedRevision {
+                stream: stream.to_string(),
+                expected: expected,
+                current: current,
+            }));
+        }
+
+        let mut last = 0;
+        for event in events {
+            last = entry.push(stream, event);
+        }
+
+        Ok(Ok(last))
+    }
+    async fn replace(&self, stream: &str, event: EventData) -> Result<u64> {
+        let mut streams = self.streams.write();
+        let entry = streams.entry(stream.to_string()).or_default();
+
+        let revision = entry.push(stream, event);
+        entry.events.retain(|event| event.revision == revision);
+
+        Ok(revision)
+    }
+    async fn read_from_end(&self, stream: &str, count: usize) -> Result<Vec<RecordedEvent>> {
+        Ok(self
+            .streams
+            .read()
+            .get(stream)
+            .map(|entry| entry.events.iter().rev().take(count).cloned().collect())
+            .unwrap_or_default())
+    }
+    async fn read_from(&self, stream: &str, revision: u64) -> Result<EventStream> {
+        let events: Vec<RecordedEvent> = self
+            .streams
+            .read()
+            .get(stream)
+            .map(|entry| entry.since(revision))
+            .unwrap_or_default();
+
+        Ok(futures::stream::iter(events.into_iter().map(Ok)).boxed())
+    }
+    async fn subscribe_from(&self, stream: &str, revision: Option<u64>) -> Result<EventStream> {
+        // Both the existing events and the live receiver are retrieved while
+        // holding the lock, so no event can be missed.
+        let mut streams = self.streams.write();
+        let entry = streams.entry(stream.to_string()).or_default();
+
+        let live = entry.sender().subscribe();
+        let existing = entry.since(revision.map(|r| r + 1).unwrap_or(0));
+
+        Ok(catch_up_subscription(existing, live, revision))
+    }
+}
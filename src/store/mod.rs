@@ -0,0 +1,169 @@
+//! Storage backends for the event streams. The aggregates and projections
+//! only interact with the `EventStore` trait, so the backend can be chosen via
+//! the config: either an EventStoreDB server or an embedded, file-backed
+//! store which does not require any external service.
+
+use crate::Result;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+mod embedded;
+mod eventstore_db;
+#[cfg(test)]
+mod memory;
+
+pub use embedded::EmbeddedStore;
+pub use eventstore_db::EventStoreDb;
+#[cfg(test)]
+pub use memory::InMemoryStore;
+
+/// A (possibly infinite) stream of recorded events.
+pub type EventStream = BoxStream<'static, Result<RecordedEvent>>;
+
+/// An event which has not been written to a stream yet.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EventData {
+    pub event_type: String,
+    pub data: Value,
+}
+
+impl EventData {
+    pub fn json<T: Serialize>(event_type: &str, data: T) -> Result<Self> {
+        Ok(EventData {
+            event_type: event_type.to_string(),
+            data: serde_json::to_value(data)?,
+        })
+    }
+}
+
+/// An event which was written to a stream at the given revision.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordedEvent {
+    pub stream: String,
+    pub revision: u64,
+    pub event_type: String,
+    pub data: Value,
+}
+
+impl RecordedEvent {
+    pub fn as_json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_value(self.data.clone()).map_err(|err| err.into())
+    }
+}
+
+/// The revision a stream is expected to be at when appending events.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExpectedRevision {
+    /// Append regardless of the current revision.
+    Any,
+    /// The stream must not exist yet.
+    NoStream,
+    /// The latest event of the stream must have the given revision.
+    Exact(u64),
+}
+
+impl ExpectedRevision {
+    /// Returns the expected revision for a stream whose latest known event
+    /// has the given revision, if any.
+    pub fn from_latest(latest: Option<u64>) -> Self {
+        match latest {
+            Some(revision) => ExpectedRevision::Exact(revision),
+            None => ExpectedRevision::NoStream,
+        }
+    }
+    fn matches(&self, current: Option<u64>) -> bool {
+        match self {
+            ExpectedRevision::Any => true,
+            ExpectedRevision::NoStream => current.is_none(),
+            ExpectedRevision::Exact(revision) => current == Some(*revision),
+        }
+    }
+}
+
+/// The stream was modified concurrently, the events were not appended.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+#[error("wrong expected revision on stream '{stream}': expected {expected:?}, current {current:?}")]
+pub struct WrongExpectedRevision {
+    pub stream: String,
+    pub expected: ExpectedRevision,
+    /// The revision of the latest event, or `None` if the stream is empty.
+    pub current: Option<u64>,
+}
+
+#[async_trait]
+pub trait EventStore: Send + Sync {
+    /// Appends the events to the stream and returns the revision of the last
+    /// written event. The stream is created if it does not exist yet. If the
+    /// stream is not at the expected revision, nothing is written and
+    /// `WrongExpectedRevision` is returned.
+    async fn append(
+        &self,
+        stream: &str,
+        expected: ExpectedRevision,
+        events: Vec<EventData>,
+    ) -> Result<std::result::Result<u64, WrongExpectedRevision>>;
+    /// Appends the event to a stream of which only the latest event is read,
+    /// e.g. checkpoints, and discards the previous events, so the stream does
+    /// not grow indefinitely. Revisions keep counting up. Returns the revision
+    /// of the written event.
+    async fn replace(&self, stream: &str, event: EventData) -> Result<u64>;
+    /// Reads up to `count` events from the end of the stream, the latest
+    /// event first. Returns an empty list if the stream does not exist.
+    async fn read_from_end(&self, stream: &str, count: usize) -> Result<Vec<RecordedEvent>>;
+    /// Reads all events from the stream starting at (and including) the
+    /// given revision. The returned stream ends once the end of the event
+    /// stream has been reached.
+    async fn read_from(&self, stream: &str, revision: u64) -> Result<EventStream>;
+    /// Subscribes to the stream, starting *after* the given revision or from
+    /// the very beginning if no revision is specified. The returned stream
+    /// first delivers the existing events and then waits for new ones.
+    async fn subscribe_from(&self, stream: &str, revision: Option<u64>) -> Result<EventStream>;
+}
+
+/// Combines the already existing events with the events received on the
+/// live channel, skipping duplicates. The live receiver must be created
+/// *before* the existing events are read, otherwise events written in between
+/// could be missed.
+fn catch_up_subscription(
+    existing: Vec<RecordedEvent>,
+    live: broadcast::Receiver<RecordedEvent>,
+    revision: Option<u64>,
+) -> EventStream {
+    let last_seen = existing.last().map(|event| event.revision).or(revision);
+
+    let live = stream::unfold((live, last_seen), |(mut live, last_seen)| async move {
+        loop {
+            match live.recv().await {
+                Ok(event) => {
+                    if last_seen
+                        .map(|seen| event.revision <= seen)
+                        .unwrap_or(false)
+                    {
+                        continue;
+                    }
+
+                    let last_seen = Some(event.revision);
+                    return Some((Ok(event), (live, last_seen)));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    // The subscriber must reconnect in order to catch up.
+                    return Some((
+                        Err(anyhow!(
+                            "subscription lagged behind by {} events, resubscribe required",
+                            skipped
+                        )),
+                        (live, last_seen),
+                    ));
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    stream::iter(existing.into_iter().map(Ok))
+        .chain(live)
+        .boxed()
+}
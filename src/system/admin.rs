@@ -0,0 +1,85 @@
+//! Operator tooling which runs against the configured event store, such as
+//! replaying streams or inspecting the current identity state.
+
+use crate::aggregate::message_watcher::MessageWatcherId;
+use crate::aggregate::remark::RemarkWatcherId;
+use crate::aggregate::verifier::{
+    VerifierAggregate, VerifierAggregateId, VerifierAggregateSnapshotsId,
+};
+use crate::aggregate::Repository;
+use crate::chain::Networks;
+use crate::event::{BlankNetwork, Event};
+use crate::projection::{Projection, Projector, Selection};
+use crate::store::EventStore;
+use crate::{NetworkConfig, Result};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Prints each projected event as JSON, one event per line.
+struct EventPrinter {
+    stream: String,
+}
+
+#[async_trait]
+impl Projection for EventPrinter {
+    type Event = Event;
+    type Error = anyhow::Error;
+
+    fn selection(&self) -> Selection {
+        Selection::stream(&self.stream)
+    }
+
+    async fn project(&mut self, event: Self::Event) -> Result<()> {
+        println!("{}", serde_json::to_string(&event)?);
+        Ok(())
+    }
+}
+
+/// Prints every event of the specified stream to stdout.
+pub async fn replay_stream(store: Arc<dyn EventStore>, stream: &str) -> Result<()> {
+    let known = [
+        VerifierAggregateId.as_ref(),
+        VerifierAggregateSnapshotsId.as_ref(),
+        MessageWatcherId.as_ref(),
+        RemarkWatcherId.as_ref(),
+    ];
+
+    if !known.contains(&stream) {
+        return Err(anyhow!(
+            "unknown stream '{}', expected one of: {}",
+            stream,
+            known.join(", ")
+        ));
+    }
+
+    let projection = Arc::new(RwLock::new(EventPrinter {
+        stream: stream.to_string(),
+    }));
+
+    Projector::new(projection, store).replay().await
+}
+
+/// Returns the identity state of all pending judgement requests as JSON.
+pub async fn export_state(store: Arc<dyn EventStore>) -> Result<String> {
+    let repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), store).await?;
+    Ok(serde_json::to_string_pretty(&repo.state().export_state())?)
+}
+
+/// Returns the full identity state of the specified address as JSON, if a
+/// judgement request is pending.
+pub async fn inspect_state(
+    store: Arc<dyn EventStore>,
+    networks: &[NetworkConfig],
+    network: &str,
+    address: &str,
+) -> Result<Option<String>> {
+    let net_address =
+        Networks::new(networks).parse_address(&BlankNetwork::from(network), address)?;
+
+    let repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), store).await?;
+    repo.state()
+        .lookup_full_state(&net_address)
+        .map(|state| serde_json::to_string_pretty(&state))
+        .transpose()
+        .map_err(|err| err.into())
+}
@@ -1,43 +1,625 @@
 use crate::adapters::matrix::MatrixClient;
 use crate::adapters::twitter::TwitterBuilder;
+use crate::aggregate::remark::RemarkWatcher;
 use crate::aggregate::verifier::{VerifierAggregate, VerifierAggregateId, VerifierCommand};
-use crate::aggregate::{
-    Aggregate, MessageWatcher, MessageWatcherCommand, MessageWatcherId, Repository,
+use crate::aggregate::{MessageWatcher, MessageWatcherCommand, Repository};
+use crate::api::{ChallengeRegenerator, ConnectionPool, PublicRpc, PublicRpcApi};
+use crate::api_v2::session::{AccountStateChange, WsAccountStatusServer, WsAccountStatusSession};
+use crate::chain::{
+    ChainWatcher, JudgementSigner, KeystoreSigner, Networks, RemarkScanner, SubxtClient,
+    WatcherSigner,
 };
-use crate::api::{ConnectionPool, PublicRpc, PublicRpcApi};
-use crate::api_v2::session::WsAccountStatusSession;
-use crate::event::{Event, EventType, ExternalMessage};
-use crate::projection::{Projector, SessionNotifier};
-use crate::{adapters::email::SmtpImapClientBuilder, manager::IdentityManager};
-use crate::{EmailConfig, MatrixConfig, Result, TwitterConfig};
+use crate::event::{ExternalMessage, TTL};
+use crate::projection::{JudgmentGiver, MessageVerifier, Projector, SessionNotifier};
+use crate::store::{EmbeddedStore, EventStore, EventStoreDb};
+use crate::{
+    adapters::email::SmtpImapClientBuilder,
+    manager::{AttemptLimits, IdentityManager},
+};
+use crate::{
+    Config, EmailConfig, EventStoreBackend, EventStoreConfig, JudgementConfig, MatrixConfig,
+    NetworkConfig, ProjectionConfig, Result, SignerConfig, TwitterConfig, VerifierConfig,
+};
+use actix::Actor;
 use actix_web::{web, App, Error as ActixError, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 use async_channel::Receiver;
 use eventstore::Client;
-use futures::join;
-use futures::stream::StreamExt;
+use futures::future::{join_all, BoxFuture};
+use futures::{Future, FutureExt};
+use jsonrpc_core::MetaIoHandler;
 use jsonrpc_pubsub::{PubSubHandler, Session};
 use jsonrpc_ws_server::{RequestContext, Server as WsServer, ServerBuilder};
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 
-pub async fn run_rest_api_server_blocking(addr: &str) -> Result<()> {
+mod admin;
+pub use admin::{export_state, inspect_state, replay_stream};
+
+/// The delay before a crashed service gets restarted.
+const RESTART_DELAY: u64 = 5;
+/// How many account state changes the account status server can fall behind.
+const ACCOUNT_CHANGES_CAPACITY: usize = 1_000;
+/// Seconds between the checks of the submitted judgements on-chain.
+const RECONCILE_INTERVAL: u64 = 60;
+
+/// Starts all services and keeps them running. A service which exits or
+/// crashes is logged and restarted after `RESTART_DELAY` seconds.
+pub async fn run_service_blocking(config: Config, rebuild_projections: bool) -> Result<()> {
+    let store = connect_event_store(&config.event_store).await?;
+
+    let mut services: Vec<BoxFuture<'static, ()>> = vec![];
+
+    // Start the account status server (actix). This server runs its own
+    // runtime, so it's placed in a separate thread. It receives the state
+    // changes from the session notifier.
+    let (account_changes, _) = broadcast::channel(ACCOUNT_CHANGES_CAPACITY);
+    let networks = Networks::new(&config.networks);
+    let addr = config.api.account_status_address.clone();
+    let t_changes = account_changes.clone();
+    let t_networks = networks.clone();
+    std::thread::spawn(move || loop {
+        if let Err(err) =
+            run_rest_api_server_blocking(&addr, t_networks.clone(), t_changes.subscribe())
+        {
+            error!("Account status server has failed: {:?}, restarting", err);
+        } else {
+            warn!("Account status server has exited, restarting");
+        }
+
+        std::thread::sleep(std::time::Duration::from_secs(RESTART_DELAY));
+    });
+
+    // Start the JSON-RPC API server and the session notifier, which keeps the
+    // subscribers informed about any state changes.
+    let pool = ConnectionPool::new(networks);
+    let manager = Arc::new(RwLock::new(IdentityManager::default()));
+    let regenerator = ChallengeRegenerator::new(
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone()).await?,
+        Duration::from_secs(config.api.regenerate_cooldown),
+    );
+    run_rpc_api_server(
+        pool.clone(),
+        config.api.rpc_port,
+        Arc::clone(&manager),
+        regenerator,
+    )?;
+
+    // A rebuild of the projections is only done on the first start, not
+    // when a service is restarted.
+    let rebuild_notifier = Arc::new(AtomicBool::new(rebuild_projections));
+    let rebuild_verifier = Arc::new(AtomicBool::new(rebuild_projections));
+
+    let t_store = store.clone();
+    let t_config = config.projections.session_notifier.clone();
+    services.push(
+        run_supervised("session notifier", move || {
+            // The state is restored (or rebuilt) by the projection.
+            *manager.write() = IdentityManager::default();
+            run_session_notifier(
+                pool.clone(),
+                account_changes.clone(),
+                t_store.clone(),
+                Arc::clone(&manager),
+                t_config.clone(),
+                rebuild_notifier.swap(false, Ordering::Relaxed),
+            )
+        })
+        .boxed(),
+    );
+
+    // Start the verifier, which verifies the messages received by the
+    // adapters.
+    let t_store = store.clone();
+    let t_config = config.projections.message_verifier.clone();
+    let t_limits = config.verifier.attempt_limits;
+    services.push(
+        run_supervised("message verifier", move || {
+            run_message_verifier(
+                t_store.clone(),
+                t_config.clone(),
+                t_limits,
+                rebuild_verifier.swap(false, Ordering::Relaxed),
+            )
+        })
+        .boxed(),
+    );
+
+    // Start the expiry of judgement requests which are not verified in time.
+    if config.verifier.identity_ttl > 0 {
+        let (config, store) = (config.verifier.clone(), store.clone());
+        services.push(
+            run_supervised("identity expiry", move || {
+                run_identity_expiry(store.clone(), config.clone())
+            })
+            .boxed(),
+        );
+    } else {
+        info!("Expiry of judgement requests is disabled");
+    }
+
+    // Start a chain watcher for each network, which inserts the identities
+    // of new judgement requests, a remark watcher, which detects the on-chain
+    // challenges, and a judgement giver, which submits the judgements of the
+    // verified identities. An unreachable network does not affect the others.
+    for network in config.networks {
+        let (judgements, t_config, store_giver, network_giver) = (
+            config.judgements.clone(),
+            config.projections.judgment_giver.clone(),
+            store.clone(),
+            network.clone(),
+        );
+        let rebuild_giver = Arc::new(AtomicBool::new(rebuild_projections));
+        services.push(
+            run_supervised("judgement giver", move || {
+                run_judgment_giver(
+                    store_giver.clone(),
+                    network_giver.clone(),
+                    judgements.clone(),
+                    t_config.clone(),
+                    rebuild_giver.swap(false, Ordering::Relaxed),
+                )
+            })
+            .boxed(),
+        );
+
+        let store_remarks = store.clone();
+        let network_remarks = network.clone();
+        services.push(
+            run_supervised("remark watcher", move || {
+                run_remark_watcher(store_remarks.clone(), network_remarks.clone())
+            })
+            .boxed(),
+        );
+
+        let (verifier, store) = (config.verifier.clone(), store.clone());
+        services.push(
+            run_supervised("chain watcher", move || {
+                run_chain_watcher(store.clone(), network.clone(), verifier.clone())
+            })
+            .boxed(),
+        );
+    }
+
+    // Start the enabled adapters.
+    let accounts = config.accounts;
+    if accounts.matrix.enabled {
+        let (config, store) = (accounts.matrix, store.clone());
+        services.push(
+            run_supervised("Matrix adapter", move || {
+                let (config, store) = (config.clone(), store.clone());
+                async move {
+                    let repo = Repository::new_with_snapshot_service(MessageWatcher, store).await?;
+                    run_matrix_listener_blocking(config, repo).await
+                }
+            })
+            .boxed(),
+        );
+    } else {
+        info!("Matrix adapter is disabled");
+    }
+
+    if accounts.email.enabled {
+        let (config, store) = (accounts.email, store.clone());
+        services.push(
+            run_supervised("email adapter", move || {
+                let (config, store) = (config.clone(), store.clone());
+                async move {
+                    let repo = Repository::new_with_snapshot_service(MessageWatcher, store).await?;
+                    run_email_listener_blocking(config, repo).await
+                }
+            })
+            .boxed(),
+        );
+    } else {
+        info!("Email adapter is disabled");
+    }
+
+    if accounts.twitter.enabled {
+        let (config, store) = (accounts.twitter, store.clone());
+        services.push(
+            run_supervised("Twitter adapter", move || {
+                let (config, store) = (config.clone(), store.clone());
+                async move {
+                    let repo = Repository::new_with_snapshot_service(MessageWatcher, store).await?;
+                    run_twitter_listener_blocking(config, repo).await
+                }
+            })
+            .boxed(),
+        );
+    } else {
+        info!("Twitter adapter is disabled");
+    }
+
+    // Services are restarted indefinitely, so this never returns.
+    join_all(services).await;
+
+    Ok(())
+}
+
+pub async fn connect_event_store(config: &EventStoreConfig) -> Result<Arc<dyn EventStore>> {
+    match config.backend {
+        EventStoreBackend::EventStoreDb => connect_event_store_db(config).await,
+        EventStoreBackend::Embedded => {
+            // Enforced by the config parser.
+            let path = config
+                .path
+                .as_ref()
+                .ok_or_else(|| anyhow!("no path specified for the embedded event store"))?;
+
+            info!("Opening embedded event store at '{}'", path);
+            Ok(Arc::new(EmbeddedStore::open(path)?))
+        }
+    }
+}
+
+async fn connect_event_store_db(config: &EventStoreConfig) -> Result<Arc<dyn EventStore>> {
+    info!(
+        "Connecting to the event store at {}",
+        config.display_hosts()
+    );
+    let client = Client::create(
+        config
+            .full_connection_string()
+            .parse()
+            .map_err(|err| anyhow!("invalid event store connection string: {:?}", err))?,
+    )
+    .await
+    .map_err(|err| anyhow!("failed to connect to the event store: {:?}", err))?;
+
+    let store = EventStoreDb::new(client);
+
+    // The client connects lazily, so make sure the event store is actually
+    // reachable (and the credentials are valid) before starting any services.
+    let probe = store.read_from_end(VerifierAggregateId.as_ref(), 1);
+
+    match time::timeout(Duration::from_secs(config.connection_timeout), probe).await {
+        Ok(Ok(_)) => {
+            info!("Connected to the event store");
+            Ok(Arc::new(store))
+        }
+        Ok(Err(err)) => Err(anyhow!(
+            "event store at {} is not usable: {:?}",
+            config.display_hosts(),
+            err
+        )),
+        Err(_) => Err(anyhow!(
+            "event store at {} is unreachable: no response within {} seconds",
+            config.display_hosts(),
+            config.connection_timeout
+        )),
+    }
+}
+
+/// Runs the service returned by `service` in a separate task. If the service
+/// exits, returns an error or panics, the incident is logged and the service
+/// is restarted.
+async fn run_supervised<F, Fut>(name: &'static str, service: F)
+where
+    F: Fn() -> Fut,
+    Fut: 'static + Send + Future<Output = Result<()>>,
+{
+    loop {
+        info!("Starting {}", name);
+
+        match tokio::spawn(service()).await {
+            Ok(Ok(())) => warn!("The {} has exited unexpectedly", name),
+            Ok(Err(err)) => error!("The {} has failed: {:?}", name, err),
+            Err(err) => error!("The {} has crashed: {:?}", name, err),
+        }
+
+        warn!("Restarting the {} in {} seconds", name, RESTART_DELAY);
+        time::sleep(Duration::from_secs(RESTART_DELAY)).await;
+    }
+}
+
+pub fn run_rest_api_server_blocking(
+    addr: &str,
+    networks: Networks,
+    changes: broadcast::Receiver<AccountStateChange>,
+) -> Result<()> {
     async fn account_status_server_route(
         req: HttpRequest,
         stream: web::Payload,
+        networks: web::Data<Networks>,
     ) -> std::result::Result<HttpResponse, ActixError> {
-        ws::start(WsAccountStatusSession::default(), &req, stream)
+        ws::start(
+            WsAccountStatusSession::new(networks.get_ref().clone()),
+            &req,
+            stream,
+        )
     }
 
-    let server = HttpServer::new(move || {
-        App::new().service(web::resource("/api/account_status").to(account_status_server_route))
-    })
-    .bind(addr)?;
+    let mut system = actix_web::rt::System::new("account_status_server");
+    system.block_on(async move {
+        // Handles the subscriptions of all sessions.
+        let server = WsAccountStatusServer::new(networks.clone()).start();
+        actix_web::rt::spawn(WsAccountStatusServer::forward_changes(server, changes));
+
+        HttpServer::new(move || {
+            App::new()
+                .data(networks.clone())
+                .service(web::resource("/api/account_status").to(account_status_server_route))
+                .service(web::resource("/healthcheck").to(|| async { HttpResponse::Ok().finish() }))
+        })
+        .bind(addr)?
+        .run()
+        .await
+    })?;
+
+    Ok(())
+}
+
+/// Starts the JSON-RPC API server and keeps it running in a separate thread.
+/// If the server exits, it is restarted after `RESTART_DELAY` seconds. The
+/// state is kept up to date by the session notifier (see
+/// `run_session_notifier`).
+pub fn run_rpc_api_server(
+    pool: ConnectionPool,
+    port: usize,
+    manager: Arc<RwLock<IdentityManager>>,
+    regenerator: ChallengeRegenerator,
+) -> Result<()> {
+    let server = start_rpc_api_server(
+        pool.clone(),
+        port,
+        Arc::clone(&manager),
+        regenerator.clone(),
+    )?;
+
+    std::thread::spawn(move || {
+        let mut server = Ok(server);
+        loop {
+            match server {
+                Ok(server) => match server.wait() {
+                    Ok(()) => warn!("JSON-RPC API server has exited, restarting"),
+                    Err(err) => error!("JSON-RPC API server has failed: {:?}, restarting", err),
+                },
+                Err(err) => error!("{:?}, retrying", err),
+            }
+
+            std::thread::sleep(std::time::Duration::from_secs(RESTART_DELAY));
+            server = start_rpc_api_server(
+                pool.clone(),
+                port,
+                Arc::clone(&manager),
+                regenerator.clone(),
+            );
+        }
+    });
 
-    server.run();
     Ok(())
 }
 
+fn start_rpc_api_server(
+    pool: ConnectionPool,
+    port: usize,
+    manager: Arc<RwLock<IdentityManager>>,
+    regenerator: ChallengeRegenerator,
+) -> Result<WsServer> {
+    let mut io = PubSubHandler::new(MetaIoHandler::default());
+    io.extend_with(PublicRpcApi::new(pool, manager, regenerator).to_delegate());
+
+    let server = ServerBuilder::with_meta_extractor(io, |context: &RequestContext| {
+        Arc::new(Session::new(context.sender()))
+    })
+    .start(&format!("0.0.0.0:{}", port).parse()?)
+    .map_err(|err| anyhow!("failed to start the JSON-RPC API server: {:?}", err))?;
+
+    info!("JSON-RPC API server listening on port {}", port);
+
+    Ok(server)
+}
+
+/// Projects the identity state changes into the shared state and notifies
+/// the API subscribers about those changes.
+pub async fn run_session_notifier(
+    pool: ConnectionPool,
+    account_changes: broadcast::Sender<AccountStateChange>,
+    store: Arc<dyn EventStore>,
+    manager: Arc<RwLock<IdentityManager>>,
+    config: ProjectionConfig,
+    rebuild: bool,
+) -> Result<()> {
+    // Restore the state from the latest snapshot, so only subsequent events
+    // are projected and no stale notifications are broadcast. On rebuild,
+    // the full stream is projected onto an empty state instead.
+    let mut revision = None;
+    if !rebuild {
+        let repo =
+            Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone())
+                .await?;
+
+        *manager.write() = repo.state().clone();
+        revision = repo.revision();
+    }
+
+    let projection = Arc::new(tokio::sync::RwLock::new(SessionNotifier::new(
+        pool,
+        account_changes,
+        manager,
+    )));
+
+    Projector::new(projection, store)
+        .with_config(config)
+        .start_after(VerifierAggregateId, revision)
+        .run_blocking()
+        .await;
+
+    Err(anyhow!("The session notifier projection has exited"))
+}
+
+pub async fn run_rpc_api_service_blocking(
+    pool: ConnectionPool,
+    port: usize,
+    store: Arc<dyn EventStore>,
+    manager: Arc<RwLock<IdentityManager>>,
+    regenerate_cooldown: Duration,
+) -> Result<()> {
+    let (account_changes, _) = broadcast::channel(ACCOUNT_CHANGES_CAPACITY);
+
+    let regenerator = ChallengeRegenerator::new(
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone()).await?,
+        regenerate_cooldown,
+    );
+    run_rpc_api_server(pool.clone(), port, Arc::clone(&manager), regenerator)?;
+    run_session_notifier(
+        pool,
+        account_changes,
+        store,
+        manager,
+        ProjectionConfig::default(),
+        false,
+    )
+    .await
+}
+
+/// Verifies the messages received by the adapters and commits the changes to
+/// the identity state.
+pub async fn run_message_verifier(
+    store: Arc<dyn EventStore>,
+    config: ProjectionConfig,
+    attempt_limits: AttemptLimits,
+    rebuild: bool,
+) -> Result<()> {
+    let aggregate = VerifierAggregate::default().set_attempt_limits(attempt_limits);
+    let repo = Repository::new_with_snapshot_service(aggregate, store.clone()).await?;
+
+    let projection = Arc::new(tokio::sync::RwLock::new(MessageVerifier::new(repo)));
+    Projector::new(projection, store)
+        .with_config(config)
+        .with_checkpoint("message_verifier")
+        .rebuild(rebuild)
+        .run_blocking()
+        .await;
+
+    Err(anyhow!("The message verifier projection has exited"))
+}
+
+/// Submits the judgements of the verified identities on the given network.
+/// Each network has its own judgement giver, with its own checkpoint.
+pub async fn run_judgment_giver(
+    store: Arc<dyn EventStore>,
+    network: NetworkConfig,
+    config: JudgementConfig,
+    projection_config: ProjectionConfig,
+    rebuild: bool,
+) -> Result<()> {
+    if config.dry_run {
+        info!("Judgement dry-run is enabled, judgements are only logged");
+    }
+
+    let repo =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone()).await?;
+
+    let client = SubxtClient::connect(&network.endpoint).await?;
+    // The signer of the network falls back to the global signer.
+    let signer: Option<Box<dyn JudgementSigner>> = match &network.signer {
+        Some(SignerConfig::Keystore { seed }) => Some(Box::new(
+            KeystoreSigner::connect(&network.endpoint, seed).await?,
+        )),
+        Some(SignerConfig::Watcher { url }) => Some(Box::new(WatcherSigner::new(
+            url.clone(),
+            network.network.clone(),
+        ))),
+        None => {
+            if !config.dry_run {
+                warn!(
+                    "No judgement signer configured for {}, judgements are only logged",
+                    network.network
+                );
+            }
+
+            None
+        }
+    };
+
+    let giver = JudgmentGiver::new(repo).with_network(&network, Box::new(client), signer);
+
+    // The pending identities and remarks are restored from the events up to
+    // the checkpoint.
+    let projection = Arc::new(tokio::sync::RwLock::new(giver));
+    let projector = Projector::new(Arc::clone(&projection), store)
+        .with_config(projection_config)
+        .with_checkpoint(&format!("judgment_giver_{}", network.network))
+        .restore_state()
+        .rebuild(rebuild)
+        .run_blocking();
+
+    tokio::select! {
+        _ = projector => Err(anyhow!("The judgement giver projection has exited")),
+        result = reconcile_judgements(projection) => result,
+    }
+}
+
+/// Periodically confirms the submitted judgements once they show up on-chain,
+/// or submits them again if they got lost. Only returns on error.
+async fn reconcile_judgements(giver: Arc<tokio::sync::RwLock<JudgmentGiver>>) -> Result<()> {
+    let mut interval = time::interval(Duration::from_secs(RECONCILE_INTERVAL));
+    loop {
+        interval.tick().await;
+        giver.write().await.reconcile().await?;
+    }
+}
+
+/// Watches the judgement requests on the given network and inserts or removes
+/// the corresponding identities.
+pub async fn run_chain_watcher(
+    store: Arc<dyn EventStore>,
+    network: NetworkConfig,
+    verifier: VerifierConfig,
+) -> Result<()> {
+    let aggregate =
+        VerifierAggregate::default().set_identity_ttl(TTL::from_secs(verifier.identity_ttl));
+    let repo = Repository::new_with_snapshot_service(aggregate, store.clone()).await?;
+
+    info!(
+        "Connecting to {} node at {}",
+        network.network, network.endpoint
+    );
+    let client = SubxtClient::connect(&network.endpoint).await?;
+
+    ChainWatcher::new(client, repo, store, &network)
+        .run_blocking()
+        .await
+}
+
+/// Scans the finalized blocks of the given network for on-chain challenges.
+pub async fn run_remark_watcher(store: Arc<dyn EventStore>, network: NetworkConfig) -> Result<()> {
+    let repo =
+        Repository::new_with_snapshot_service(RemarkWatcher::default(), store.clone()).await?;
+
+    info!(
+        "Connecting to {} node at {} for remark detection",
+        network.network, network.endpoint
+    );
+    let client = SubxtClient::connect(&network.endpoint).await?;
+
+    RemarkScanner::new(client, repo, store, network.chain_spec())
+        .run_blocking()
+        .await
+}
+
+/// Periodically expires the judgement requests which were not verified within
+/// their TTL.
+pub async fn run_identity_expiry(store: Arc<dyn EventStore>, config: VerifierConfig) -> Result<()> {
+    let mut repo =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store).await?;
+
+    let mut interval = time::interval(Duration::from_secs(config.expiry_interval));
+    loop {
+        interval.tick().await;
+
+        // Consider the identities which were inserted in the meantime.
+        repo.catch_up().await?;
+        repo.apply(VerifierCommand::ExpireIdentities).await?;
+    }
+}
+
 /*
 pub async fn run_verifier_subscription(
     client: Client,
@@ -83,7 +665,10 @@ pub async fn run_verifier_subscription(
 }
 */
 
-pub async fn run_matrix_listener_blocking(config: MatrixConfig, repo: Repository<MessageWatcher>) -> Result<()> {
+pub async fn run_matrix_listener_blocking(
+    config: MatrixConfig,
+    repo: Repository<MessageWatcher>,
+) -> Result<()> {
     info!("Configuring Matrix client");
 
     let (client, recv) = MatrixClient::new(
@@ -97,10 +682,13 @@ pub async fn run_matrix_listener_blocking(config: MatrixConfig, repo: Repository
     info!("Starting Matrix client");
     client.start().await;
 
-    messages_event_loop(repo, recv, "email").await
+    messages_event_loop(repo, recv, "Matrix").await
 }
 
-pub async fn run_email_listener_blocking(config: EmailConfig, repo: Repository<MessageWatcher>) -> Result<()> {
+pub async fn run_email_listener_blocking(
+    config: EmailConfig,
+    repo: Repository<MessageWatcher>,
+) -> Result<()> {
     info!("Configuring email client");
 
     let (client, recv) = SmtpImapClientBuilder::new()
@@ -113,9 +701,9 @@ pub async fn run_email_listener_blocking(config: EmailConfig, repo: Repository<M
         .build()?;
 
     info!("Starting email client");
-    client.start().await;
+    let client = tokio::spawn(async move { client.start().await });
 
-    messages_event_loop(repo, recv, "email").await
+    run_adapter(client, repo, recv, "email").await
 }
 
 pub async fn run_twitter_listener_blocking(
@@ -133,9 +721,33 @@ pub async fn run_twitter_listener_blocking(
         .build()?;
 
     info!("Starting Twitter client");
-    client.start().await;
+    let client = tokio::spawn(async move { client.start().await });
+
+    run_adapter(client, repo, recv, "Twitter").await
+}
+
+/// Runs the event loop of an adapter next to its client task. Once either one
+/// exits, the client is aborted, so a restarted adapter does not run next to
+/// the client of a previous run.
+async fn run_adapter<T>(
+    mut client: JoinHandle<()>,
+    repo: Repository<MessageWatcher>,
+    recv: Receiver<T>,
+    name: &str,
+) -> Result<()>
+where
+    T: Into<ExternalMessage>,
+{
+    let res = tokio::select! {
+        res = &mut client => match res {
+            Ok(()) => Err(anyhow!("The {} client has exited", name)),
+            Err(err) => Err(anyhow!("The {} client has crashed: {:?}", name, err)),
+        },
+        res = messages_event_loop(repo, recv, name) => res,
+    };
 
-    messages_event_loop(repo, recv, "Twitter").await
+    client.abort();
+    res
 }
 
 /// For each message received by an adapter, send a command to the aggregate and
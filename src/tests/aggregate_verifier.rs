@@ -1,14 +1,21 @@
 use super::InMemBackend;
-use crate::aggregate::verifier::{VerifierAggregate, VerifierAggregateId, VerifierCommand};
+use crate::aggregate::verifier::{
+    RemovalReason, VerifierAggregate, VerifierAggregateId, VerifierAggregateSnapshotsId,
+    VerifierCommand,
+};
 use crate::aggregate::Repository;
 use crate::event::{
     DisplayNamePersisted, Event, EventType, ExternalMessage, ExternalOrigin, FieldStatusVerified,
+    IdentityExpired, IdentityLocked, IdentityRemoved, IdentityUnlocked, JudgementRequestCancelled,
+    TTL,
 };
 use crate::manager::{
-    ChallengeStatus, DisplayName, ExpectedMessage, FieldAddress, FieldStatus, IdentityField,
-    IdentityFieldType, IdentityState, ProvidedMessage, RegistrarIdentityField, Validity,
+    AttemptLimits, ChallengeStatus, DisplayName, ExpectedMessage, FieldAddress, FieldStatus,
+    IdentityField, IdentityFieldType, IdentityState, LimitAction, ProvidedMessage,
+    RegistrarIdentityField, Validity,
 };
 use std::convert::TryFrom;
+use tokio::time::{self, Duration};
 
 #[tokio::test]
 async fn insert_identities() {
@@ -184,6 +191,61 @@ async fn insert_identities_state_change() {
     assert!(state.contains(&alice_new));
 }
 
+#[tokio::test]
+async fn insert_identities_keep_challenges() {
+    let be = InMemBackend::run().await;
+    let mut repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), be.store())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    // The same identity with freshly generated challenges, as created by the
+    // chain watcher on every `IdentitySet` event.
+    let alice_again = IdentityState::alice();
+    assert_ne!(alice_again, alice);
+
+    repo.apply(VerifierCommand::InsertIdentity(alice_again.clone()))
+        .await
+        .unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 1);
+
+    // Only the changed field gets a new challenge.
+    let mut alice_new = alice_again;
+    alice_new
+        .fields
+        .get_mut(&IdentityFieldType::Email)
+        .map(|field| {
+            *field.mut_field() =
+                IdentityField::Email(FieldAddress::from("alice_new@email.com".to_string()))
+        })
+        .unwrap();
+
+    repo.apply(VerifierCommand::InsertIdentity(alice_new.clone()))
+        .await
+        .unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 2);
+    let inserted = match &events[1].body {
+        EventType::IdentityInserted(inserted) => inserted.identity.clone(),
+        _ => panic!(),
+    };
+
+    assert_eq!(inserted.on_chain_challenge, alice.on_chain_challenge);
+    assert_eq!(
+        inserted.fields[&IdentityFieldType::Email],
+        alice_new.fields[&IdentityFieldType::Email]
+    );
+    for field_ty in &[IdentityFieldType::Matrix, IdentityFieldType::Twitter] {
+        assert_eq!(inserted.fields[field_ty], alice.fields[field_ty]);
+    }
+    assert!(repo.state().contains(&inserted));
+}
+
 #[tokio::test]
 async fn verify_message_valid_message() {
     let be = InMemBackend::run().await;
@@ -248,6 +310,7 @@ async fn verify_message_valid_message() {
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: new_field_state,
+            message_revision: None,
         })),
     ];
 
@@ -331,6 +394,7 @@ async fn verify_message_invalid_message() {
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: alice_invalid_state,
+            message_revision: None,
         })),
     ];
 
@@ -467,14 +531,17 @@ async fn verify_message_valid_message_multiple() {
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: alice_invalid_state.clone(),
+            message_revision: None,
         })),
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: alice_invalid_state,
+            message_revision: None,
         })),
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: alice_valid_state,
+            message_revision: None,
         })),
         // Since the field has been verified, no new event is created, even
         // though a invalid message has been sent after verification.
@@ -610,10 +677,12 @@ async fn verify_message_invalid_origin() {
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: bob.net_address.clone(),
             field_status: bob_invalid_state,
+            message_revision: None,
         })),
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: alice_invalid_state,
+            message_revision: None,
         })),
     ];
 
@@ -752,10 +821,12 @@ async fn verify_and_persist_display_names() {
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: alice.net_address.clone(),
             field_status: alice_invalid_state,
+            message_revision: None,
         })),
         Event::from(EventType::FieldStatusVerified(FieldStatusVerified {
             net_address: bob.net_address.clone(),
             field_status: bob_valid_state,
+            message_revision: None,
         })),
     ];
 
@@ -787,3 +858,539 @@ async fn verify_and_persist_display_names() {
     assert!(state.contains(&bob_new));
     assert!(state.contains(&eve));
 }
+
+#[tokio::test]
+async fn concurrent_repositories() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    let mut repo_a =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone())
+            .await
+            .unwrap();
+    let mut repo_b =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone())
+            .await
+            .unwrap();
+
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+
+    repo_a
+        .apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    // The second repository is behind, so it must catch up first. Alice was
+    // already inserted, so this results in no new event.
+    repo_b
+        .apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    repo_b
+        .apply(VerifierCommand::InsertIdentity(bob.clone()))
+        .await
+        .unwrap();
+
+    // Check the resulting events.
+    let expected = [
+        Event::from(EventType::IdentityInserted(alice.clone().into())),
+        Event::from(EventType::IdentityInserted(bob.clone().into())),
+    ];
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), expected.len());
+
+    for (expected, event) in expected.iter().zip(events.iter()) {
+        assert_eq!(expected.body, event.body);
+    }
+
+    // Both identities are known to the second repository.
+    let state = repo_b.state();
+    assert!(state.contains(&alice));
+    assert!(state.contains(&bob));
+}
+
+#[tokio::test]
+async fn catch_up_after_snapshot() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    // A snapshot is created after the second event.
+    let aggregate = VerifierAggregate::default().set_snapshot_every(2);
+    let mut repo = Repository::new_with_snapshot_service(aggregate, store.clone())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+    let eve = IdentityState::eve();
+
+    for identity in &[&alice, &bob, &eve] {
+        repo.apply(VerifierCommand::InsertIdentity((*identity).clone()))
+            .await
+            .unwrap();
+    }
+
+    // The snapshot only covers Alice and Bob, Eve must be restored from the
+    // subsequent event.
+    let snapshots = be.get_events(VerifierAggregateSnapshotsId).await;
+    assert_eq!(snapshots.len(), 1);
+
+    let aggregate = VerifierAggregate::default().set_snapshot_every(2);
+    let repo = Repository::new_with_snapshot_service(aggregate, store)
+        .await
+        .unwrap();
+
+    let state = repo.state();
+    assert!(state.contains(&alice));
+    assert!(state.contains(&bob));
+    assert!(state.contains(&eve));
+}
+
+#[tokio::test]
+async fn expire_identities() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+
+    // Alice expires, Bob does not.
+    let aggregate = VerifierAggregate::default()
+        .set_snapshot_every(1)
+        .set_identity_ttl(TTL::from_secs(1));
+    let mut repo = Repository::new_with_snapshot_service(aggregate, store.clone())
+        .await
+        .unwrap();
+
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    let mut repo =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone())
+            .await
+            .unwrap();
+
+    repo.apply(VerifierCommand::InsertIdentity(bob.clone()))
+        .await
+        .unwrap();
+
+    // Nothing has expired yet.
+    repo.apply(VerifierCommand::ExpireIdentities).await.unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 2);
+
+    time::sleep(Duration::from_millis(1_500)).await;
+
+    // The expiry is restored from the snapshot.
+    let mut repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), store)
+        .await
+        .unwrap();
+
+    repo.apply(VerifierCommand::ExpireIdentities).await.unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 3);
+    assert_eq!(
+        events[2].body,
+        EventType::IdentityExpired(IdentityExpired {
+            net_address: alice.net_address.clone(),
+        })
+    );
+
+    let state = repo.state();
+    assert!(state.lookup_full_state(&alice.net_address).is_none());
+    assert!(state.contains(&bob));
+
+    // Already expired identities are not expired again.
+    repo.apply(VerifierCommand::ExpireIdentities).await.unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 3);
+}
+
+#[tokio::test]
+async fn remove_identities() {
+    let be = InMemBackend::run().await;
+    let mut repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), be.store())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+    let display_name = alice
+        .fields
+        .values()
+        .find_map(|status| match &status.field {
+            IdentityField::DisplayName(name) => Some(name.clone()),
+            _ => None,
+        })
+        .unwrap();
+
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+    repo.apply(VerifierCommand::PersistDisplayName {
+        net_address: alice.net_address.clone(),
+        display_name: display_name.clone(),
+    })
+    .await
+    .unwrap();
+
+    // Bob has no pending judgement request.
+    repo.apply(VerifierCommand::RemoveIdentity {
+        net_address: bob.net_address.clone(),
+        reason: RemovalReason::JudgementRequestCancelled,
+    })
+    .await
+    .unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 2);
+
+    // A cancelled request keeps the persisted display name.
+    repo.apply(VerifierCommand::RemoveIdentity {
+        net_address: alice.net_address.clone(),
+        reason: RemovalReason::JudgementRequestCancelled,
+    })
+    .await
+    .unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 3);
+    assert_eq!(
+        events[2].body,
+        EventType::JudgementRequestCancelled(JudgementRequestCancelled {
+            net_address: alice.net_address.clone(),
+        })
+    );
+
+    let state = repo.state();
+    assert!(state.lookup_full_state(&alice.net_address).is_none());
+    assert_eq!(state.display_name(&alice.net_address), Some(&display_name));
+
+    // A cleared identity also releases the display name.
+    repo.apply(VerifierCommand::RemoveIdentity {
+        net_address: alice.net_address.clone(),
+        reason: RemovalReason::IdentityCleared,
+    })
+    .await
+    .unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 4);
+    assert_eq!(
+        events[3].body,
+        EventType::IdentityRemoved(IdentityRemoved {
+            net_address: alice.net_address.clone(),
+        })
+    );
+    assert!(repo.state().display_name(&alice.net_address).is_none());
+
+    // Nothing is left to remove.
+    repo.apply(VerifierCommand::RemoveIdentity {
+        net_address: alice.net_address.clone(),
+        reason: RemovalReason::IdentityCleared,
+    })
+    .await
+    .unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 4);
+}
+
+#[tokio::test]
+async fn lock_identity_after_failed_attempts() {
+    let be = InMemBackend::run().await;
+    let aggregate = VerifierAggregate::default().set_attempt_limits(AttemptLimits {
+        field: 0,
+        action: LimitAction::Regenerate,
+        identity: 2,
+    });
+    let mut repo = Repository::new_with_snapshot_service(aggregate, be.store())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    let challenge = match alice.fields[&IdentityFieldType::Matrix].challenge() {
+        ChallengeStatus::ExpectMessage(challenge) => challenge.expected_message.clone(),
+        _ => panic!(),
+    };
+
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    let message = |message: ExpectedMessage| ExternalMessage {
+        origin: ExternalOrigin::Matrix,
+        field_address: FieldAddress::from("@alice:matrix.org".to_string()),
+        message: ProvidedMessage::from(message),
+    };
+
+    for _ in 0..2 {
+        repo.apply(VerifierCommand::VerifyMessage(message(
+            ExpectedMessage::invalid(),
+        )))
+        .await
+        .unwrap();
+    }
+
+    // The second failed attempt locks the field and the identity.
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 4);
+    match &events[1].body {
+        EventType::FieldStatusVerified(verified) => assert!(!verified.field_status.is_locked()),
+        _ => panic!(),
+    }
+    match &events[2].body {
+        EventType::FieldStatusVerified(verified) => assert!(verified.field_status.is_locked()),
+        _ => panic!(),
+    }
+    assert_eq!(
+        events[3].body,
+        EventType::IdentityLocked(IdentityLocked {
+            net_address: alice.net_address.clone(),
+        })
+    );
+    assert!(repo.state().is_locked(&alice.net_address));
+
+    // Even the valid challenge is no longer verified.
+    repo.apply(VerifierCommand::VerifyMessage(message(challenge)))
+        .await
+        .unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 4);
+}
+
+#[tokio::test]
+async fn ignore_already_verified_messages() {
+    let be = InMemBackend::run().await;
+    let limits = AttemptLimits {
+        field: 0,
+        action: LimitAction::Regenerate,
+        identity: 2,
+    };
+    let aggregate = VerifierAggregate::default().set_attempt_limits(limits);
+    let mut repo = Repository::new_with_snapshot_service(aggregate, be.store())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    let message = ExternalMessage {
+        origin: ExternalOrigin::Matrix,
+        field_address: FieldAddress::from("@alice:matrix.org".to_string()),
+        message: ProvidedMessage::from(ExpectedMessage::invalid()),
+    };
+
+    repo.apply(VerifierCommand::VerifyRecordedMessage {
+        message: message.clone(),
+        revision: 0,
+    })
+    .await
+    .unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 2);
+    match &events[1].body {
+        EventType::FieldStatusVerified(verified) => {
+            assert_eq!(verified.message_revision, Some(0))
+        }
+        _ => panic!(),
+    }
+
+    // The same message is verified again after a restart.
+    let aggregate = VerifierAggregate::default().set_attempt_limits(limits);
+    let mut repo = Repository::new_with_snapshot_service(aggregate, be.store())
+        .await
+        .unwrap();
+
+    repo.apply(VerifierCommand::VerifyRecordedMessage {
+        message: message,
+        revision: 0,
+    })
+    .await
+    .unwrap();
+
+    // The failed attempt is only counted once, so the identity is not locked.
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 2);
+    let state = repo.state();
+    assert_eq!(state.failed_attempts(&alice.net_address), 1);
+    assert!(!state.is_locked(&alice.net_address));
+}
+
+#[tokio::test]
+async fn unlock_identity_on_renewed_request() {
+    let be = InMemBackend::run().await;
+    let aggregate = VerifierAggregate::default().set_attempt_limits(AttemptLimits {
+        field: 0,
+        action: LimitAction::Regenerate,
+        identity: 2,
+    });
+    let mut repo = Repository::new_with_snapshot_service(aggregate, be.store())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    let challenge = match alice.fields[&IdentityFieldType::Matrix].challenge() {
+        ChallengeStatus::ExpectMessage(challenge) => challenge.expected_message.clone(),
+        _ => panic!(),
+    };
+
+    repo.apply(VerifierCommand::RequestJudgement(alice.clone()))
+        .await
+        .unwrap();
+
+    let message = |message: ExpectedMessage| ExternalMessage {
+        origin: ExternalOrigin::Matrix,
+        field_address: FieldAddress::from("@alice:matrix.org".to_string()),
+        message: ProvidedMessage::from(message),
+    };
+
+    for _ in 0..2 {
+        repo.apply(VerifierCommand::VerifyMessage(message(
+            ExpectedMessage::invalid(),
+        )))
+        .await
+        .unwrap();
+    }
+    assert!(repo.state().is_locked(&alice.net_address));
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 4);
+
+    // Inserting the unchanged identity, e.g. because it was set again
+    // on-chain, does not unlock it.
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+    assert!(repo.state().is_locked(&alice.net_address));
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 4);
+
+    // The judgement is requested again with unchanged fields.
+    repo.apply(VerifierCommand::RequestJudgement(alice.clone()))
+        .await
+        .unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 5);
+    assert_eq!(
+        events[4].body,
+        EventType::IdentityUnlocked(IdentityUnlocked {
+            net_address: alice.net_address.clone(),
+        })
+    );
+
+    let state = repo.state();
+    assert!(!state.is_locked(&alice.net_address));
+    assert_eq!(state.failed_attempts(&alice.net_address), 0);
+    let fields = state.lookup_full_state(&alice.net_address).unwrap().fields;
+    assert!(!fields[&IdentityFieldType::Matrix].is_locked());
+
+    // Renewing the request of an unlocked identity changes nothing.
+    repo.apply(VerifierCommand::RequestJudgement(alice.clone()))
+        .await
+        .unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 5);
+
+    // The challenge is verified again.
+    repo.apply(VerifierCommand::VerifyMessage(message(challenge)))
+        .await
+        .unwrap();
+
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 6);
+    match &events[5].body {
+        EventType::FieldStatusVerified(verified) => assert!(verified.field_status.is_valid()),
+        _ => panic!(),
+    }
+}
+
+#[tokio::test]
+async fn regenerate_challenge() {
+    let be = InMemBackend::run().await;
+    let aggregate = VerifierAggregate::default().set_attempt_limits(AttemptLimits {
+        field: 1,
+        action: LimitAction::Lock,
+        identity: 0,
+    });
+    let mut repo = Repository::new_with_snapshot_service(aggregate, be.store())
+        .await
+        .unwrap();
+
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+    let challenge =
+        |state: &IdentityState| match state.fields[&IdentityFieldType::Matrix].challenge() {
+            ChallengeStatus::ExpectMessage(challenge) => challenge.expected_message.clone(),
+            _ => panic!(),
+        };
+
+    repo.apply(VerifierCommand::InsertIdentity(alice.clone()))
+        .await
+        .unwrap();
+
+    let message = |message: ExpectedMessage| ExternalMessage {
+        origin: ExternalOrigin::Matrix,
+        field_address: FieldAddress::from("@alice:matrix.org".to_string()),
+        message: ProvidedMessage::from(message),
+    };
+
+    // The failed attempt locks the field.
+    repo.apply(VerifierCommand::VerifyMessage(message(
+        ExpectedMessage::invalid(),
+    )))
+    .await
+    .unwrap();
+
+    // Requests which cannot be fulfilled are ignored.
+    for (net_address, field) in vec![
+        (bob.net_address.clone(), IdentityFieldType::Matrix),
+        (alice.net_address.clone(), IdentityFieldType::DisplayName),
+        (alice.net_address.clone(), IdentityFieldType::Web),
+    ] {
+        repo.apply(VerifierCommand::RegenerateChallenge {
+            net_address: net_address,
+            field: field,
+        })
+        .await
+        .unwrap();
+    }
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 2);
+
+    repo.apply(VerifierCommand::RegenerateChallenge {
+        net_address: alice.net_address.clone(),
+        field: IdentityFieldType::Matrix,
+    })
+    .await
+    .unwrap();
+
+    // The field is unlocked and expects the new challenge, which has not been
+    // attempted yet.
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 3);
+    let regenerated = match &events[2].body {
+        EventType::ChallengeRegenerated(regenerated) => regenerated.field_status.clone(),
+        _ => panic!(),
+    };
+    assert!(!regenerated.is_locked());
+    assert!(!regenerated.is_invalid());
+
+    let state = repo.state().lookup_full_state(&alice.net_address).unwrap();
+    assert_eq!(state.fields[&IdentityFieldType::Matrix], regenerated);
+
+    let new_challenge = challenge(&state);
+    assert_ne!(new_challenge, challenge(&alice));
+
+    repo.apply(VerifierCommand::VerifyMessage(message(new_challenge)))
+        .await
+        .unwrap();
+
+    let state = repo.state().lookup_full_state(&alice.net_address).unwrap();
+    assert!(state.fields[&IdentityFieldType::Matrix].is_valid());
+
+    // Verified fields keep their challenge.
+    repo.apply(VerifierCommand::RegenerateChallenge {
+        net_address: alice.net_address.clone(),
+        field: IdentityFieldType::Matrix,
+    })
+    .await
+    .unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 4);
+}
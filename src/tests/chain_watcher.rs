@@ -0,0 +1,563 @@
+use super::{kusama, polkadot, InMemBackend};
+use crate::aggregate::verifier::{VerifierAggregate, VerifierAggregateId};
+use crate::aggregate::Repository;
+use crate::chain::{
+    net_address, parse_address, AccountId, AddressError, BlockNumber, ChainClient, ChainSpec,
+    ChainWatcher, Data, IdentityEvent, IdentityInfo, Judgement, Networks, Registration,
+};
+use crate::event::{BlankNetwork, EventType, IdentityRemoved, JudgementRequestCancelled};
+use crate::manager::{
+    ChallengeStatus, DisplayName, FieldAddress, IdentityField, IdentityFieldType, IdentityState,
+    NetworkAddress,
+};
+use crate::{NetworkConfig, RegistrarConfig, Result};
+use parity_scale_codec::{Decode, Encode};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Identity events and storage of a Polkadot node, one entry per block.
+const IDENTITY_EVENTS: &str = include_str!("fixtures/identity_events.json");
+
+const ALICE: &str = "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d";
+const BOB: &str = "8eaf04151687736326c9fea17e25fc5287613693c912909cb226aa4794f26a48";
+const CHARLIE: &str = "90b5ab205c6974c9ea841be688864633dc9ca8a357843eeacf2314649965fe22";
+
+#[derive(Deserialize)]
+struct Fixture {
+    blocks: VecDeque<Block>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    events: Vec<RawEvent>,
+    // The identities after the block was finalized.
+    registrations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawEvent {
+    module: String,
+    variant: String,
+    data: String,
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    hex::decode(hex.trim_start_matches("0x")).unwrap()
+}
+
+fn account(hex: &str) -> AccountId {
+    let mut account = [0; 32];
+    account.copy_from_slice(&decode_hex(hex));
+    account
+}
+
+/// Replays the recorded blocks, one more block is finalized on each request.
+/// Fails once all blocks are processed.
+struct FixtureClient {
+    blocks: Vec<Block>,
+    finalized: Mutex<Option<BlockNumber>>,
+    registrations: Mutex<HashMap<AccountId, Registration>>,
+}
+
+impl FixtureClient {
+    fn new() -> Self {
+        let fixture: Fixture = serde_json::from_str(IDENTITY_EVENTS).unwrap();
+
+        FixtureClient {
+            blocks: fixture.blocks.into_iter().collect(),
+            finalized: Mutex::new(None),
+            registrations: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl ChainClient for FixtureClient {
+    async fn finalized_block(&self) -> Result<BlockNumber> {
+        let mut finalized = self.finalized.lock();
+        let next = finalized.map(|number| number + 1).unwrap_or(0);
+
+        if next as usize >= self.blocks.len() {
+            return Err(anyhow!("no blocks left in fixture"));
+        }
+
+        *finalized = Some(next);
+        Ok(next)
+    }
+    async fn events(&self, number: BlockNumber) -> Result<Vec<IdentityEvent>> {
+        let block = &self.blocks[number as usize];
+
+        *self.registrations.lock() = block
+            .registrations
+            .iter()
+            .map(|(who, registration)| {
+                (
+                    account(who),
+                    Registration::decode(&mut decode_hex(registration).as_slice()).unwrap(),
+                )
+            })
+            .collect();
+
+        let mut events = vec![];
+        for raw in &block.events {
+            if let Some(event) =
+                IdentityEvent::decode(&raw.module, &raw.variant, &decode_hex(&raw.data))?
+            {
+                events.push(event);
+            }
+        }
+
+        Ok(events)
+    }
+    async fn registration(&self, who: &AccountId) -> Result<Option<Registration>> {
+        Ok(self.registrations.lock().get(who).cloned())
+    }
+}
+
+/// Serves the given events, one entry per block, up to the finalized block,
+/// and the same registration for every account.
+struct ScriptedClient {
+    blocks: Vec<Vec<IdentityEvent>>,
+    finalized: Arc<Mutex<BlockNumber>>,
+    registration: Registration,
+}
+
+#[async_trait]
+impl ChainClient for ScriptedClient {
+    async fn finalized_block(&self) -> Result<BlockNumber> {
+        Ok(*self.finalized.lock())
+    }
+    async fn events(&self, number: BlockNumber) -> Result<Vec<IdentityEvent>> {
+        assert!(number <= *self.finalized.lock());
+
+        Ok(self.blocks[number as usize].clone())
+    }
+    async fn registration(&self, _: &AccountId) -> Result<Option<Registration>> {
+        Ok(Some(self.registration.clone()))
+    }
+}
+
+fn raw(val: &str) -> Data {
+    Data::Raw(val.as_bytes().to_vec())
+}
+
+fn network() -> NetworkConfig {
+    NetworkConfig {
+        network: BlankNetwork::from("polkadot"),
+        endpoint: "ws://localhost:9944".to_string(),
+        ss58_prefix: None,
+        utility_pallet: None,
+        registrar_index: 0,
+        registrar: Default::default(),
+        signer: None,
+        policy: Default::default(),
+    }
+}
+
+fn field(identity: &IdentityState, ty: IdentityFieldType) -> &IdentityField {
+    &identity.fields.get(&ty).unwrap().field
+}
+
+#[test]
+fn encode_addresses() {
+    assert_eq!(
+        net_address(&polkadot(), &account(ALICE)),
+        NetworkAddress::from(
+            BlankNetwork::from("polkadot"),
+            "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6Sp5"
+                .to_string()
+                .into()
+        )
+    );
+    assert_eq!(
+        net_address(&kusama(), &account(ALICE)),
+        NetworkAddress::from(
+            BlankNetwork::from("kusama"),
+            "HNZata7iMYWmk5RvZRTiAsSDhV8366zq2YGb3tLH5Upf74F"
+                .to_string()
+                .into()
+        )
+    );
+}
+
+#[test]
+fn parse_addresses() {
+    let polkadot = "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6Sp5";
+    let kusama = "HNZata7iMYWmk5RvZRTiAsSDhV8366zq2YGb3tLH5Upf74F";
+    let substrate = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+
+    let alice = net_address(&polkadot(), &account(ALICE));
+    assert_eq!(parse_address(&polkadot(), polkadot).unwrap(), alice);
+    assert_eq!(
+        parse_address(&kusama(), kusama).unwrap(),
+        net_address(&kusama(), &account(ALICE))
+    );
+
+    // Generic addresses are re-encoded for the network.
+    assert_eq!(parse_address(&polkadot(), substrate).unwrap(), alice);
+
+    // Addresses of other networks are rejected.
+    assert_eq!(
+        parse_address(&polkadot(), kusama),
+        Err(AddressError::WrongNetwork {
+            address: kusama.to_string(),
+            network: BlankNetwork::from("polkadot"),
+        })
+    );
+
+    // Invalid checksums, lengths and characters are rejected.
+    let invalid = [
+        "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6Sp6",
+        "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6S",
+        "15oF4uVJwmo4TdGW7VfQxNLavjCXviqxT9S1MgbjMNHr6Sp0",
+        "",
+    ];
+    for address in &invalid {
+        assert!(matches!(
+            parse_address(&polkadot(), address),
+            Err(AddressError::Invalid { .. })
+        ));
+    }
+
+    // Other networks use the configured prefix.
+    let westend = ChainSpec {
+        network: BlankNetwork::from("westend"),
+        ss58_prefix: 42,
+        utility_pallet: None,
+    };
+    assert_eq!(
+        net_address(&westend, &account(ALICE)),
+        NetworkAddress::from(BlankNetwork::from("westend"), substrate.to_string().into())
+    );
+    assert!(matches!(
+        parse_address(&westend, polkadot),
+        Err(AddressError::WrongNetwork { .. })
+    ));
+
+    // Only the configured networks are served.
+    let networks = Networks::new(&[network()]);
+    assert_eq!(
+        networks
+            .parse_address(&BlankNetwork::from("polkadot"), substrate)
+            .unwrap(),
+        alice
+    );
+    assert_eq!(
+        networks.parse_address(&BlankNetwork::from("kusama"), kusama),
+        Err(AddressError::UnknownNetwork(BlankNetwork::from("kusama")))
+    );
+}
+
+#[test]
+fn decode_identity_data() {
+    let values = vec![
+        Data::None,
+        Data::Raw(vec![]),
+        Data::Raw(b"Alice".to_vec()),
+        Data::Raw(vec![1; 32]),
+        Data::BlakeTwo256([2; 32]),
+        Data::ShaThree256([3; 32]),
+    ];
+
+    for data in values {
+        let encoded = data.encode();
+        assert_eq!(Data::decode(&mut encoded.as_slice()).unwrap(), data);
+    }
+
+    // Truncated values and unknown variants are rejected.
+    assert!(Data::decode(&mut [6, b'A'].as_ref()).is_err());
+    assert!(Data::decode(&mut [34, 0].as_ref()).is_err());
+    assert!(Data::decode(&mut [38].as_ref()).is_err());
+}
+
+#[test]
+fn decode_identity_events() {
+    let mut fixture: Fixture = serde_json::from_str(IDENTITY_EVENTS).unwrap();
+    let block = fixture.blocks.pop_front().unwrap();
+
+    let events: Vec<Option<IdentityEvent>> = block
+        .events
+        .iter()
+        .map(|raw| {
+            IdentityEvent::decode(&raw.module, &raw.variant, &decode_hex(&raw.data)).unwrap()
+        })
+        .collect();
+
+    assert_eq!(
+        events,
+        vec![
+            None,
+            Some(IdentityEvent::JudgementRequested {
+                who: account(ALICE),
+                registrar_index: 0,
+            }),
+            Some(IdentityEvent::JudgementRequested {
+                who: account(BOB),
+                registrar_index: 1,
+            }),
+        ]
+    );
+
+    // Truncated events are rejected.
+    assert!(IdentityEvent::decode("Identity", "JudgementRequested", &[0; 32]).is_err());
+}
+
+#[test]
+fn decode_registrations() {
+    let mut fixture: Fixture = serde_json::from_str(IDENTITY_EVENTS).unwrap();
+    let block = fixture.blocks.pop_front().unwrap();
+
+    let alice = Registration::decode(
+        &mut decode_hex(&block.registrations[&format!("0x{}", ALICE)]).as_slice(),
+    )
+    .unwrap();
+
+    assert!(alice.is_pending(0));
+    assert!(!alice.is_pending(1));
+    assert_eq!(
+        alice.info.fields(),
+        vec![
+            IdentityField::DisplayName(DisplayName::from("Alice".to_string())),
+            IdentityField::LegalName(FieldAddress::from("Alice Doe".to_string())),
+            IdentityField::Matrix(FieldAddress::from("@alice:matrix.org".to_string())),
+            IdentityField::Email(FieldAddress::from("alice@email.com".to_string())),
+            IdentityField::Twitter(FieldAddress::from("@alice".to_string())),
+            IdentityField::PGPFingerprint(FieldAddress::from(
+                "000102030405060708090a0b0c0d0e0f10111213".to_string()
+            )),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn watch_judgement_requests() {
+    let be = InMemBackend::run().await;
+    let repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), be.store())
+        .await
+        .unwrap();
+
+    // No Twitter account is available, and display names are not verified.
+    let mut network = network();
+    network.registrar = RegistrarConfig {
+        email: Some("registrar@web3.foundation".to_string()),
+        twitter: None,
+        matrix: Some("@registrar:web3.foundation".to_string()),
+        fields: Some(vec![
+            IdentityFieldType::Email,
+            IdentityFieldType::Twitter,
+            IdentityFieldType::Matrix,
+            IdentityFieldType::Additional,
+        ]),
+    };
+
+    // Each scan processes the next block, until all blocks of the fixture
+    // are processed.
+    let mut watcher = ChainWatcher::new(FixtureClient::new(), repo, be.store(), &network);
+    while watcher.scan().await.is_ok() {}
+
+    let alice = net_address(&polkadot(), &account(ALICE));
+    let charlie = net_address(&polkadot(), &account(CHARLIE));
+
+    // The request to another registrar is ignored.
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 5);
+
+    let inserted: Vec<IdentityState> = events[..3]
+        .iter()
+        .map(|event| match &event.body {
+            EventType::IdentityInserted(inserted) => inserted.identity.clone(),
+            _ => panic!("unexpected event: {:?}", event),
+        })
+        .collect();
+
+    // Judgement requested by Alice.
+    let identity = &inserted[0];
+    assert_eq!(identity.net_address, alice);
+    assert_eq!(identity.fields.len(), 6);
+    assert_eq!(
+        field(identity, IdentityFieldType::Email),
+        &IdentityField::Email(FieldAddress::from("alice@email.com".to_string()))
+    );
+    assert_eq!(
+        identity
+            .fields
+            .get(&IdentityFieldType::Twitter)
+            .unwrap()
+            .challenge(),
+        &ChallengeStatus::Unsupported
+    );
+    assert!(matches!(
+        identity
+            .fields
+            .get(&IdentityFieldType::Matrix)
+            .unwrap()
+            .challenge(),
+        ChallengeStatus::ExpectMessage(_)
+    ));
+    assert_eq!(
+        identity
+            .fields
+            .get(&IdentityFieldType::DisplayName)
+            .unwrap()
+            .challenge(),
+        &ChallengeStatus::Unsupported
+    );
+
+    // Alice changed her email address.
+    assert_eq!(inserted[1].net_address, alice);
+    assert_eq!(
+        field(&inserted[1], IdentityFieldType::Email),
+        &IdentityField::Email(FieldAddress::from("alice@example.com".to_string()))
+    );
+
+    // The hashed display name of Charlie is skipped.
+    let identity = &inserted[2];
+    assert_eq!(identity.net_address, charlie);
+    assert_eq!(identity.fields.len(), 2);
+    assert!(identity.fields.contains_key(&IdentityFieldType::Matrix));
+    assert!(identity.fields.contains_key(&IdentityFieldType::Additional));
+
+    // Charlie cancelled the request, Alice cleared the identity.
+    assert_eq!(
+        events[3].body,
+        EventType::JudgementRequestCancelled(JudgementRequestCancelled {
+            net_address: charlie.clone(),
+        })
+    );
+    assert_eq!(
+        events[4].body,
+        EventType::IdentityRemoved(IdentityRemoved {
+            net_address: alice.clone(),
+        })
+    );
+
+    let repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), be.store())
+        .await
+        .unwrap();
+    assert!(repo.state().lookup_full_state(&alice).is_none());
+    assert!(repo.state().lookup_full_state(&charlie).is_none());
+}
+
+/// A pending judgement request to registrar zero.
+fn pending_registration() -> Registration {
+    Registration {
+        judgements: vec![(0, Judgement::FeePaid(0))],
+        deposit: 0,
+        info: IdentityInfo {
+            additional: vec![],
+            display: raw("Alice"),
+            legal: Data::None,
+            web: Data::None,
+            riot: raw("@alice:matrix.org"),
+            email: raw("alice@email.com"),
+            pgp_fingerprint: None,
+            image: Data::None,
+            twitter: Data::None,
+        },
+    }
+}
+
+#[tokio::test]
+async fn repeated_identity_set() {
+    let be = InMemBackend::run().await;
+    let repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), be.store())
+        .await
+        .unwrap();
+
+    let who = account(ALICE);
+
+    let mut network = network();
+    network.registrar.email = Some("registrar@web3.foundation".to_string());
+    network.registrar.matrix = Some("@registrar:web3.foundation".to_string());
+
+    let finalized = Arc::new(Mutex::new(0));
+    let client = ScriptedClient {
+        blocks: vec![
+            vec![IdentityEvent::JudgementRequested {
+                who: who,
+                registrar_index: 0,
+            }],
+            vec![IdentityEvent::IdentitySet { who: who }],
+            vec![IdentityEvent::IdentitySet { who: who }],
+        ],
+        finalized: Arc::clone(&finalized),
+        registration: pending_registration(),
+    };
+
+    let mut watcher = ChainWatcher::new(client, repo, be.store(), &network);
+    watcher.scan().await.unwrap();
+    *finalized.lock() = 2;
+    watcher.scan().await.unwrap();
+
+    // The identity did not change, so the challenges remain.
+    let events = be.get_events(VerifierAggregateId).await;
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0].body, EventType::IdentityInserted(_)));
+}
+
+#[tokio::test]
+async fn process_missed_blocks() {
+    let be = InMemBackend::run().await;
+
+    let mut network = network();
+    network.registrar.email = Some("registrar@web3.foundation".to_string());
+    network.registrar.matrix = Some("@registrar:web3.foundation".to_string());
+
+    let finalized = Arc::new(Mutex::new(0));
+    let watcher = |finalized: &Arc<Mutex<BlockNumber>>| {
+        let (store, network, finalized) = (be.store(), network.clone(), Arc::clone(finalized));
+        async move {
+            let repo =
+                Repository::new_with_snapshot_service(VerifierAggregate::default(), store.clone())
+                    .await
+                    .unwrap();
+
+            let client = ScriptedClient {
+                blocks: vec![
+                    vec![],
+                    vec![IdentityEvent::JudgementRequested {
+                        who: account(ALICE),
+                        registrar_index: 0,
+                    }],
+                    vec![IdentityEvent::JudgementRequested {
+                        who: account(BOB),
+                        registrar_index: 0,
+                    }],
+                ],
+                finalized: finalized,
+                registration: pending_registration(),
+            };
+
+            ChainWatcher::new(client, repo, store, &network)
+        }
+    };
+
+    watcher(&finalized).await.scan().await.unwrap();
+    assert!(be.get_events(VerifierAggregateId).await.is_empty());
+
+    // Both requests were made while the service was down.
+    *finalized.lock() = 2;
+    watcher(&finalized).await.scan().await.unwrap();
+
+    let inserted: Vec<NetworkAddress> = be
+        .get_events(VerifierAggregateId)
+        .await
+        .into_iter()
+        .map(|event| match event.body {
+            EventType::IdentityInserted(inserted) => inserted.identity.net_address,
+            other => panic!("unexpected event: {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        inserted,
+        vec![
+            net_address(&polkadot(), &account(ALICE)),
+            net_address(&polkadot(), &account(BOB)),
+        ]
+    );
+
+    // The blocks are not processed again after another restart.
+    watcher(&finalized).await.scan().await.unwrap();
+    assert_eq!(be.get_events(VerifierAggregateId).await.len(), 2);
+}
@@ -0,0 +1,128 @@
+use crate::challenge::{matches, MIN_CHALLENGE_LEN};
+use proptest::prelude::*;
+
+const CHALLENGE: &str = "4d8b1f07e2c94a6b9f3e0c5d7a2b8e1f";
+
+fn challenge() -> impl Strategy<Value = String> {
+    any::<[u8; 16]>().prop_map(hex::encode)
+}
+
+// Text which can surround the challenge without changing its tokens.
+fn surrounding() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9 .,:;!?\n]{0,64}"
+}
+
+#[test]
+fn match_plain_text() {
+    assert!(matches(CHALLENGE, CHALLENGE));
+    assert!(matches(CHALLENGE, &format!("  {}\r\n", CHALLENGE)));
+    assert!(matches(
+        CHALLENGE,
+        &format!("Hi,\n\nhere is my challenge: {}.\n\nBest", CHALLENGE)
+    ));
+    assert!(matches(CHALLENGE, &CHALLENGE.to_uppercase()));
+}
+
+#[test]
+fn match_html_bodies() {
+    let body = format!(
+        "<html><body><div dir=\"ltr\">Challenge:<br>{}</div></body></html>",
+        CHALLENGE
+    );
+    assert!(matches(CHALLENGE, &body));
+
+    let body = format!("<p>Challenge:&nbsp;<b>{}</b></p>", CHALLENGE);
+    assert!(matches(CHALLENGE, &body));
+
+    // Markup is removed before the search, so attributes do not count.
+    let body = format!(
+        "<p><a href=\"https://example.com/{}\">link</a></p>",
+        CHALLENGE
+    );
+    assert!(!matches(CHALLENGE, &body));
+}
+
+#[test]
+fn match_quoted_replies() {
+    let reply = format!(
+        "Done!\n\nOn Mon, 1 Mar 2021, registrar wrote:\n> Please reply with:\n> {}\n",
+        CHALLENGE
+    );
+    assert!(matches(CHALLENGE, &reply));
+
+    let reply = format!(
+        "<div>Done!</div><blockquote><p>&gt; {}</p></blockquote>",
+        CHALLENGE
+    );
+    assert!(matches(CHALLENGE, &reply));
+}
+
+#[test]
+fn reject_partial_messages() {
+    // Previously, any part of the challenge was accepted.
+    assert!(!matches(CHALLENGE, ""));
+    assert!(!matches(CHALLENGE, "4"));
+    assert!(!matches(CHALLENGE, &CHALLENGE[..31]));
+    assert!(!matches(CHALLENGE, &CHALLENGE[1..]));
+
+    // The challenge must be a token on its own.
+    assert!(!matches(CHALLENGE, &format!("0{}", CHALLENGE)));
+    assert!(!matches(CHALLENGE, &format!("{}0", CHALLENGE)));
+
+    // Split challenges are rejected.
+    let split = format!("{} {}", &CHALLENGE[..16], &CHALLENGE[16..]);
+    assert!(!matches(CHALLENGE, &split));
+}
+
+#[test]
+fn reject_short_challenges() {
+    let short = &CHALLENGE[..MIN_CHALLENGE_LEN - 1];
+    assert!(!matches(short, short));
+    assert!(!matches("", ""));
+    assert!(!matches("-- --", "-- --"));
+
+    let long_enough = &CHALLENGE[..MIN_CHALLENGE_LEN];
+    assert!(matches(long_enough, long_enough));
+}
+
+proptest! {
+    #[test]
+    fn match_surrounded_challenge(
+        challenge in challenge(),
+        prefix in surrounding(),
+        suffix in surrounding(),
+    ) {
+        let text = format!("{} {} {}", prefix, challenge, suffix);
+        prop_assert!(matches(&challenge, &text));
+
+        let html = format!("<p>{}</p><p>{}</p>{}<br>", prefix, challenge, suffix);
+        prop_assert!(matches(&challenge, &html));
+
+        let quoted = format!("{}\n> {}\n> {}", prefix, challenge, suffix);
+        prop_assert!(matches(&challenge, &quoted));
+    }
+
+    #[test]
+    fn reject_substrings(challenge in challenge(), start in 0..32usize, len in 0..32usize) {
+        let end = (start + len).min(31);
+        let part = &challenge[start.min(end)..end];
+        prop_assert!(!matches(&challenge, part));
+    }
+
+    #[test]
+    fn reject_modified_challenge(challenge in challenge(), idx in 0..32usize, c in "[g-z]") {
+        let mut modified = challenge.clone();
+        modified.replace_range(idx..idx + 1, &c);
+        prop_assert!(!matches(&challenge, &modified));
+    }
+
+    #[test]
+    fn reject_arbitrary_text(challenge in challenge(), text in ".*") {
+        prop_assert!(!matches(&challenge, &text));
+    }
+
+    #[test]
+    fn never_panic(challenge in ".*", text in ".*") {
+        let _ = matches(&challenge, &text);
+    }
+}
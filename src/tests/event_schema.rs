@@ -0,0 +1,112 @@
+use crate::event::{upcast, Event, EventType, ExternalOrigin, JudgementKind, EVENT_SCHEMA_VERSION};
+use crate::store::{EventData, RecordedEvent};
+use serde_json::Value;
+use std::convert::TryFrom;
+
+/// Events as they were written before the schema version was introduced.
+const EVENTS_V0: &str = include_str!("fixtures/events_v0.json");
+
+fn fixtures() -> Vec<Value> {
+    serde_json::from_str(EVENTS_V0).unwrap()
+}
+
+fn recorded(revision: u64, event_type: &str, data: Value) -> RecordedEvent {
+    RecordedEvent {
+        stream: "identity_state_changes".to_string(),
+        revision: revision,
+        event_type: event_type.to_string(),
+        data: data,
+    }
+}
+
+#[test]
+fn read_legacy_events() {
+    for (revision, data) in fixtures().into_iter().enumerate() {
+        let event =
+            Event::try_from(recorded(revision as u64, "registrar-event", data.clone())).unwrap();
+
+        // Nothing is lost, apart from the added schema version and the kind of
+        // the given judgement.
+        let mut expected = data;
+        expected["header"]["version"] = Value::from(EVENT_SCHEMA_VERSION);
+        if expected["body"]["type"] == "judgement_given" {
+            expected["body"]["content"]["judgement"] = Value::from("reasonable");
+        }
+        assert_eq!(serde_json::to_value(&event).unwrap(), expected);
+
+        // The variant name is used as the event type.
+        let written = EventData::try_from(event.clone()).unwrap();
+        assert_eq!(written.event_type, expected["body"]["type"]);
+        assert_eq!(written.event_type, event.body.name());
+    }
+}
+
+#[test]
+fn read_legacy_event_content() {
+    let events: Vec<Event> = fixtures()
+        .into_iter()
+        .enumerate()
+        .map(|(revision, data)| {
+            Event::try_from(recorded(revision as u64, "registrar-event", data)).unwrap()
+        })
+        .collect();
+
+    match &events[1].body {
+        EventType::ExternalMessage(message) => {
+            assert_eq!(message.origin, ExternalOrigin::Email);
+            assert_eq!(message.field_address, "alice@email.com".to_string().into());
+        }
+        _ => panic!("unexpected event: {:?}", events[1]),
+    }
+
+    match &events[6].body {
+        EventType::JudgementGiven(given) => {
+            assert_eq!(given.judgement, JudgementKind::Reasonable)
+        }
+        _ => panic!("unexpected event: {:?}", events[6]),
+    }
+
+    match &events[7].body {
+        EventType::ExportedIdentityState(state) => assert_eq!(state.len(), 1),
+        _ => panic!("unexpected event: {:?}", events[7]),
+    }
+}
+
+#[test]
+fn current_events_roundtrip() {
+    for data in fixtures() {
+        let event = Event::try_from(recorded(0, "registrar-event", data)).unwrap();
+
+        let written = EventData::try_from(event.clone()).unwrap();
+        let read = Event::try_from(recorded(0, &written.event_type, written.data)).unwrap();
+        assert_eq!(read, event);
+    }
+}
+
+#[test]
+fn reject_unknown_schema_version() {
+    let mut data = fixtures().remove(0);
+    data["header"]["version"] = Value::from(EVENT_SCHEMA_VERSION + 1);
+
+    assert!(upcast(data.clone()).is_err());
+    assert!(Event::try_from(recorded(0, "identity_inserted", data)).is_err());
+}
+
+#[test]
+fn upcast_judgement_kind() {
+    let mut data = fixtures().remove(6);
+    data["header"]["version"] = Value::from(1);
+
+    let upcasted = upcast(data.clone()).unwrap();
+    assert_eq!(upcasted["body"]["content"]["judgement"], "reasonable");
+
+    // The kind recorded by the current version is kept.
+    data["header"]["version"] = Value::from(EVENT_SCHEMA_VERSION);
+    data["body"]["content"]["judgement"] = Value::from("erroneous");
+
+    let event = Event::try_from(recorded(0, "judgement_given", data)).unwrap();
+    match event.body {
+        EventType::JudgementGiven(given) => assert_eq!(given.judgement, JudgementKind::Erroneous),
+        _ => panic!("unexpected event: {:?}", event),
+    }
+}
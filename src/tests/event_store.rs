@@ -0,0 +1,265 @@
+use super::gen_port;
+use crate::store::{
+    EmbeddedStore, EventData, EventStore, ExpectedRevision, InMemoryStore, RecordedEvent,
+    WrongExpectedRevision,
+};
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use tokio::time::{self, Duration};
+
+fn event(n: u64) -> EventData {
+    EventData::json("test-event", n).unwrap()
+}
+
+fn numbers(events: &[RecordedEvent]) -> Vec<u64> {
+    events
+        .iter()
+        .map(|recorded| recorded.as_json::<u64>().unwrap())
+        .collect()
+}
+
+fn revisions(events: &[RecordedEvent]) -> Vec<u64> {
+    events.iter().map(|recorded| recorded.revision).collect()
+}
+
+fn embedded_store() -> Arc<dyn EventStore> {
+    let path = std::env::temp_dir().join(format!("registrar-test-{}", gen_port()));
+    let _ = std::fs::remove_dir_all(&path);
+    Arc::new(EmbeddedStore::open(path).unwrap())
+}
+
+/// Runs the common checks against any `EventStore` implementation.
+async fn append_and_read(store: Arc<dyn EventStore>) {
+    // Unknown streams are empty.
+    assert!(store.read_from_end("alice", 10).await.unwrap().is_empty());
+
+    // Revisions are assigned per stream.
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::Any, vec![event(0), event(1)])
+            .await
+            .unwrap()
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::Any, vec![event(2)])
+            .await
+            .unwrap()
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        store
+            .append("bob", ExpectedRevision::Any, vec![event(10)])
+            .await
+            .unwrap()
+            .unwrap(),
+        0
+    );
+
+    // Read from end.
+    let events = store.read_from_end("alice", 2).await.unwrap();
+    assert_eq!(numbers(&events), vec![2, 1]);
+    assert_eq!(revisions(&events), vec![2, 1]);
+    assert!(events.iter().all(|recorded| recorded.stream == "alice"));
+
+    // Read forward.
+    let events: Vec<RecordedEvent> = store
+        .read_from("alice", 1)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(numbers(&events), vec![1, 2]);
+
+    let events: Vec<RecordedEvent> = store
+        .read_from("unknown", 0)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert!(events.is_empty());
+}
+
+async fn expected_revision(store: Arc<dyn EventStore>) {
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::NoStream, vec![event(0)])
+            .await
+            .unwrap(),
+        Ok(0)
+    );
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::Exact(0), vec![event(1)])
+            .await
+            .unwrap(),
+        Ok(1)
+    );
+
+    // Stale revisions are rejected and nothing is written.
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::Exact(0), vec![event(2)])
+            .await
+            .unwrap(),
+        Err(WrongExpectedRevision {
+            stream: "alice".to_string(),
+            expected: ExpectedRevision::Exact(0),
+            current: Some(1),
+        })
+    );
+    assert!(store
+        .append("alice", ExpectedRevision::NoStream, vec![event(2)])
+        .await
+        .unwrap()
+        .is_err());
+
+    let events = store.read_from_end("alice", 10).await.unwrap();
+    assert_eq!(numbers(&events), vec![1, 0]);
+}
+
+async fn subscribe(store: Arc<dyn EventStore>) {
+    store
+        .append("alice", ExpectedRevision::Any, vec![event(0), event(1)])
+        .await
+        .unwrap()
+        .unwrap();
+
+    let from_start = store.subscribe_from("alice", None).await.unwrap();
+    let after_first = store.subscribe_from("alice", Some(0)).await.unwrap();
+
+    // New events are delivered to existing subscribers.
+    store
+        .append("alice", ExpectedRevision::Any, vec![event(2)])
+        .await
+        .unwrap()
+        .unwrap();
+    store
+        .append("bob", ExpectedRevision::Any, vec![event(10)])
+        .await
+        .unwrap()
+        .unwrap();
+
+    let events: Vec<RecordedEvent> = from_start
+        .take_until(time::sleep(Duration::from_secs(1)))
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(revisions(&events), vec![0, 1, 2]);
+    assert_eq!(numbers(&events), vec![0, 1, 2]);
+
+    let events: Vec<RecordedEvent> = after_first
+        .take_until(time::sleep(Duration::from_secs(1)))
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(revisions(&events), vec![1, 2]);
+}
+
+/// Only the latest event of a replaced stream is kept.
+async fn replace(store: Arc<dyn EventStore>) {
+    store
+        .append("alice", ExpectedRevision::Any, vec![event(0), event(1)])
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(store.replace("alice", event(2)).await.unwrap(), 2);
+    assert_eq!(store.replace("alice", event(3)).await.unwrap(), 3);
+
+    let events = store.read_from_end("alice", 10).await.unwrap();
+    assert_eq!(numbers(&events), vec![3]);
+    assert_eq!(revisions(&events), vec![3]);
+
+    let events: Vec<RecordedEvent> = store
+        .read_from("alice", 0)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(revisions(&events), vec![3]);
+
+    // Appending continues after the latest revision.
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::Exact(3), vec![event(4)])
+            .await
+            .unwrap()
+            .unwrap(),
+        4
+    );
+}
+
+#[tokio::test]
+async fn embedded_append_and_read() {
+    append_and_read(embedded_store()).await;
+}
+
+#[tokio::test]
+async fn embedded_expected_revision() {
+    expected_revision(embedded_store()).await;
+}
+
+#[tokio::test]
+async fn embedded_subscribe() {
+    subscribe(embedded_store()).await;
+}
+
+#[tokio::test]
+async fn embedded_replace() {
+    replace(embedded_store()).await;
+}
+
+#[tokio::test]
+async fn embedded_persists_events() {
+    let path = std::env::temp_dir().join(format!("registrar-test-{}", gen_port()));
+    let _ = std::fs::remove_dir_all(&path);
+
+    {
+        let store = EmbeddedStore::open(&path).unwrap();
+        store
+            .append("alice", ExpectedRevision::Any, vec![event(0), event(1)])
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    let store = EmbeddedStore::open(&path).unwrap();
+    assert_eq!(
+        store
+            .append("alice", ExpectedRevision::Any, vec![event(2)])
+            .await
+            .unwrap()
+            .unwrap(),
+        2
+    );
+
+    let events = store.read_from_end("alice", 10).await.unwrap();
+    assert_eq!(numbers(&events), vec![2, 1, 0]);
+}
+
+#[tokio::test]
+async fn in_memory_append_and_read() {
+    append_and_read(Arc::new(InMemoryStore::new())).await;
+}
+
+#[tokio::test]
+async fn in_memory_expected_revision() {
+    expected_revision(Arc::new(InMemoryStore::new())).await;
+}
+
+#[tokio::test]
+async fn in_memory_subscribe() {
+    subscribe(Arc::new(InMemoryStore::new())).await;
+}
+
+#[tokio::test]
+async fn in_memory_replace() {
+    replace(Arc::new(InMemoryStore::new())).await;
+}
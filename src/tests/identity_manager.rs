@@ -0,0 +1,446 @@
+use crate::event::{DisplayNamePersisted, FieldStatusVerified, IdentityInserted, JudgementKind};
+use crate::manager::{
+    AttemptLimits, ChallengeStatus, ExpectedMessage, FieldStatus, IdentityField, IdentityFieldType,
+    IdentityManager, IdentityManagerSnapshot, IdentityState, LimitAction, UpdateChanges,
+    VerificationOutcome,
+};
+use proptest::prelude::*;
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Insert(usize),
+    PersistDisplayName(usize),
+    VerifyMessage(usize, bool),
+    VerifyDisplayName(usize),
+    Lock(usize),
+    SubmitJudgement(usize),
+    Remove(usize),
+}
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+        (0..3usize).prop_map(Operation::Insert),
+        (0..3usize).prop_map(Operation::PersistDisplayName),
+        (0..3usize, any::<bool>()).prop_map(|(idx, valid)| Operation::VerifyMessage(idx, valid)),
+        (0..3usize).prop_map(Operation::VerifyDisplayName),
+        (0..3usize).prop_map(Operation::Lock),
+        (0..3usize).prop_map(Operation::SubmitJudgement),
+        (0..3usize).prop_map(Operation::Remove),
+    ]
+}
+
+fn update(manager: &mut IdentityManager, outcome: VerificationOutcome) {
+    let _ = manager.update_field(FieldStatusVerified {
+        net_address: outcome.net_address,
+        field_status: outcome.field_status,
+        message_revision: None,
+    });
+}
+
+/// Runs the operations on a fresh manager. Invalid operations, such as
+/// verifying a field of an identity which was not inserted yet, are ignored.
+fn run(operations: Vec<Operation>) -> IdentityManager {
+    let identities = [
+        IdentityState::alice(),
+        IdentityState::bob(),
+        IdentityState::eve(),
+    ];
+
+    let mut manager = IdentityManager::default();
+
+    for operation in operations {
+        match operation {
+            Operation::Insert(idx) => manager.insert_identity(IdentityInserted {
+                identity: identities[idx].clone(),
+            }),
+            Operation::PersistDisplayName(idx) => {
+                let identity = &identities[idx];
+                for status in identity.fields.values() {
+                    if let IdentityField::DisplayName(name) = &status.field {
+                        let _ = manager.persist_display_name(DisplayNamePersisted {
+                            net_address: identity.net_address.clone(),
+                            display_name: name.clone(),
+                        });
+                    }
+                }
+            }
+            Operation::VerifyMessage(idx, valid) => {
+                for status in identities[idx].fields.values() {
+                    let expected = match status.challenge() {
+                        ChallengeStatus::ExpectMessage(challenge) => {
+                            challenge.expected_message.clone()
+                        }
+                        ChallengeStatus::BackAndForth(challenge) => {
+                            challenge.expected_message.clone()
+                        }
+                        _ => continue,
+                    };
+
+                    let message = if valid {
+                        expected
+                    } else {
+                        ExpectedMessage::invalid()
+                    };
+
+                    if let Some(outcome) = manager.verify_message(&status.field, &message.into()) {
+                        update(&mut manager, outcome);
+                    }
+                }
+            }
+            Operation::VerifyDisplayName(idx) => {
+                let identity = &identities[idx];
+                for status in identity.fields.values() {
+                    if let IdentityField::DisplayName(name) = &status.field {
+                        if let Ok(Some(outcome)) =
+                            manager.verify_display_name(identity.net_address.clone(), name.clone())
+                        {
+                            update(&mut manager, outcome);
+                        }
+                    }
+                }
+            }
+            Operation::Lock(idx) => {
+                manager.lock_identity(&identities[idx].net_address);
+            }
+            Operation::SubmitJudgement(idx) => {
+                manager.submit_judgement(&identities[idx].net_address, JudgementKind::Reasonable);
+            }
+            Operation::Remove(idx) => {
+                manager.remove_identity(&identities[idx].net_address);
+            }
+        }
+    }
+
+    manager
+}
+
+proptest! {
+    #[test]
+    fn snapshot_restore_roundtrip(operations in prop::collection::vec(operation(), 0..20)) {
+        let manager = run(operations);
+
+        let restored = IdentityManager::restore(manager.snapshot()).unwrap();
+        prop_assert_eq!(&restored, &manager);
+
+        // The snapshot survives the serialization to the event store.
+        let json = serde_json::to_string(&manager.snapshot()).unwrap();
+        let snapshot: IdentityManagerSnapshot = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(&IdentityManager::restore(snapshot).unwrap(), &manager);
+    }
+}
+
+#[test]
+fn snapshot_unsupported_version() {
+    let manager = run(vec![Operation::Insert(0)]);
+    let mut json = serde_json::to_value(&manager.snapshot()).unwrap();
+    json["version"] = serde_json::Value::from(6);
+
+    let snapshot: IdentityManagerSnapshot = serde_json::from_value(json).unwrap();
+    assert!(IdentityManager::restore(snapshot).is_err());
+}
+
+#[test]
+fn snapshot_without_expirations() {
+    let manager = run(vec![Operation::Insert(0), Operation::Insert(1)]);
+    let mut json = serde_json::to_value(&manager.snapshot()).unwrap();
+    json["version"] = serde_json::Value::from(1);
+    json.as_object_mut().unwrap().remove("expirations");
+
+    let snapshot: IdentityManagerSnapshot = serde_json::from_value(json).unwrap();
+    assert_eq!(IdentityManager::restore(snapshot).unwrap(), manager);
+}
+
+#[test]
+fn snapshot_without_failed_attempts() {
+    let manager = run(vec![Operation::Insert(0), Operation::Insert(1)]);
+    let mut json = serde_json::to_value(&manager.snapshot()).unwrap();
+    json["version"] = serde_json::Value::from(2);
+    json.as_object_mut().unwrap().remove("failed_attempts");
+    json.as_object_mut().unwrap().remove("locked");
+    json.as_object_mut().unwrap().remove("submitted");
+    json.as_object_mut().unwrap().remove("message_revision");
+
+    let snapshot: IdentityManagerSnapshot = serde_json::from_value(json).unwrap();
+    assert_eq!(IdentityManager::restore(snapshot).unwrap(), manager);
+}
+
+fn twitter_status(manager: &IdentityManager, identity: &IdentityState) -> FieldStatus {
+    manager
+        .lookup_full_state(&identity.net_address)
+        .unwrap()
+        .fields
+        .remove(&IdentityFieldType::Twitter)
+        .unwrap()
+}
+
+fn twitter_challenge(status: &FieldStatus) -> ExpectedMessage {
+    match status.challenge() {
+        ChallengeStatus::ExpectMessage(challenge) => challenge.expected_message.clone(),
+        _ => panic!("unexpected challenge type"),
+    }
+}
+
+/// Verifies the message for the Twitter field and applies the limits, the
+/// same way as the verifier aggregate does. Returns the changes and whether
+/// the identity was locked.
+fn verify_limited(
+    manager: &mut IdentityManager,
+    identity: &IdentityState,
+    message: ExpectedMessage,
+    limits: &AttemptLimits,
+) -> Option<(UpdateChanges, bool)> {
+    let field = twitter_status(manager, identity).field;
+    let mut outcome = manager.verify_message(&field, &message.into())?;
+    let lock = manager.limit_attempts(&mut outcome, limits);
+
+    let changes = manager
+        .update_field(FieldStatusVerified {
+            net_address: outcome.net_address.clone(),
+            field_status: outcome.field_status,
+            message_revision: None,
+        })
+        .unwrap()?;
+
+    if lock {
+        manager.lock_identity(&outcome.net_address);
+    }
+
+    Some((changes, lock))
+}
+
+#[test]
+fn regenerate_challenge_after_failed_attempts() {
+    let alice = IdentityState::alice();
+    let limits = AttemptLimits {
+        field: 2,
+        action: LimitAction::Regenerate,
+        identity: 0,
+    };
+
+    let mut manager = IdentityManager::default();
+    manager.insert_identity(alice.clone().into());
+
+    let status = twitter_status(&manager, &alice);
+    let challenge = twitter_challenge(&status);
+
+    let (changes, _) =
+        verify_limited(&mut manager, &alice, ExpectedMessage::invalid(), &limits).unwrap();
+    assert_eq!(
+        changes,
+        UpdateChanges::VerificationInvalid(status.field.clone())
+    );
+    assert_eq!(
+        twitter_challenge(&twitter_status(&manager, &alice)),
+        challenge
+    );
+
+    // The second failed attempt replaces the challenge.
+    let (changes, lock) =
+        verify_limited(&mut manager, &alice, ExpectedMessage::invalid(), &limits).unwrap();
+    assert_eq!(
+        changes,
+        UpdateChanges::ChallengeRegenerated(status.field.clone())
+    );
+    assert!(!lock);
+    assert_eq!(
+        manager.field_failed_attempts(&alice.net_address, &status.field),
+        2
+    );
+
+    let regenerated = twitter_challenge(&twitter_status(&manager, &alice));
+    assert_ne!(regenerated, challenge);
+
+    // The previous challenge is no longer accepted, but the new one is.
+    let (changes, _) = verify_limited(&mut manager, &alice, challenge, &limits).unwrap();
+    assert_eq!(
+        changes,
+        UpdateChanges::VerificationInvalid(status.field.clone())
+    );
+
+    let (changes, _) = verify_limited(&mut manager, &alice, regenerated, &limits).unwrap();
+    assert_eq!(
+        changes,
+        UpdateChanges::VerificationValid(status.field.clone())
+    );
+}
+
+#[test]
+fn lock_field_after_failed_attempts() {
+    let alice = IdentityState::alice();
+    let limits = AttemptLimits {
+        field: 2,
+        action: LimitAction::Lock,
+        identity: 0,
+    };
+
+    let mut manager = IdentityManager::default();
+    manager.insert_identity(alice.clone().into());
+
+    let status = twitter_status(&manager, &alice);
+    let challenge = twitter_challenge(&status);
+
+    verify_limited(&mut manager, &alice, ExpectedMessage::invalid(), &limits).unwrap();
+    let (changes, lock) =
+        verify_limited(&mut manager, &alice, ExpectedMessage::invalid(), &limits).unwrap();
+    assert_eq!(changes, UpdateChanges::FieldLocked(status.field.clone()));
+    assert!(!lock);
+    assert!(twitter_status(&manager, &alice).is_locked());
+    assert!(!manager.is_locked(&alice.net_address));
+
+    // Even the valid challenge is no longer accepted.
+    assert!(verify_limited(&mut manager, &alice, challenge, &limits).is_none());
+}
+
+#[test]
+fn lock_identity_after_failed_attempts() {
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+    let limits = AttemptLimits {
+        field: 0,
+        action: LimitAction::Regenerate,
+        identity: 3,
+    };
+
+    let mut manager = IdentityManager::default();
+    manager.insert_identity(alice.clone().into());
+    manager.insert_identity(bob.clone().into());
+
+    let challenge = twitter_challenge(&twitter_status(&manager, &alice));
+
+    for _ in 0..2 {
+        let (_, lock) =
+            verify_limited(&mut manager, &alice, ExpectedMessage::invalid(), &limits).unwrap();
+        assert!(!lock);
+    }
+
+    let (_, lock) =
+        verify_limited(&mut manager, &alice, ExpectedMessage::invalid(), &limits).unwrap();
+    assert!(lock);
+    assert!(manager.is_locked(&alice.net_address));
+    assert_eq!(manager.failed_attempts(&alice.net_address), 3);
+
+    // Messages for the locked identity are no longer verified, while other
+    // identities are unaffected.
+    assert!(verify_limited(&mut manager, &alice, challenge, &limits).is_none());
+    assert!(!manager.is_locked(&bob.net_address));
+
+    let challenge = twitter_challenge(&twitter_status(&manager, &bob));
+    let (changes, _) = verify_limited(&mut manager, &bob, challenge, &limits).unwrap();
+    assert_eq!(
+        changes,
+        UpdateChanges::VerificationValid(twitter_status(&manager, &bob).field)
+    );
+
+    // The removal of the identity resets the lock and the attempts.
+    manager.remove_identity(&alice.net_address);
+    manager.insert_identity(alice.clone().into());
+    assert!(!manager.is_locked(&alice.net_address));
+    assert_eq!(manager.failed_attempts(&alice.net_address), 0);
+}
+
+#[test]
+fn remove_identity() {
+    let alice = IdentityState::alice();
+    let bob = IdentityState::bob();
+
+    let mut expected = IdentityManager::default();
+    expected.insert_identity(bob.clone().into());
+
+    let mut manager = IdentityManager::default();
+    manager.insert_identity(alice.clone().into());
+    manager.insert_identity(bob.clone().into());
+
+    // Removing an identity leaves no traces behind.
+    assert_eq!(
+        manager.remove_identity(&alice.net_address),
+        Some(alice.clone())
+    );
+    assert_eq!(manager, expected);
+    assert!(manager.remove_identity(&alice.net_address).is_none());
+
+    manager.remove_identity(&bob.net_address);
+    assert_eq!(manager, IdentityManager::default());
+
+    // The identity can no longer be verified.
+    for status in alice.fields.values() {
+        if let ChallengeStatus::ExpectMessage(challenge) = status.challenge() {
+            let message = challenge.expected_message.clone().into();
+            assert!(manager.verify_message(&status.field, &message).is_none());
+        }
+    }
+}
+
+#[test]
+fn clear_identity() {
+    let alice = IdentityState::alice();
+    let display_name = alice
+        .fields
+        .values()
+        .find_map(|status| match &status.field {
+            IdentityField::DisplayName(name) => Some(name.clone()),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut manager = IdentityManager::default();
+    manager.insert_identity(alice.clone().into());
+    manager
+        .persist_display_name(DisplayNamePersisted {
+            net_address: alice.net_address.clone(),
+            display_name: display_name,
+        })
+        .unwrap();
+
+    // The persisted display name is removed, too.
+    assert!(manager.clear_identity(&alice.net_address));
+    assert_eq!(manager, IdentityManager::default());
+    assert!(!manager.clear_identity(&alice.net_address));
+}
+
+#[test]
+fn fields_without_challenge_are_not_required() {
+    let matrix = IdentityField::Matrix(FieldAddress::from("@alice:matrix.org".to_string()));
+    let identity = IdentityState::new(
+        NetworkAddress::alice(),
+        vec![
+            FieldStatus::new(matrix.clone(), Some(RegistrarIdentityField::matrix())),
+            FieldStatus::new(
+                IdentityField::LegalName(FieldAddress::from("Alice Doe".to_string())),
+                None,
+            ),
+            FieldStatus::new(
+                IdentityField::Web(FieldAddress::from("alice.com".to_string())),
+                None,
+            ),
+            FieldStatus::new(IdentityField::Image, None),
+            FieldStatus::new(IdentityField::Additional, None),
+        ],
+    );
+    assert!(identity
+        .fields
+        .values()
+        .filter(|status| status.field != matrix)
+        .all(|status| !status.is_required()));
+
+    let mut manager = IdentityManager::default();
+    manager.insert_identity(identity.clone().into());
+    assert!(!manager.is_fully_verified(&identity.net_address).unwrap());
+
+    let challenge = match identity.fields[&IdentityFieldType::Matrix].challenge() {
+        ChallengeStatus::ExpectMessage(challenge) => challenge.expected_message.clone(),
+        _ => panic!(),
+    };
+    let outcome = manager.verify_message(&matrix, &challenge.into()).unwrap();
+    update(&mut manager, outcome);
+
+    // Only the Matrix account is verified by the registrar.
+    assert!(manager.is_fully_verified(&identity.net_address).unwrap());
+
+    // Identities without any verifiable field are never fully verified.
+    let identity = IdentityState::new(
+        NetworkAddress::bob(),
+        vec![FieldStatus::new(IdentityField::Image, None)],
+    );
+    manager.insert_identity(identity.clone().into());
+    assert!(!manager.is_fully_verified(&identity.net_address).unwrap());
+}
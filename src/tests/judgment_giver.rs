@@ -0,0 +1,629 @@
+use super::InMemBackend;
+use crate::aggregate::verifier::{VerifierAggregate, VerifierAggregateId, VerifierCommand};
+use crate::aggregate::Repository;
+use crate::chain::{
+    account_id, AccountId, BlockNumber, ChainClient, Data, IdentityEvent, IdentityInfo, Judgement,
+    JudgementSigner, NotSubmitted, ProvideJudgementCall, RegistrarIndex, Registration,
+};
+use crate::event::{
+    BlankNetwork, Event, EventType, FieldStatusVerified, IdentityFullyVerified, JudgementGiven,
+    JudgementKind, OnChainRemark, RemarkFound,
+};
+use crate::manager::{ChallengeStatus, IdentityFieldType, IdentityState, NetworkAddress, Validity};
+use crate::projection::{JudgmentGiver, Projection};
+use crate::{NetworkConfig, PolicyConfig, PolicyRule, Result};
+use parity_scale_codec::Encode;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::time::Duration;
+
+type Registrations = Arc<Mutex<HashMap<AccountId, Registration>>>;
+
+fn registration(judgement: Judgement) -> Registration {
+    Registration {
+        judgements: vec![(0, judgement)],
+        deposit: 0,
+        info: IdentityInfo {
+            additional: vec![],
+            display: Data::None,
+            legal: Data::None,
+            web: Data::None,
+            riot: Data::None,
+            email: Data::None,
+            pgp_fingerprint: None,
+            image: Data::None,
+            twitter: Data::None,
+        },
+    }
+}
+
+/// Serves the identities, which are updated by the signer.
+struct TestClient {
+    registrations: Registrations,
+}
+
+#[async_trait]
+impl ChainClient for TestClient {
+    async fn finalized_block(&self) -> Result<BlockNumber> {
+        Err(anyhow!("not supported"))
+    }
+    async fn events(&self, _: BlockNumber) -> Result<Vec<IdentityEvent>> {
+        Err(anyhow!("not supported"))
+    }
+    async fn registration(&self, who: &AccountId) -> Result<Option<Registration>> {
+        Ok(self.registrations.lock().get(who).cloned())
+    }
+}
+
+enum Outcome {
+    // The extrinsic was never included.
+    Rejected,
+    // The outcome of the submission is unknown, e.g. because the watcher
+    // timed out. The extrinsic is not finalized yet.
+    Unknown,
+    // The extrinsic was finalized, but the submission was reported as failed
+    // anyway, e.g. because the connection was lost.
+    FinalizedButFailed,
+}
+
+/// Records the submissions and gives the judgement on-chain, unless
+/// specified otherwise by the outcomes.
+#[derive(Clone)]
+struct TestSigner {
+    registrations: Registrations,
+    submissions: Arc<Mutex<Vec<(AccountId, Judgement)>>>,
+    outcomes: Arc<Mutex<VecDeque<Outcome>>>,
+}
+
+#[async_trait]
+impl JudgementSigner for TestSigner {
+    async fn provide_judgement(
+        &self,
+        registrar_index: RegistrarIndex,
+        target: &AccountId,
+        judgement: Judgement,
+    ) -> Result<()> {
+        assert_eq!(registrar_index, 0);
+
+        self.submissions.lock().push((*target, judgement.clone()));
+
+        let outcome = self.outcomes.lock().pop_front();
+        match outcome {
+            Some(Outcome::Rejected) => {
+                return Err(NotSubmitted("extrinsic rejected".to_string()).into())
+            }
+            Some(Outcome::Unknown) => return Err(anyhow!("timed out")),
+            _ => {}
+        }
+
+        self.registrations
+            .lock()
+            .insert(*target, registration(judgement));
+
+        match outcome {
+            Some(Outcome::FinalizedButFailed) => Err(anyhow!("connection lost")),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct Setup {
+    be: InMemBackend,
+    registrations: Registrations,
+    signer: TestSigner,
+    alice: IdentityState,
+    bob: IdentityState,
+}
+
+impl Setup {
+    /// Inserts the identities of Alice and Bob, both with a pending judgement
+    /// request on-chain.
+    async fn new(outcomes: Vec<Outcome>) -> Self {
+        let be = InMemBackend::run().await;
+        let mut repo =
+            Repository::new_with_snapshot_service(VerifierAggregate::default(), be.store())
+                .await
+                .unwrap();
+
+        let (alice, bob) = (IdentityState::alice(), IdentityState::bob());
+        let registrations: Registrations = Default::default();
+
+        for identity in &[&alice, &bob] {
+            repo.apply(VerifierCommand::InsertIdentity((*identity).clone()))
+                .await
+                .unwrap();
+
+            registrations.lock().insert(
+                account_id(&identity.net_address).unwrap(),
+                registration(Judgement::FeePaid(0)),
+            );
+        }
+
+        Setup {
+            be: be,
+            registrations: registrations.clone(),
+            signer: TestSigner {
+                registrations: registrations,
+                submissions: Default::default(),
+                outcomes: Arc::new(Mutex::new(outcomes.into())),
+            },
+            alice: alice,
+            bob: bob,
+        }
+    }
+    async fn giver(&self, dry_run: bool) -> JudgmentGiver {
+        self.giver_with_policy(dry_run, PolicyConfig::default())
+            .await
+    }
+    async fn giver_with_policy(&self, dry_run: bool, policy: PolicyConfig) -> JudgmentGiver {
+        self.giver_on("polkadot", dry_run, policy).await
+    }
+    /// A giver which only judges on the given network.
+    async fn giver_on(&self, network: &str, dry_run: bool, policy: PolicyConfig) -> JudgmentGiver {
+        let repo =
+            Repository::new_with_snapshot_service(VerifierAggregate::default(), self.be.store())
+                .await
+                .unwrap();
+
+        let signer: Option<Box<dyn JudgementSigner>> = if dry_run {
+            None
+        } else {
+            Some(Box::new(self.signer.clone()))
+        };
+
+        let network = NetworkConfig {
+            network: BlankNetwork::from(network),
+            endpoint: "ws://localhost:9944".to_string(),
+            ss58_prefix: None,
+            utility_pallet: None,
+            registrar_index: 0,
+            registrar: Default::default(),
+            signer: None,
+            policy: policy,
+        };
+
+        JudgmentGiver::new(repo).with_network(
+            &network,
+            Box::new(TestClient {
+                registrations: self.registrations.clone(),
+            }),
+            signer,
+        )
+    }
+    fn submissions(&self) -> usize {
+        self.signer.submissions.lock().len()
+    }
+    fn judgements(&self) -> Vec<Judgement> {
+        self.signer
+            .submissions
+            .lock()
+            .iter()
+            .map(|(_, judgement)| judgement.clone())
+            .collect()
+    }
+    /// The judgements recorded as given.
+    async fn given(&self) -> Vec<EventType> {
+        self.be
+            .get_events(VerifierAggregateId)
+            .await
+            .into_iter()
+            .map(|event| event.body)
+            .filter(|body| matches!(body, EventType::JudgementGiven(_)))
+            .collect()
+    }
+    /// Gives the judgement on-chain, as if a submission was finalized.
+    fn finalize(&self, identity: &IdentityState, judgement: Judgement) {
+        self.registrations.lock().insert(
+            account_id(&identity.net_address).unwrap(),
+            registration(judgement),
+        );
+    }
+}
+
+fn verified(identity: &IdentityState) -> Event {
+    Event::from(IdentityFullyVerified {
+        net_address: identity.net_address.clone(),
+        on_chain_challenge: identity.on_chain_challenge.clone(),
+    })
+}
+
+/// The field of the identity failed verification.
+fn failed(identity: &IdentityState, field: IdentityFieldType) -> Event {
+    let mut field_status = identity.fields[&field].clone();
+    match field_status.challenge_mut() {
+        ChallengeStatus::ExpectMessage(challenge) => challenge.status = Validity::Invalid,
+        ChallengeStatus::BackAndForth(challenge) => {
+            challenge.first_check_status = Validity::Invalid
+        }
+        ChallengeStatus::CheckDisplayName(challenge) => challenge.status = Validity::Invalid,
+        ChallengeStatus::Unsupported => panic!("unsupported field: {:?}", field),
+    }
+
+    Event::from(FieldStatusVerified {
+        net_address: identity.net_address.clone(),
+        field_status: field_status,
+        message_revision: None,
+    })
+}
+
+fn given(net_address: &NetworkAddress, judgement: JudgementKind) -> EventType {
+    EventType::JudgementGiven(JudgementGiven {
+        net_address: net_address.clone(),
+        judgement: judgement,
+    })
+}
+
+fn remark(net_address: &NetworkAddress, remark: &str) -> Event {
+    Event::from(RemarkFound {
+        net_address: net_address.clone(),
+        remark: OnChainRemark::from(remark.to_string()),
+        extrinsic: None,
+    })
+}
+
+#[test]
+fn encode_provide_judgement() {
+    let target = account_id(&NetworkAddress::alice()).unwrap();
+    let call = ProvideJudgementCall {
+        registrar_index: 3,
+        target: target,
+        judgement: Judgement::Reasonable,
+    };
+
+    let mut expected = vec![12, 0];
+    expected.extend(&target);
+    expected.push(2);
+
+    assert_eq!(call.encode(), expected);
+}
+
+#[tokio::test]
+async fn give_judgements() {
+    let setup = Setup::new(vec![Outcome::Rejected, Outcome::FinalizedButFailed]).await;
+    let mut giver = setup.giver(false).await;
+    let (alice, bob) = (&setup.alice, &setup.bob);
+
+    // Nothing is submitted until the remark is found, and invalid remarks are
+    // ignored.
+    giver.project(verified(alice)).await.unwrap();
+    giver
+        .project(remark(&alice.net_address, "w3f_registrar:invalid"))
+        .await
+        .unwrap();
+    assert_eq!(setup.submissions(), 0);
+
+    // The first submission fails, the retry succeeds.
+    let alice_remark = remark(&alice.net_address, alice.on_chain_challenge.as_str());
+    assert!(giver.project(alice_remark.clone()).await.is_err());
+    assert_eq!(setup.submissions(), 1);
+    giver.project(alice_remark.clone()).await.unwrap();
+    assert_eq!(setup.submissions(), 2);
+
+    // The judgement is not submitted again.
+    giver.project(alice_remark).await.unwrap();
+    assert_eq!(setup.submissions(), 2);
+
+    // The remark of Bob is found before the identity is verified. The
+    // judgement is given on-chain, but reported as failed. The retry notices
+    // the on-chain judgement and does not submit it again.
+    giver
+        .project(remark(&bob.net_address, bob.on_chain_challenge.as_str()))
+        .await
+        .unwrap();
+    assert!(giver.project(verified(bob)).await.is_err());
+    assert_eq!(setup.submissions(), 3);
+    giver.project(verified(bob)).await.unwrap();
+    assert_eq!(setup.submissions(), 3);
+
+    assert_eq!(
+        setup.given().await,
+        vec![
+            given(&alice.net_address, JudgementKind::Reasonable),
+            given(&bob.net_address, JudgementKind::Reasonable),
+        ]
+    );
+
+    // Both requests are completed.
+    let repo =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), setup.be.store())
+            .await
+            .unwrap();
+    assert!(!repo.state().is_pending(&alice.net_address));
+    assert!(!repo.state().is_pending(&bob.net_address));
+
+    // A new instance, which replays all events, does not submit anything.
+    let mut giver = setup.giver(false).await;
+    giver.project(verified(alice)).await.unwrap();
+    giver
+        .project(remark(
+            &alice.net_address,
+            alice.on_chain_challenge.as_str(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(setup.submissions(), 3);
+}
+
+#[tokio::test]
+async fn ignore_other_networks() {
+    let setup = Setup::new(vec![]).await;
+    let alice = &setup.alice;
+
+    // The identity of Alice is judged by the Polkadot giver.
+    let mut giver = setup
+        .giver_on("kusama", false, PolicyConfig::default())
+        .await;
+    giver.project(verified(alice)).await.unwrap();
+    giver
+        .project(remark(
+            &alice.net_address,
+            alice.on_chain_challenge.as_str(),
+        ))
+        .await
+        .unwrap();
+
+    assert_eq!(setup.submissions(), 0);
+    assert!(setup.given().await.is_empty());
+}
+
+#[tokio::test]
+async fn dry_run_judgements() {
+    let setup = Setup::new(vec![]).await;
+    let mut giver = setup.giver(true).await;
+    let alice = &setup.alice;
+
+    giver.project(verified(alice)).await.unwrap();
+    giver
+        .project(remark(
+            &alice.net_address,
+            alice.on_chain_challenge.as_str(),
+        ))
+        .await
+        .unwrap();
+
+    // Only logged, the request remains pending.
+    assert_eq!(setup.submissions(), 0);
+    assert_eq!(setup.be.get_events(VerifierAggregateId).await.len(), 2);
+
+    let repo =
+        Repository::new_with_snapshot_service(VerifierAggregate::default(), setup.be.store())
+            .await
+            .unwrap();
+    assert!(repo.state().is_pending(&alice.net_address));
+}
+
+#[tokio::test]
+async fn policy_failed_verifications() {
+    let setup = Setup::new(vec![]).await;
+    let mut giver = setup
+        .giver_with_policy(
+            false,
+            PolicyConfig {
+                display_name_violation: Some(JudgementKind::Erroneous),
+                invalid_challenges: Some(PolicyRule {
+                    limit: 2,
+                    judgement: JudgementKind::LowQuality,
+                }),
+                remark_mismatches: None,
+            },
+        )
+        .await;
+    let (alice, bob) = (&setup.alice, &setup.bob);
+
+    // Display name violations close the request immediately.
+    giver
+        .project(failed(alice, IdentityFieldType::DisplayName))
+        .await
+        .unwrap();
+    assert_eq!(setup.judgements(), vec![Judgement::Erroneous]);
+
+    // Further attempts of closed requests are ignored.
+    giver
+        .project(failed(alice, IdentityFieldType::Twitter))
+        .await
+        .unwrap();
+    giver
+        .project(failed(alice, IdentityFieldType::Matrix))
+        .await
+        .unwrap();
+    assert_eq!(setup.submissions(), 1);
+
+    // Invalid challenges are counted up to the limit.
+    giver
+        .project(failed(bob, IdentityFieldType::Twitter))
+        .await
+        .unwrap();
+    assert_eq!(setup.submissions(), 1);
+    giver
+        .project(failed(bob, IdentityFieldType::Email))
+        .await
+        .unwrap();
+    assert_eq!(
+        setup.judgements(),
+        vec![Judgement::Erroneous, Judgement::LowQuality]
+    );
+
+    assert_eq!(
+        setup.given().await,
+        vec![
+            given(&alice.net_address, JudgementKind::Erroneous),
+            given(&bob.net_address, JudgementKind::LowQuality),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn policy_remark_mismatches() {
+    let setup = Setup::new(vec![]).await;
+    let mut giver = setup
+        .giver_with_policy(
+            false,
+            PolicyConfig {
+                display_name_violation: None,
+                invalid_challenges: None,
+                remark_mismatches: Some(PolicyRule {
+                    limit: 2,
+                    judgement: JudgementKind::Erroneous,
+                }),
+            },
+        )
+        .await;
+    let (alice, bob) = (&setup.alice, &setup.bob);
+
+    giver.project(verified(alice)).await.unwrap();
+    giver
+        .project(remark(&alice.net_address, "w3f_registrar:invalid"))
+        .await
+        .unwrap();
+    assert_eq!(setup.submissions(), 0);
+    giver
+        .project(remark(&alice.net_address, "w3f_registrar:invalid"))
+        .await
+        .unwrap();
+    assert_eq!(setup.judgements(), vec![Judgement::Erroneous]);
+
+    // A single mismatch, found before the identity is verified, does not
+    // prevent the valid judgement.
+    giver
+        .project(remark(&bob.net_address, "w3f_registrar:invalid"))
+        .await
+        .unwrap();
+    giver.project(verified(bob)).await.unwrap();
+    giver
+        .project(remark(&bob.net_address, bob.on_chain_challenge.as_str()))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        setup.given().await,
+        vec![
+            given(&alice.net_address, JudgementKind::Erroneous),
+            given(&bob.net_address, JudgementKind::Reasonable),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unknown_submissions_are_not_repeated() {
+    let setup = Setup::new(vec![Outcome::Unknown, Outcome::Unknown]).await;
+    let mut giver = setup.giver(false).await;
+    let (alice, bob) = (&setup.alice, &setup.bob);
+
+    let alice_remark = remark(&alice.net_address, alice.on_chain_challenge.as_str());
+    giver.project(verified(alice)).await.unwrap();
+    assert!(giver.project(alice_remark.clone()).await.is_err());
+    assert_eq!(setup.submissions(), 1);
+
+    // The submission might still be included, so it is not repeated, not
+    // even after a restart.
+    giver.project(alice_remark.clone()).await.unwrap();
+    let mut giver = setup.giver(false).await;
+    giver.project(verified(alice)).await.unwrap();
+    giver.project(alice_remark.clone()).await.unwrap();
+    giver.reconcile().await.unwrap();
+    assert_eq!(setup.submissions(), 1);
+    assert!(setup.given().await.is_empty());
+
+    // The judgement is confirmed once it shows up on-chain.
+    setup.finalize(alice, Judgement::Reasonable);
+    giver.project(alice_remark).await.unwrap();
+    assert_eq!(setup.submissions(), 1);
+    assert_eq!(
+        setup.given().await,
+        vec![given(&alice.net_address, JudgementKind::Reasonable)]
+    );
+
+    // The judgement request is withdrawn while the outcome is unknown.
+    let bob_remark = remark(&bob.net_address, bob.on_chain_challenge.as_str());
+    giver.project(verified(bob)).await.unwrap();
+    assert!(giver.project(bob_remark.clone()).await.is_err());
+    setup
+        .registrations
+        .lock()
+        .remove(&account_id(&bob.net_address).unwrap());
+    giver.project(bob_remark).await.unwrap();
+    assert_eq!(setup.submissions(), 2);
+    assert_eq!(setup.given().await.len(), 1);
+}
+
+#[tokio::test]
+async fn restore_pending_identities() {
+    let setup = Setup::new(vec![]).await;
+    let mut giver = setup.giver(false).await;
+    let (alice, bob) = (&setup.alice, &setup.bob);
+
+    // Alice was verified and the remark of Bob was found before the
+    // checkpoint. Nothing is submitted while restoring.
+    giver.restore(verified(alice)).unwrap();
+    giver
+        .restore(remark(&bob.net_address, bob.on_chain_challenge.as_str()))
+        .unwrap();
+    assert_eq!(setup.submissions(), 0);
+
+    giver
+        .project(remark(
+            &alice.net_address,
+            alice.on_chain_challenge.as_str(),
+        ))
+        .await
+        .unwrap();
+    giver.project(verified(bob)).await.unwrap();
+
+    assert_eq!(
+        setup.given().await,
+        vec![
+            given(&alice.net_address, JudgementKind::Reasonable),
+            given(&bob.net_address, JudgementKind::Reasonable),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn reconcile_submitted_judgements() {
+    let setup = Setup::new(vec![Outcome::Unknown, Outcome::Unknown]).await;
+    let mut giver = setup.giver(false).await;
+    let (alice, bob) = (&setup.alice, &setup.bob);
+
+    for identity in &[alice, bob] {
+        giver.project(verified(identity)).await.unwrap();
+        assert!(giver
+            .project(remark(
+                &identity.net_address,
+                identity.on_chain_challenge.as_str()
+            ))
+            .await
+            .is_err());
+    }
+    assert_eq!(setup.submissions(), 2);
+
+    // The judgement of Alice shows up on-chain and is confirmed without any
+    // further event.
+    setup.finalize(alice, Judgement::Reasonable);
+    giver.reconcile().await.unwrap();
+    assert_eq!(setup.submissions(), 2);
+    assert_eq!(
+        setup.given().await,
+        vec![given(&alice.net_address, JudgementKind::Reasonable)]
+    );
+
+    // The judgement of Bob got lost, it is submitted again once the
+    // submission timed out.
+    let mut giver = setup
+        .giver(false)
+        .await
+        .set_submission_timeout(Duration::from_secs(0));
+    giver.reconcile().await.unwrap();
+    assert_eq!(setup.submissions(), 3);
+    assert_eq!(
+        setup.given().await,
+        vec![
+            given(&alice.net_address, JudgementKind::Reasonable),
+            given(&bob.net_address, JudgementKind::Reasonable),
+        ]
+    );
+
+    // Nothing is left to reconcile.
+    giver.reconcile().await.unwrap();
+    assert_eq!(setup.submissions(), 3);
+}
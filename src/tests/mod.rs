@@ -1,4 +1,6 @@
+use crate::chain::ChainSpec;
 use crate::event::Event;
+use crate::store::{EventStore, InMemoryStore};
 use crate::system::run_rpc_api_service_blocking;
 use crate::{
     aggregate::{
@@ -9,7 +11,6 @@ use crate::{
     manager::{ChallengeStatus, ExpectedMessage, IdentityFieldType, IdentityState, Validity},
 };
 use crate::{api::ConnectionPool, manager::IdentityManager};
-use eventstore::Client;
 use futures::{future::Join, FutureExt, Stream, StreamExt};
 use hmac::digest::generic_array::typenum::Exp;
 use jsonrpc_client_transports::transports::ws::connect;
@@ -19,16 +20,29 @@ use jsonrpc_ws_server::Server as WsServer;
 use lock_api::RwLock;
 use rand::{thread_rng, Rng};
 use std::convert::TryFrom;
-use std::fs::canonicalize;
-use std::process::Stdio;
 use std::sync::Arc;
-use tokio::process::{Child, Command};
 use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 
 mod aggregate_verifier;
+mod chain_watcher;
+mod challenge;
+mod event_schema;
+mod event_store;
+mod identity_manager;
+mod judgment_giver;
+mod projector;
+mod remark_watcher;
 mod rpc_api_service;
 
+fn polkadot() -> ChainSpec {
+    ChainSpec::known("polkadot").unwrap()
+}
+
+fn kusama() -> ChainSpec {
+    ChainSpec::known("kusama").unwrap()
+}
+
 /// Generates (kind of) random events. Primarily used for manual testing in
 /// order to see whether the front end can process new messages and display
 /// notifications.
@@ -204,10 +218,13 @@ impl ApiClient {
     }
 }
 
+// The cooldown between the challenge regenerations of an identity.
+const REGENERATE_COOLDOWN: Duration = Duration::from_secs(60);
+
 struct ApiBackend;
 
 impl ApiBackend {
-    async fn run(store: Client) -> usize {
+    async fn run(store: Arc<dyn EventStore>) -> usize {
         let rpc_port = gen_port();
         let manager = Arc::new(parking_lot::RwLock::new(IdentityManager::default()));
         tokio::spawn(run_rpc_api_service_blocking(
@@ -215,13 +232,14 @@ impl ApiBackend {
             rpc_port,
             store,
             manager,
+            REGENERATE_COOLDOWN,
         ));
 
         rpc_port
     }
     async fn run_fixed_port(
         rpc_port: usize,
-        store: Client,
+        store: Arc<dyn EventStore>,
         manager: Arc<parking_lot::RwLock<IdentityManager>>,
     ) {
         tokio::spawn(run_rpc_api_service_blocking(
@@ -229,56 +247,24 @@ impl ApiBackend {
             rpc_port,
             store,
             manager,
+            REGENERATE_COOLDOWN,
         ));
     }
 }
 
+/// Hermetic test backend, keeping all events in memory.
 struct InMemBackend {
-    store: Client,
-    port: usize,
-    _handle: Child,
+    store: Arc<dyn EventStore>,
 }
 
 impl InMemBackend {
     async fn run() -> Self {
-        // Configure and spawn the event store in a background process.
-        let port: usize = gen_port();
-        let handle = Command::new(canonicalize("/usr/bin/eventstored").unwrap())
-            .arg("--mem-db")
-            .arg("--disable-admin-ui")
-            .arg("--insecure")
-            .arg("--http-port")
-            .arg(port.to_string())
-            .arg("--int-tcp-port")
-            .arg((port + 1).to_string())
-            .arg("--ext-tcp-port")
-            .arg((port + 2).to_string())
-            .kill_on_drop(true)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .stdin(Stdio::null())
-            .spawn()
-            .unwrap();
-
-        tokio::time::sleep(Duration::from_secs(2)).await;
-
-        // Build store.
-        let store = Client::create(
-            format!("esdb://localhost:{}?tls=false", port)
-                .parse()
-                .unwrap(),
-        )
-        .await
-        .unwrap();
-
         InMemBackend {
-            store: store,
-            port: port,
-            _handle: handle,
+            store: Arc::new(InMemoryStore::new()),
         }
     }
-    fn store(&self) -> Client {
-        self.store.clone()
+    fn store(&self) -> Arc<dyn EventStore> {
+        Arc::clone(&self.store)
     }
     /*
     async fn run_session_notifier(&self, pool: ConnectionPool) {
@@ -288,29 +274,16 @@ impl InMemBackend {
         });
     }
     */
-    async fn subscription<Id>(&self, id: Id) -> impl Stream<Item = Event>
+    /// Returns all events currently in the stream.
+    async fn get_events<Id>(&self, id: Id) -> Vec<Event>
     where
         Id: Send + Sync + Eq + AsRef<str> + Default,
     {
         self.store
-            .subscribe_to_stream_from(Id::default())
-            .execute_event_appeared_only()
+            .read_from(id.as_ref(), 0)
             .await
             .unwrap()
-            .then(|resolved| async {
-                match resolved.unwrap().event {
-                    Some(recorded) => Event::try_from(recorded).unwrap(),
-                    _ => panic!(),
-                }
-            })
-    }
-    async fn get_events<Id>(&self, id: Id) -> Vec<Event>
-    where
-        Id: Send + Sync + Eq + AsRef<str> + Default,
-    {
-        self.subscription(id)
-            .await
-            .take_until(time::sleep(Duration::from_secs(2)))
+            .map(|recorded| Event::try_from(recorded.unwrap()).unwrap())
             .collect()
             .await
     }
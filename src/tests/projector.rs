@@ -0,0 +1,440 @@
+use super::InMemBackend;
+use crate::aggregate::{MessageWatcher, MessageWatcherCommand, MessageWatcherId, Repository};
+use crate::event::{Event, ExternalMessage, ExternalOrigin};
+use crate::manager::ExpectedMessage;
+use crate::projection::{DeadLetter, Projection, Projector, Selection, DEAD_LETTER_STREAM};
+use crate::store::{EventData, EventStore, ExpectedRevision, RecordedEvent};
+use crate::{ProjectionConfig, Result};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{self, Duration};
+
+#[derive(Default)]
+struct Counter {
+    count: usize,
+}
+
+#[async_trait]
+impl Projection for Counter {
+    type Event = Event;
+    type Error = anyhow::Error;
+
+    fn selection(&self) -> Selection {
+        Selection::stream(MessageWatcherId)
+    }
+
+    async fn project(&mut self, _event: Self::Event) -> Result<()> {
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Fails to process the second event `failures` times in a row. With a maximum
+/// of two retries, three failures exhaust all attempts.
+struct Flaky {
+    failures: usize,
+    seen: usize,
+    processed: usize,
+}
+
+#[async_trait]
+impl Projection for Flaky {
+    type Event = Event;
+    type Error = anyhow::Error;
+
+    fn selection(&self) -> Selection {
+        Selection::stream(MessageWatcherId)
+    }
+
+    async fn project(&mut self, _event: Self::Event) -> Result<()> {
+        if self.seen == 1 && self.failures > 0 {
+            self.failures -= 1;
+            return Err(anyhow!("failed to process event"));
+        }
+
+        self.seen += 1;
+        self.processed += 1;
+        Ok(())
+    }
+}
+
+/// Records the stream and revision of each event. Events of the type
+/// `poison` fail to be processed.
+struct Recorder {
+    selection: Selection,
+    events: Vec<(String, u64)>,
+    restored: Vec<(String, u64)>,
+}
+
+#[async_trait]
+impl Projection for Recorder {
+    type Event = RecordedEvent;
+    type Error = anyhow::Error;
+
+    fn selection(&self) -> Selection {
+        self.selection.clone()
+    }
+    async fn project(&mut self, event: Self::Event) -> Result<()> {
+        if event.event_type == "poison" {
+            return Err(anyhow!("failed to process event"));
+        }
+
+        self.events.push((event.stream, event.revision));
+        Ok(())
+    }
+    fn restore(&mut self, event: Self::Event) -> Result<()> {
+        if event.event_type == "poison" {
+            return Err(anyhow!("failed to restore event"));
+        }
+
+        self.restored.push((event.stream, event.revision));
+        Ok(())
+    }
+}
+
+/// Persists the checkpoint after two events, or once the projection is idle
+/// for a short time. Failed events are skipped without retries.
+fn checkpoint_config() -> ProjectionConfig {
+    ProjectionConfig {
+        max_retries: 0,
+        checkpoint_every: 2,
+        checkpoint_interval: 50,
+        ..ProjectionConfig::default()
+    }
+}
+
+async fn add_messages(store: Arc<dyn EventStore>, count: usize) {
+    let mut repo = Repository::new_with_snapshot_service(MessageWatcher, store)
+        .await
+        .unwrap();
+
+    for _ in 0..count {
+        repo.apply(MessageWatcherCommand::AddMessage(ExternalMessage {
+            origin: ExternalOrigin::Matrix,
+            field_address: "@alice:matrix.org".to_string().into(),
+            message: ExpectedMessage::gen().into(),
+        }))
+        .await
+        .unwrap();
+    }
+}
+
+/// Runs the projector for a short time and returns the number of processed
+/// events.
+async fn count_events(store: Arc<dyn EventStore>, rebuild: bool) -> usize {
+    let counter = Arc::new(RwLock::new(Counter::default()));
+    let projector = Projector::new(Arc::clone(&counter), store)
+        .with_config(checkpoint_config())
+        .with_checkpoint("counter")
+        .rebuild(rebuild);
+
+    let _ = time::timeout(Duration::from_millis(500), projector.run_blocking()).await;
+
+    let count = counter.read().await.count;
+    count
+}
+
+#[tokio::test]
+async fn resume_from_checkpoint() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    add_messages(store.clone(), 3).await;
+    assert_eq!(count_events(store.clone(), false).await, 3);
+
+    // Only new events are processed after a restart.
+    add_messages(store.clone(), 2).await;
+    assert_eq!(count_events(store.clone(), false).await, 2);
+    assert_eq!(count_events(store.clone(), false).await, 0);
+
+    // Force a full rebuild.
+    assert_eq!(count_events(store.clone(), true).await, 5);
+    assert_eq!(count_events(store, false).await, 0);
+}
+
+#[tokio::test]
+async fn checkpoint_periodically() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    add_messages(store.clone(), 5).await;
+    assert_eq!(count_events(store.clone(), false).await, 5);
+
+    // Persisted after the second and fourth event, the last event is
+    // persisted once the projection is idle. Only the latest checkpoint is
+    // kept.
+    let checkpoints = store
+        .read_from_end("counter_checkpoints", 10)
+        .await
+        .unwrap();
+    assert_eq!(checkpoints.len(), 1);
+    assert_eq!(checkpoints[0].revision, 2);
+}
+
+/// Runs the flaky projection for a short time and returns the number of
+/// processed events.
+async fn run_flaky(store: Arc<dyn EventStore>, failures: usize, skip_failed: bool) -> usize {
+    let flaky = Arc::new(RwLock::new(Flaky {
+        failures: failures,
+        seen: 0,
+        processed: 0,
+    }));
+
+    let projector = Projector::new(Arc::clone(&flaky), store).with_config(ProjectionConfig {
+        max_retries: 2,
+        initial_backoff: 10,
+        max_backoff: 20,
+        skip_failed: skip_failed,
+        ..ProjectionConfig::default()
+    });
+
+    let _ = time::timeout(Duration::from_millis(500), projector.run_blocking()).await;
+
+    let processed = flaky.read().await.processed;
+    processed
+}
+
+#[tokio::test]
+async fn retry_failed_event() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    add_messages(store.clone(), 3).await;
+
+    // The event succeeds on the last retry.
+    assert_eq!(run_flaky(store.clone(), 2, true).await, 3);
+    assert!(store
+        .read_from_end(DEAD_LETTER_STREAM, 1)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn skip_poison_event() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    add_messages(store.clone(), 3).await;
+
+    // The poison event is skipped, the remaining events are processed.
+    assert_eq!(run_flaky(store.clone(), 3, true).await, 2);
+
+    let dead_letters = store.read_from_end(DEAD_LETTER_STREAM, 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+
+    let dead_letter = dead_letters[0].as_json::<DeadLetter>().unwrap();
+    assert_eq!(dead_letter.stream, MessageWatcherId.as_ref());
+    assert_eq!(dead_letter.revision, 1);
+    assert!(dead_letter.skipped);
+}
+
+#[tokio::test]
+async fn stop_on_poison_event() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    add_messages(store.clone(), 3).await;
+
+    // The projection stops at the poison event.
+    assert_eq!(run_flaky(store.clone(), 3, false).await, 1);
+
+    let dead_letters = store.read_from_end(DEAD_LETTER_STREAM, 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert!(!dead_letters[0].as_json::<DeadLetter>().unwrap().skipped);
+
+    // The restarted projection fails at the same event, which is not
+    // recorded again.
+    assert_eq!(run_flaky(store.clone(), 3, false).await, 1);
+    let dead_letters = store.read_from_end(DEAD_LETTER_STREAM, 10).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(
+        dead_letters[0].as_json::<DeadLetter>().unwrap().projection,
+        MessageWatcherId.as_ref()
+    );
+}
+
+async fn append(store: &Arc<dyn EventStore>, stream: &str, event_type: &str) {
+    store
+        .append(
+            stream,
+            ExpectedRevision::Any,
+            vec![EventData::json(event_type, ()).unwrap()],
+        )
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+/// Runs the recorder for a short time and returns the recorded events, sorted.
+async fn record_events(store: Arc<dyn EventStore>, selection: Selection) -> Vec<(String, u64)> {
+    run_recorder(store, selection, false).await.0
+}
+
+/// Runs the recorder for a short time and returns the projected and the
+/// restored events, sorted.
+async fn run_recorder(
+    store: Arc<dyn EventStore>,
+    selection: Selection,
+    restore: bool,
+) -> (Vec<(String, u64)>, Vec<(String, u64)>) {
+    let recorder = Arc::new(RwLock::new(Recorder {
+        selection: selection,
+        events: vec![],
+        restored: vec![],
+    }));
+
+    let mut projector = Projector::new(Arc::clone(&recorder), store)
+        .with_config(checkpoint_config())
+        .with_checkpoint("recorder");
+    if restore {
+        projector = projector.restore_state();
+    }
+
+    let _ = time::timeout(Duration::from_millis(500), projector.run_blocking()).await;
+
+    let recorder = recorder.read().await;
+    let (mut events, mut restored) = (recorder.events.clone(), recorder.restored.clone());
+    events.sort();
+    restored.sort();
+    (events, restored)
+}
+
+fn entry(stream: &str, revision: u64) -> (String, u64) {
+    (stream.to_string(), revision)
+}
+
+#[tokio::test]
+async fn project_multiple_streams() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+    let selection = Selection::stream("alice").and_stream("bob");
+
+    append(&store, "alice", "test-event").await;
+    append(&store, "bob", "test-event").await;
+    append(&store, "bob", "test-event").await;
+    append(&store, "eve", "test-event").await;
+
+    assert_eq!(
+        record_events(store.clone(), selection.clone()).await,
+        vec![entry("alice", 0), entry("bob", 0), entry("bob", 1)]
+    );
+
+    // Each stream resumes from its own checkpoint.
+    append(&store, "alice", "test-event").await;
+    assert_eq!(
+        record_events(store.clone(), selection.clone()).await,
+        vec![entry("alice", 1)]
+    );
+
+    append(&store, "bob", "test-event").await;
+    assert_eq!(record_events(store, selection).await, vec![entry("bob", 2)]);
+}
+
+#[tokio::test]
+async fn filter_event_types() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+    let selection = Selection::stream("alice")
+        .and_stream("bob")
+        .event_types(&["wanted"]);
+
+    append(&store, "alice", "wanted").await;
+    append(&store, "alice", "unwanted").await;
+    append(&store, "bob", "unwanted").await;
+    append(&store, "bob", "wanted").await;
+
+    assert_eq!(
+        record_events(store, selection).await,
+        vec![entry("alice", 0), entry("bob", 1)]
+    );
+}
+
+#[tokio::test]
+async fn restore_state_from_checkpoint() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+    let selection = Selection::stream("alice")
+        .and_stream("bob")
+        .event_types(&["wanted"]);
+
+    // Nothing to restore without a checkpoint.
+    append(&store, "alice", "wanted").await;
+    append(&store, "bob", "unwanted").await;
+    assert_eq!(
+        run_recorder(store.clone(), selection.clone(), true).await,
+        (vec![entry("alice", 0)], vec![])
+    );
+
+    // The events up to the checkpoint are restored, only new events are
+    // projected.
+    append(&store, "alice", "wanted").await;
+    append(&store, "bob", "wanted").await;
+    assert_eq!(
+        run_recorder(store, selection, true).await,
+        (
+            vec![entry("alice", 1), entry("bob", 1)],
+            vec![entry("alice", 0)]
+        )
+    );
+}
+
+#[tokio::test]
+async fn restore_state_skips_poison_event() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+    let selection = Selection::stream("alice");
+
+    // The poison event is skipped and recorded as a dead letter.
+    append(&store, "alice", "wanted").await;
+    append(&store, "alice", "poison").await;
+    append(&store, "alice", "wanted").await;
+    assert_eq!(
+        run_recorder(store.clone(), selection.clone(), true).await,
+        (vec![entry("alice", 0), entry("alice", 2)], vec![])
+    );
+    assert_eq!(
+        store
+            .read_from_end(DEAD_LETTER_STREAM, 10)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // The poison event before the checkpoint does not prevent the restart.
+    append(&store, "alice", "wanted").await;
+    assert_eq!(
+        run_recorder(store, selection, true).await,
+        (
+            vec![entry("alice", 3)],
+            vec![entry("alice", 0), entry("alice", 2)]
+        )
+    );
+}
+
+#[tokio::test]
+async fn replay_skips_poison_event() {
+    let be = InMemBackend::run().await;
+    let store = be.store();
+
+    append(&store, "alice", "wanted").await;
+    append(&store, "alice", "poison").await;
+    append(&store, "alice", "wanted").await;
+
+    let recorder = Arc::new(RwLock::new(Recorder {
+        selection: Selection::stream("alice"),
+        events: vec![],
+        restored: vec![],
+    }));
+
+    // The poison event is logged, the replay continues.
+    Projector::new(Arc::clone(&recorder), store)
+        .replay()
+        .await
+        .unwrap();
+    assert_eq!(
+        recorder.read().await.events,
+        vec![entry("alice", 0), entry("alice", 2)]
+    );
+}
@@ -0,0 +1,201 @@
+use super::{kusama, polkadot, InMemBackend};
+use crate::aggregate::remark::{RemarkWatcher, RemarkWatcherId};
+use crate::aggregate::Repository;
+use crate::chain::{
+    decode_remarks, net_address, AccountId, BlockClient, BlockNumber, ChainSpec, RemarkScanner,
+};
+use crate::event::{EventType, ExtrinsicPosition, OnChainRemark, RemarkFound};
+use crate::store::EventData;
+use crate::Result;
+
+/// The encoded extrinsics of Polkadot blocks, one entry per block, starting at
+/// block zero.
+const REMARK_BLOCKS: &str = include_str!("fixtures/remark_blocks.json");
+
+const ALICE: &str = "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d";
+const BOB: &str = "8eaf04151687736326c9fea17e25fc5287613693c912909cb226aa4794f26a48";
+
+const CHALLENGE_1: &str = "w3f_registrar:0123456789abcdef0123456789abcdef";
+const CHALLENGE_2: &str = "w3f_registrar:fedcba9876543210fedcba9876543210";
+
+// Signed by Alice: `system.remark(CHALLENGE_1)`.
+const ALICE_REMARK: &str = "0x5d028400d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d01000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001b87733665f7265676973747261723a3031323334353637383961626364656630313233343536373839616263646566";
+// Signed by Bob: `utility.batch([system.remark(CHALLENGE_2), balances.transfer,
+// system.remark(CHALLENGE_1)])`.
+const BOB_BATCH: &str = "0xbd0384008eaf04151687736326c9fea17e25fc5287613693c912909cb226aa4794f26a4801000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001a000c0001b87733665f7265676973747261723a6665646362613938373635343332313066656463626139383736353433323130050000d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d040001b87733665f7265676973747261723a3031323334353637383961626364656630313233343536373839616263646566";
+// Unsigned: `timestamp.set`.
+const TIMESTAMP: &str = "0x1c04020002ec0b20";
+
+#[derive(Deserialize)]
+struct Fixture {
+    blocks: Vec<Vec<String>>,
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    hex::decode(hex.trim_start_matches("0x")).unwrap()
+}
+
+fn account(hex: &str) -> AccountId {
+    let mut account = [0; 32];
+    account.copy_from_slice(&decode_hex(hex));
+    account
+}
+
+/// Serves the recorded blocks, up to the given finalized block.
+struct FixtureClient {
+    blocks: Vec<Vec<String>>,
+    finalized: BlockNumber,
+}
+
+impl FixtureClient {
+    fn new(finalized: BlockNumber) -> Self {
+        let fixture: Fixture = serde_json::from_str(REMARK_BLOCKS).unwrap();
+
+        FixtureClient {
+            blocks: fixture.blocks,
+            finalized: finalized,
+        }
+    }
+}
+
+#[async_trait]
+impl BlockClient for FixtureClient {
+    async fn finalized_block(&self) -> Result<BlockNumber> {
+        Ok(self.finalized)
+    }
+    async fn extrinsics(&self, number: BlockNumber) -> Result<Vec<Vec<u8>>> {
+        assert!(number <= self.finalized);
+
+        Ok(self
+            .blocks
+            .get(number as usize)
+            .ok_or(anyhow!("block {} not found in fixture", number))?
+            .iter()
+            .map(|hex| decode_hex(hex))
+            .collect())
+    }
+}
+
+#[test]
+fn decode_signed_remarks() {
+    assert_eq!(
+        decode_remarks(&polkadot(), &decode_hex(ALICE_REMARK)).unwrap(),
+        Some((account(ALICE), vec![CHALLENGE_1.as_bytes().to_vec()]))
+    );
+
+    // Unsigned extrinsics are skipped.
+    assert_eq!(
+        decode_remarks(&polkadot(), &decode_hex(TIMESTAMP)).unwrap(),
+        None
+    );
+
+    // Truncated extrinsics are rejected.
+    let alice_remark = decode_hex(ALICE_REMARK);
+    assert!(decode_remarks(&polkadot(), &alice_remark[..60]).is_err());
+}
+
+#[test]
+fn decode_batched_remarks() {
+    // The remarks after the unknown transfer call cannot be decoded.
+    assert_eq!(
+        decode_remarks(&polkadot(), &decode_hex(BOB_BATCH)).unwrap(),
+        Some((account(BOB), vec![CHALLENGE_2.as_bytes().to_vec()]))
+    );
+
+    // The utility pallet has a different index on Kusama.
+    assert_eq!(
+        decode_remarks(&kusama(), &decode_hex(BOB_BATCH)).unwrap(),
+        None
+    );
+
+    // Batches are not decoded without the index of the utility pallet.
+    let spec = ChainSpec {
+        utility_pallet: None,
+        ..polkadot()
+    };
+    assert_eq!(decode_remarks(&spec, &decode_hex(BOB_BATCH)).unwrap(), None);
+}
+
+#[tokio::test]
+async fn scan_finalized_blocks() {
+    let be = InMemBackend::run().await;
+
+    // Without a checkpoint, the scan starts at the latest finalized block, so
+    // the challenge in block zero is never seen.
+    let repo = Repository::new_with_snapshot_service(RemarkWatcher::default(), be.store())
+        .await
+        .unwrap();
+    let mut scanner = RemarkScanner::new(FixtureClient::new(1), repo, be.store(), polkadot());
+    scanner.scan().await.unwrap();
+
+    let events = be.get_events(RemarkWatcherId).await;
+    assert_eq!(events.len(), 1);
+
+    // The blocks which were finalized in the meantime are processed on
+    // restart.
+    let repo = Repository::new_with_snapshot_service(RemarkWatcher::default(), be.store())
+        .await
+        .unwrap();
+    let mut scanner = RemarkScanner::new(FixtureClient::new(4), repo, be.store(), polkadot());
+    scanner.scan().await.unwrap();
+
+    // Non-challenge remarks and remarks after unknown batch calls are
+    // ignored.
+    let events = be.get_events(RemarkWatcherId).await;
+    let found: Vec<EventType> = events.into_iter().map(|event| event.body).collect();
+    assert_eq!(
+        found,
+        vec![
+            EventType::RemarkFound(RemarkFound {
+                net_address: net_address(&polkadot(), &account(ALICE)),
+                remark: OnChainRemark::from(CHALLENGE_1.to_string()),
+                extrinsic: Some(ExtrinsicPosition { block: 1, index: 1 }),
+            }),
+            EventType::RemarkFound(RemarkFound {
+                net_address: net_address(&polkadot(), &account(BOB)),
+                remark: OnChainRemark::from(CHALLENGE_2.to_string()),
+                extrinsic: Some(ExtrinsicPosition { block: 3, index: 2 }),
+            }),
+        ]
+    );
+
+    // Nothing new was finalized.
+    scanner.scan().await.unwrap();
+    assert_eq!(be.get_events(RemarkWatcherId).await.len(), 2);
+}
+
+#[tokio::test]
+async fn rescan_after_crash() {
+    let be = InMemBackend::run().await;
+
+    let repo = Repository::new_with_snapshot_service(RemarkWatcher::default(), be.store())
+        .await
+        .unwrap();
+    let mut scanner = RemarkScanner::new(FixtureClient::new(0), repo, be.store(), polkadot());
+    scanner.scan().await.unwrap();
+
+    let repo = Repository::new_with_snapshot_service(RemarkWatcher::default(), be.store())
+        .await
+        .unwrap();
+    let mut scanner = RemarkScanner::new(FixtureClient::new(4), repo, be.store(), polkadot());
+    scanner.scan().await.unwrap();
+    assert_eq!(be.get_events(RemarkWatcherId).await.len(), 3);
+
+    // The service crashed before the checkpoint of the scanned blocks was
+    // persisted.
+    be.store()
+        .replace(
+            "remark_watcher_polkadot_checkpoints",
+            EventData::json("registrar-checkpoint", serde_json::json!({ "block": 0 })).unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // The blocks are scanned again, without adding the remarks twice.
+    let repo = Repository::new_with_snapshot_service(RemarkWatcher::default(), be.store())
+        .await
+        .unwrap();
+    let mut scanner = RemarkScanner::new(FixtureClient::new(4), repo, be.store(), polkadot());
+    scanner.scan().await.unwrap();
+    assert_eq!(be.get_events(RemarkWatcherId).await.len(), 3);
+}
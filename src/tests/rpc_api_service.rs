@@ -1,20 +1,28 @@
 use super::{ApiBackend, ApiClient, InMemBackend};
 use crate::aggregate::verifier::{VerifierAggregate, VerifierAggregateId, VerifierCommand};
 use crate::aggregate::Repository;
-use crate::api::ConnectionPool;
-use crate::event::{ErrorMessage, ExternalMessage, ExternalOrigin, IdentityInserted, StateWrapper};
+use crate::api::{regenerate_message, ConnectionPool};
+use crate::chain::Networks;
+use crate::event::{
+    BlankNetwork, ErrorMessage, ExternalMessage, ExternalOrigin, IdentityInserted, StateWrapper,
+};
 use crate::manager::{
-    ChallengeStatus, FieldAddress, IdentityFieldType, IdentityState, ProvidedMessage,
-    UpdateChanges, Validity,
+    ChallengeStatus, FieldAddress, IdentityAddress, IdentityFieldType, IdentityState,
+    NetworkAddress, ProvidedMessage, UpdateChanges, Validity,
 };
+use crate::NetworkConfig;
 use futures::select;
 use futures::{future::FusedFuture, Stream, StreamExt, TryStreamExt};
+use jsonrpc_client_transports::RpcError;
 use jsonrpc_core::types::{to_value, Params, Value};
+use jsonrpc_core::ErrorCode;
 use matrix_sdk::api::r0::account::request_registration_token_via_email;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use substrate_subxt::sp_core::crypto::{Ss58AddressFormat, Ss58Codec};
+use substrate_subxt::sp_core::{sr25519, Pair};
 
 /*
 {"id":1,"jsonrpc":"2.0","method":"account_subscribeStatus"}
@@ -108,6 +116,37 @@ impl TokenV02 {
     }
 }
 
+#[test]
+fn registrar_index_per_network() {
+    let networks = Networks::new(&[NetworkConfig {
+        network: BlankNetwork::from("kusama"),
+        endpoint: "ws://localhost:9945".to_string(),
+        ss58_prefix: None,
+        utility_pallet: None,
+        registrar_index: 3,
+        registrar: Default::default(),
+        signer: None,
+        policy: Default::default(),
+    }]);
+
+    let kusama = NetworkAddress::from(
+        BlankNetwork::from("kusama"),
+        "HNZata7iMYWmk5RvZRTiAsSDhV8366zq2YGb3tLH5Upf74F"
+            .to_string()
+            .into(),
+    );
+
+    assert_eq!(
+        networks.no_pending_judgement_request(&kusama),
+        ErrorMessage::no_pending_judgement_request(3)
+    );
+    // Networks which are not configured use the first registrar.
+    assert_eq!(
+        networks.no_pending_judgement_request(&NetworkAddress::alice()),
+        ErrorMessage::no_pending_judgement_request(0)
+    );
+}
+
 #[test]
 fn subscribe_status_no_judgement_request() {
     let shared_port = Arc::new(AtomicUsize::new(0));
@@ -370,3 +409,167 @@ fn subscribe_status_verified_changes_in_steps() {
         ensure_empty_stream(stream).await;
     });
 }
+
+#[test]
+fn regenerate_challenge_with_cooldown() {
+    let shared_port = Arc::new(AtomicUsize::new(0));
+    let (tokenv1, tokenv2) = Regulator::new();
+
+    // The requests must be signed, so the key of the identity is required.
+    let pair = sr25519::Pair::from_string("//Alice", None).unwrap();
+    let alice = IdentityState {
+        net_address: NetworkAddress::from(
+            BlankNetwork::from("polkadot"),
+            IdentityAddress::from(
+                pair.public()
+                    .to_ss58check_with_version(Ss58AddressFormat::PolkadotAccount),
+            ),
+        ),
+        ..IdentityState::alice()
+    };
+    let t_alice = alice.clone();
+
+    tokenv1.me_first();
+
+    // Run the API service (tokio v1).
+    let t_shared_port = Arc::clone(&shared_port);
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.spawn(async move {
+        let be = InMemBackend::run().await;
+        let store = be.store();
+        let port = ApiBackend::run(store.clone()).await;
+        t_shared_port.store(port, Ordering::Relaxed);
+
+        let mut repo = Repository::new_with_snapshot_service(VerifierAggregate::default(), store)
+            .await
+            .unwrap();
+        repo.apply(VerifierCommand::InsertIdentity(alice))
+            .await
+            .unwrap();
+
+        // Let the server spin up and project the inserted identity.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        tokenv1.rotate().await;
+    });
+
+    // Make tests with the client (tokio v0.2).
+    let mut rt = tokio_02::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        tokenv2.wait().await;
+
+        let client = ApiClient::new(shared_port.load(Ordering::Relaxed)).await;
+        let alice = t_alice;
+        let bob = sr25519::Pair::from_string("//Bob", None).unwrap();
+        let bob_address = NetworkAddress::from(
+            BlankNetwork::from("polkadot"),
+            IdentityAddress::from(
+                bob.public()
+                    .to_ss58check_with_version(Ss58AddressFormat::PolkadotAccount),
+            ),
+        );
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signed =
+            |net_address: &NetworkAddress, field: &str, timestamp: u64, pair: &sr25519::Pair| {
+                let field_ty: IdentityFieldType =
+                    serde_json::from_value(Value::String(field.to_string())).unwrap();
+                let signature = pair.sign(regenerate_message(&field_ty, timestamp).as_bytes());
+
+                Params::Array(vec![
+                    Value::String(net_address.net_str().to_string()),
+                    Value::String(net_address.address_str().to_string()),
+                    Value::String(field.to_string()),
+                    Value::from(timestamp),
+                    Value::String(format!("0x{}", hex::encode(signature.0))),
+                ])
+            };
+        let params =
+            |net_address: &NetworkAddress, field: &str| signed(net_address, field, now, &pair);
+        let error_code = |result: std::result::Result<Value, RpcError>| match result {
+            Err(RpcError::JsonRpcError(err)) => err.code,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        // Requests which are not signed by the identity, or signed too long
+        // ago, are rejected.
+        let eve = sr25519::Pair::from_string("//Eve", None).unwrap();
+        let result = client
+            .raw()
+            .call_method(
+                "account_regenerateChallenge",
+                signed(&alice.net_address, "matrix", now, &eve),
+            )
+            .await;
+        assert_eq!(error_code(result), ErrorCode::ServerError(-32001));
+
+        let result = client
+            .raw()
+            .call_method(
+                "account_regenerateChallenge",
+                signed(&alice.net_address, "matrix", now - 3_600, &pair),
+            )
+            .await;
+        assert_eq!(error_code(result), ErrorCode::ServerError(-32001));
+
+        let result = client
+            .raw()
+            .call_method(
+                "account_regenerateChallenge",
+                params(&alice.net_address, "matrix"),
+            )
+            .await;
+        assert_eq!(result.unwrap(), Value::Bool(true));
+
+        // The identity is in its cooldown.
+        let result = client
+            .raw()
+            .call_method(
+                "account_regenerateChallenge",
+                params(&alice.net_address, "email"),
+            )
+            .await;
+        assert_eq!(error_code(result), ErrorCode::ServerError(-32000));
+
+        // Bob has no pending judgement request.
+        let result = client
+            .raw()
+            .call_method(
+                "account_regenerateChallenge",
+                signed(&bob_address, "matrix", now, &bob),
+            )
+            .await;
+        assert_eq!(error_code(result), ErrorCode::InvalidParams);
+
+        // Let the regenerated challenge be projected.
+        tokio_02::time::delay_for(Duration::from_secs(2)).await;
+
+        let messages = client
+            .get_messages(
+                "account_subscribeStatus",
+                Params::Array(vec![
+                    Value::String(alice.net_address.net_str().to_string()),
+                    Value::String(alice.net_address.address_str().to_string()),
+                ]),
+                "account_status",
+                "account_unsubscribeStatus",
+            )
+            .await;
+
+        let challenge =
+            |state: &IdentityState| match state.fields[&IdentityFieldType::Matrix].challenge() {
+                ChallengeStatus::ExpectMessage(challenge) => challenge.expected_message.clone(),
+                _ => panic!(),
+            };
+
+        // Only the challenge of the requested field was replaced.
+        let state: StateWrapper = serde_json::from_value(messages[0].clone()).unwrap();
+        assert_ne!(challenge(&state.state), challenge(&alice));
+        assert_eq!(
+            state.state.fields[&IdentityFieldType::Email],
+            alice.fields[&IdentityFieldType::Email]
+        );
+    });
+}